@@ -1,3 +1,4 @@
+use crate::accept::origin::OriginPolicy;
 use crate::accept::ws_headers::WsHeaders;
 use const_sha1::{sha1, ConstBuffer};
 use std::convert::{TryFrom, TryInto};
@@ -5,6 +6,12 @@ use std::convert::{TryFrom, TryInto};
 const MAGIC_GUID: &[u8; 36] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 const ACCEPT_HEADER: &[u8; 97] = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-Websocket-Accept: ";
 const HTTP_EOC: &[u8; 4] = b"\r\n\r\n";
+const BAD_REQUEST_RESPONSE: &[u8] =
+    b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+const FORBIDDEN_RESPONSE: &[u8] =
+    b"HTTP/1.1 403 Forbidden\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+const NOT_FOUND_RESPONSE: &[u8] =
+    b"HTTP/1.1 404 Not Found\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
 
 fn sha1_bytes<D>(data: D) -> [u8; 20]
 where
@@ -40,6 +47,23 @@ fn get_accept_response(response_key: &[u8; 28]) -> [u8; 129] {
 pub enum KeyError {
     Unknown,
     InvalidPayload,
+    OriginNotAllowed,
+    /// The server didn't answer with `101 Switching Protocols` and an upgrade to websocket
+    UnexpectedResponse,
+    /// The server's `Sec-WebSocket-Accept` doesn't match the key we sent
+    AcceptMismatch,
+    /// No route of the server's [`crate::router::Router`] matches the request path
+    NotFound,
+}
+impl KeyError {
+    /// The HTTP response to send back before closing a connection that failed the handshake
+    pub fn get_response(&self) -> &'static [u8] {
+        match self {
+            KeyError::OriginNotAllowed => FORBIDDEN_RESPONSE,
+            KeyError::NotFound => NOT_FOUND_RESPONSE,
+            _ => BAD_REQUEST_RESPONSE,
+        }
+    }
 }
 pub struct HeaderBuffers;
 #[derive(Debug, PartialEq)]
@@ -91,13 +115,34 @@ impl<'a> TryFrom<&'a [u8]> for AcceptResponse {
 }
 
 fn buffer_to_response_key<B>(input: B) -> Result<AcceptResponse, KeyError>
+where
+    B: AsRef<[u8]>,
+{
+    buffer_to_response_key_with_policy(input, &OriginPolicy::allow_all())
+}
+fn buffer_to_response_key_with_policy<B>(
+    input: B,
+    origin_policy: &OriginPolicy,
+) -> Result<AcceptResponse, KeyError>
 where
     B: AsRef<[u8]>,
 {
     let input = String::from_utf8_lossy(B::as_ref(&input));
     let headers = WsHeaders::from(&input);
+    headers_to_response_key(&headers, origin_policy)
+}
+/// Validate already parsed handshake headers and build the response for them
+pub fn headers_to_response_key(
+    headers: &WsHeaders,
+    origin_policy: &OriginPolicy,
+) -> Result<AcceptResponse, KeyError> {
     match (headers.get("Upgrade"), headers.get("Sec-WebSocket-Key")) {
-        (Some("websocket"), Some(key)) => AcceptResponse::try_from(key.as_bytes()),
+        (Some("websocket"), Some(key)) => {
+            if !origin_policy.is_allowed(headers.get_origin()) {
+                return Err(KeyError::OriginNotAllowed);
+            }
+            AcceptResponse::try_from(key.as_bytes())
+        }
         _ => Err(KeyError::InvalidPayload),
     }
 }
@@ -111,10 +156,22 @@ pub trait FromHeaderBuffer {
         <Self as FromBuffer<HeaderBuffers>>::from_buffer(input)
     }
 }
+impl ResponseKey {
+    pub fn get_data(&self) -> &[u8] {
+        &self.0
+    }
+}
 impl AcceptResponse {
     pub fn get_data(&self) -> &[u8] {
         &self.0
     }
+    /// Like [`FromHeaderBuffer::from_header_buffer`] but rejects origins the policy doesn't allow
+    pub fn from_header_buffer_with_policy(
+        input: &[u8],
+        origin_policy: &OriginPolicy,
+    ) -> Result<AcceptResponse, KeyError> {
+        buffer_to_response_key_with_policy(input, origin_policy)
+    }
 }
 impl FromHeaderBuffer for AcceptKey {
     type Error = KeyError;
@@ -304,4 +361,22 @@ mod tests {
         assert_eq!(ar.0, expected_result);
         assert_eq!(ar2.0, expected_result2);
     }
+    #[test]
+    fn should_deny_origins_not_in_policy() {
+        let request = b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nOrigin: https://evil.com\r\nSec-WebSocket-Key: +X1HPfJ3J0ZvPaFhlqIAmg==\r\n\r\n";
+        let policy = OriginPolicy::new().allow("https://example.com");
+
+        let result = AcceptResponse::from_header_buffer_with_policy(request, &policy);
+        assert!(matches!(result, Err(KeyError::OriginNotAllowed)));
+        assert!(result
+            .unwrap_err()
+            .get_response()
+            .starts_with(b"HTTP/1.1 403 Forbidden\r\n"));
+
+        let policy = policy.allow("https://*.evil.com").allow("https://evil.com");
+        assert_eq!(
+            AcceptResponse::from_header_buffer_with_policy(request, &policy).unwrap(),
+            AcceptResponse::from_header_buffer(request).unwrap()
+        );
+    }
 }
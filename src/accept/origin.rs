@@ -0,0 +1,248 @@
+fn split_host_port(input: &str) -> (&str, Option<&str>) {
+    // An origin never has a path, but be lenient with a trailing slash
+    let input = input.trim_end_matches('/');
+    match input.rfind(':') {
+        // Don't mistake the colons of an IPv6 literal for a port separator
+        Some(index) if !input[index..].contains(']') => {
+            (&input[..index], Some(&input[index + 1..]))
+        }
+        _ => (input, None),
+    }
+}
+/// A parsed `Origin` header value: `scheme://host[:port]`
+#[derive(Debug, PartialEq)]
+struct Origin<'a> {
+    scheme: &'a str,
+    host: &'a str,
+    port: Option<&'a str>,
+}
+impl<'a> Origin<'a> {
+    fn parse(input: &'a str) -> Option<Origin<'a>> {
+        let mut splits = input.splitn(2, "://");
+        let (scheme, rest) = match (splits.next(), splits.next()) {
+            (Some(scheme), Some(rest)) if !scheme.is_empty() => (scheme, rest),
+            _ => return None,
+        };
+        let (host, port) = split_host_port(rest);
+        if host.is_empty() {
+            return None;
+        }
+        Some(Origin { scheme, host, port })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum HostPattern {
+    Exact(String),
+    /// `*.example.com`; matches any subdomain but not `example.com` itself
+    Subdomains(String),
+}
+impl HostPattern {
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Exact(expected) => expected.eq_ignore_ascii_case(host),
+            HostPattern::Subdomains(domain) => {
+                let (host, domain) = (host.as_bytes(), domain.as_bytes());
+                host.len() > domain.len() + 1
+                    && host[host.len() - domain.len()..].eq_ignore_ascii_case(domain)
+                    && host[host.len() - domain.len() - 1] == b'.'
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct OriginRule {
+    scheme: Option<String>,
+    host: HostPattern,
+    port: Option<String>,
+}
+impl OriginRule {
+    fn parse(pattern: &str) -> OriginRule {
+        let (scheme, rest) = match pattern.find("://") {
+            Some(index) => (
+                Some(pattern[..index].to_ascii_lowercase()),
+                &pattern[index + 3..],
+            ),
+            None => (None, pattern),
+        };
+        let (host, port) = split_host_port(rest);
+        let host = if let Some(domain) = host.strip_prefix("*.") {
+            HostPattern::Subdomains(domain.to_ascii_lowercase())
+        } else {
+            HostPattern::Exact(host.to_ascii_lowercase())
+        };
+        OriginRule {
+            scheme,
+            host,
+            port: port.map(String::from),
+        }
+    }
+    fn matches(&self, origin: &Origin) -> bool {
+        let scheme_matches = self
+            .scheme
+            .as_ref()
+            .map(|scheme| scheme.eq_ignore_ascii_case(origin.scheme))
+            .unwrap_or(true);
+        // A full origin without a port means the default port of its scheme
+        let port_matches = match (&self.port, origin.port) {
+            (Some(expected), Some(port)) => expected == port,
+            (None, Some(_)) => self.scheme.is_none(),
+            (Some(_), None) => false,
+            (None, None) => true,
+        };
+        scheme_matches && port_matches && self.host.matches(origin.host)
+    }
+}
+
+/// Decides which `Origin` headers are allowed to open a websocket.
+///
+/// Browsers attach cookies to websocket handshakes regardless of which page
+/// opened them, so a server relying on cookies should only accept origins it trusts.
+/// Non-browser clients usually don't send an `Origin` header at all, which is
+/// controlled separately with [`OriginPolicy::allow_missing_origin`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct OriginPolicy {
+    allow_any: bool,
+    allow_missing: bool,
+    schemes: Vec<String>,
+    rules: Vec<OriginRule>,
+}
+impl OriginPolicy {
+    /// A policy that denies every origin until some are allowed
+    pub fn new() -> Self {
+        Self {
+            allow_any: false,
+            allow_missing: false,
+            schemes: Vec::new(),
+            rules: Vec::new(),
+        }
+    }
+    /// A policy that doesn't look at the `Origin` header at all
+    pub fn allow_all() -> Self {
+        Self {
+            allow_any: true,
+            allow_missing: true,
+            ..Self::new()
+        }
+    }
+    /// Allow an origin pattern.
+    ///
+    /// `https://example.com` only matches that exact scheme, host and port,
+    /// `example.com` matches the host on any scheme and port, and
+    /// `https://*.example.com` matches every subdomain of `example.com`.
+    pub fn allow<P>(mut self, pattern: P) -> Self
+    where
+        P: AsRef<str>,
+    {
+        self.rules.push(OriginRule::parse(pattern.as_ref()));
+        self
+    }
+    /// Only accept origins using one of these schemes, on top of the allowed patterns
+    pub fn allow_schemes<I, S>(mut self, schemes: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.schemes = schemes
+            .into_iter()
+            .map(|scheme| scheme.as_ref().to_ascii_lowercase())
+            .collect();
+        self
+    }
+    /// Whether a handshake without an `Origin` header should be accepted
+    pub fn allow_missing_origin(mut self, allow: bool) -> Self {
+        self.allow_missing = allow;
+        self
+    }
+    pub fn is_allowed(&self, origin: Option<&str>) -> bool {
+        let origin = match origin {
+            Some(origin) => origin,
+            None => return self.allow_missing,
+        };
+        if self.allow_any {
+            return true;
+        }
+        let origin = match Origin::parse(origin.trim()) {
+            Some(origin) => origin,
+            // Covers the opaque "null" origin sent from sandboxed documents
+            None => return false,
+        };
+        if !self.schemes.is_empty()
+            && !self
+                .schemes
+                .iter()
+                .any(|scheme| scheme.eq_ignore_ascii_case(origin.scheme))
+        {
+            return false;
+        }
+        self.rules.iter().any(|rule| rule.matches(&origin))
+    }
+}
+impl Default for OriginPolicy {
+    fn default() -> Self {
+        Self::allow_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn should_parse_origins() {
+        assert_eq!(
+            Origin::parse("https://example.com:8080"),
+            Some(Origin {
+                scheme: "https",
+                host: "example.com",
+                port: Some("8080")
+            })
+        );
+        assert_eq!(
+            Origin::parse("http://[::1]"),
+            Some(Origin {
+                scheme: "http",
+                host: "[::1]",
+                port: None
+            })
+        );
+        assert_eq!(Origin::parse("null"), None);
+    }
+    #[test]
+    fn should_match_exact_origins() {
+        let policy = OriginPolicy::new().allow("https://example.com");
+        assert!(policy.is_allowed(Some("https://example.com")));
+        assert!(policy.is_allowed(Some("https://EXAMPLE.com")));
+        assert!(!policy.is_allowed(Some("http://example.com")));
+        assert!(!policy.is_allowed(Some("https://example.com:8443")));
+        assert!(!policy.is_allowed(Some("https://evil-example.com")));
+        assert!(!policy.is_allowed(Some("null")));
+    }
+    #[test]
+    fn should_match_wildcard_subdomains() {
+        let policy = OriginPolicy::new().allow("*.example.com");
+        assert!(policy.is_allowed(Some("https://app.example.com")));
+        assert!(policy.is_allowed(Some("http://a.b.example.com:3000")));
+        assert!(!policy.is_allowed(Some("https://example.com")));
+        assert!(!policy.is_allowed(Some("https://evilexample.com")));
+    }
+    #[test]
+    fn should_restrict_schemes() {
+        let policy = OriginPolicy::new()
+            .allow("*.example.com")
+            .allow_schemes(["https"]);
+        assert!(policy.is_allowed(Some("https://app.example.com")));
+        assert!(!policy.is_allowed(Some("http://app.example.com")));
+        assert!(!policy.is_allowed(Some("moz-extension://720c0260-ac83-4fde-bd5f-e33127fd9e2b")));
+    }
+    #[test]
+    fn should_handle_missing_origin() {
+        let policy = OriginPolicy::new().allow("https://example.com");
+        assert!(!policy.is_allowed(None));
+        assert!(policy.allow_missing_origin(true).is_allowed(None));
+        assert!(OriginPolicy::allow_all().is_allowed(None));
+        assert!(!OriginPolicy::allow_all()
+            .allow_missing_origin(false)
+            .is_allowed(None));
+    }
+}
@@ -2,18 +2,24 @@
 pub struct WsHeaders<'a> {
     upgrade: Option<&'a str>,
     websocket_key: Option<&'a str>,
+    origin: Option<&'a str>,
+    protocol: Option<&'a str>,
 }
 impl<'a> WsHeaders<'a> {
     pub fn new() -> Self {
         Self {
             upgrade: None,
             websocket_key: None,
+            origin: None,
+            protocol: None,
         }
     }
     pub fn get(&self, key: &str) -> Option<&'a str> {
         match key {
             "Upgrade" => self.get_upgrade(),
             "Sec-WebSocket-Key" => self.get_key(),
+            "Origin" => self.get_origin(),
+            "Sec-WebSocket-Protocol" => self.get_protocol(),
             _ => None,
         }
     }
@@ -23,6 +29,20 @@ impl<'a> WsHeaders<'a> {
     pub fn get_key(&self) -> Option<&'a str> {
         self.websocket_key
     }
+    pub fn get_origin(&self) -> Option<&'a str> {
+        self.origin
+    }
+    /// The comma separated subprotocols the client offers
+    pub fn get_protocol(&self) -> Option<&'a str> {
+        self.protocol
+    }
+    /// The first header a handshake request needs that this one lacks
+    pub fn get_missing(&self) -> Option<&'static str> {
+        ["Upgrade", "Sec-WebSocket-Key"]
+            .iter()
+            .copied()
+            .find(|key| self.get(key).is_none())
+    }
     pub fn is_websocket(&self) -> bool {
         matches!(self.upgrade, Some("websocket"))
     }
@@ -37,6 +57,8 @@ fn get_ws_headers_from_str<'a>(input: &'a str) -> WsHeaders<'a> {
         match (splits.next(), splits.next()) {
             (Some("Upgrade"), value) => ws_headers.upgrade = value,
             (Some("Sec-WebSocket-Key"), value) => ws_headers.websocket_key = value,
+            (Some("Origin"), value) => ws_headers.origin = value,
+            (Some("Sec-WebSocket-Protocol"), value) => ws_headers.protocol = value,
             _ => {}
         }
     });
@@ -143,6 +165,23 @@ mod tests {
             Some("+X1HPfJ3J0ZvPaFhlqIAmg==")
         );
         assert_eq!(result.get("Upgrade"), Some("websocket"));
+        assert_eq!(
+            result.get_origin(),
+            Some("moz-extension://720c0260-ac83-4fde-bd5f-e33127fd9e2b")
+        );
+    }
+    #[test]
+    fn should_name_the_missing_header() {
+        let input = String::from_utf8_lossy(&BUFFER);
+        assert_eq!(WsHeaders::from(&input).get_missing(), None);
+        assert_eq!(
+            WsHeaders::from("GET / HTTP/1.1\r\nUpgrade: websocket\r\n\r\n").get_missing(),
+            Some("Sec-WebSocket-Key")
+        );
+        assert_eq!(
+            WsHeaders::from("GET / HTTP/1.1\r\n\r\n").get_missing(),
+            Some("Upgrade")
+        );
     }
     #[cfg(feature = "count-allocations")]
     #[test]
@@ -0,0 +1,433 @@
+use crate::accept::keys::{KeyError, ResponseKey};
+use crate::error::{Error, Result};
+use crate::net::NetStream;
+use crate::protocol::{random_bytes, Role};
+use crate::websocket::{read_http_headers, Handshake, WebSocket, WebSocketConfig};
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::time::Instant;
+
+/// The parts of a `ws://host:port/path?query` address needed to connect.
+///
+/// Unix sockets are addressed as `ws+unix://<socket path>:<path?query>`; a socket
+/// path starting with `@` names a socket in the Linux abstract namespace.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Url {
+    scheme: String,
+    host: String,
+    port: u16,
+    resource: String,
+    unix_path: Option<String>,
+}
+impl Url {
+    pub fn parse(input: &str) -> Result<Url> {
+        let (scheme, rest) = input.split_once("://").ok_or(Error::InvalidUrl)?;
+        let scheme = scheme.to_ascii_lowercase();
+        let default_port = match scheme.as_str() {
+            "ws" => 80,
+            "wss" => 443,
+            "ws+unix" => return Url::parse_unix(scheme, rest),
+            _ => return Err(Error::InvalidUrl),
+        };
+        let (authority, resource) = match rest.find(['/', '?']) {
+            Some(position) => rest.split_at(position),
+            None => (rest, ""),
+        };
+        let resource = parse_resource(resource);
+        if authority.contains('@') {
+            return Err(Error::InvalidUrl);
+        }
+        let (host, port) = if let Some(bracketed) = authority.strip_prefix('[') {
+            let end = bracketed.find(']').ok_or(Error::InvalidUrl)?;
+            (&bracketed[..end], &bracketed[end + 1..])
+        } else {
+            match authority.find(':') {
+                Some(position) => authority.split_at(position),
+                None => (authority, ""),
+            }
+        };
+        let port = match port {
+            "" => default_port,
+            port => port
+                .strip_prefix(':')
+                .and_then(|port| port.parse().ok())
+                .ok_or(Error::InvalidUrl)?,
+        };
+        if host.is_empty() {
+            return Err(Error::InvalidUrl);
+        }
+        Ok(Url {
+            scheme,
+            host: String::from(host),
+            port,
+            resource,
+            unix_path: None,
+        })
+    }
+    fn parse_unix(scheme: String, rest: &str) -> Result<Url> {
+        let (path, resource) = rest.split_once(':').unwrap_or((rest, ""));
+        if path.is_empty() || path == "@" {
+            return Err(Error::InvalidUrl);
+        }
+        Ok(Url {
+            scheme,
+            host: String::from("localhost"),
+            port: 0,
+            resource: parse_resource(resource),
+            unix_path: Some(String::from(path)),
+        })
+    }
+    pub fn get_scheme(&self) -> &str {
+        &self.scheme
+    }
+    /// The host without the brackets around IPv6 addresses
+    pub fn get_host(&self) -> &str {
+        &self.host
+    }
+    /// The TCP port, 0 for unix sockets
+    pub fn get_port(&self) -> u16 {
+        self.port
+    }
+    /// The socket path of a `ws+unix://` url
+    pub fn get_unix_path(&self) -> Option<&str> {
+        self.unix_path.as_deref()
+    }
+    /// Path and query, as sent in the request line
+    pub fn get_resource(&self) -> &str {
+        &self.resource
+    }
+    pub fn is_secure(&self) -> bool {
+        self.scheme == "wss"
+    }
+    /// The value of the `Host` header
+    pub fn get_authority(&self) -> String {
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        match (self.is_secure(), self.port) {
+            (false, 80) | (true, 443) | (_, 0) => host,
+            (_, port) => format!("{}:{}", host, port),
+        }
+    }
+}
+
+fn parse_resource(resource: &str) -> String {
+    let resource = match resource.find('#') {
+        Some(position) => &resource[..position],
+        None => resource,
+    };
+    match resource {
+        "" => String::from("/"),
+        query if query.starts_with('?') => format!("/{}", query),
+        path => String::from(path),
+    }
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &str) -> Result<NetStream> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        if let Some(name) = path.strip_prefix('@') {
+            #[cfg(target_os = "android")]
+            use std::os::android::net::SocketAddrExt;
+            #[cfg(target_os = "linux")]
+            use std::os::linux::net::SocketAddrExt;
+
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            return Ok(NetStream::Unix(UnixStream::connect_addr(&address)?));
+        }
+    }
+    Ok(NetStream::Unix(UnixStream::connect(path)?))
+}
+#[cfg(not(unix))]
+fn connect_unix(_path: &str) -> Result<NetStream> {
+    Err(Error::InvalidUrl)
+}
+
+/// Connect to a `ws://` or `ws+unix://` url, or `wss://` with the `rustls` feature,
+/// and perform the client side of the opening handshake
+pub fn connect(url: &str) -> Result<WebSocket<NetStream>> {
+    connect_with_config(url, WebSocketConfig::default())
+}
+pub fn connect_with_config(url: &str, config: WebSocketConfig) -> Result<WebSocket<NetStream>> {
+    let url = Url::parse(url)?;
+    let stream = match (url.get_unix_path(), url.is_secure()) {
+        (Some(path), _) => connect_unix(path)?,
+        #[cfg(feature = "rustls")]
+        (None, true) => {
+            let stream = TcpStream::connect((url.get_host(), url.get_port()))?;
+            crate::tls::connect(crate::tls::client_config()?, url.get_host(), stream)?
+        }
+        #[cfg(not(feature = "rustls"))]
+        (None, true) => return Err(Error::InvalidUrl),
+        (None, false) => NetStream::Tcp(TcpStream::connect((url.get_host(), url.get_port()))?),
+    };
+    connected_handshake(stream, &url, config)
+}
+/// Like [`connect_with_config`], verifying `wss://` servers with `tls_config`
+#[cfg(feature = "rustls")]
+pub fn connect_with_tls(
+    url: &str,
+    config: WebSocketConfig,
+    tls_config: std::sync::Arc<rustls::ClientConfig>,
+) -> Result<WebSocket<NetStream>> {
+    let url = Url::parse(url)?;
+    let stream = match (url.get_unix_path(), url.is_secure()) {
+        (Some(path), _) => connect_unix(path)?,
+        (None, true) => {
+            let stream = TcpStream::connect((url.get_host(), url.get_port()))?;
+            crate::tls::connect(tls_config, url.get_host(), stream)?
+        }
+        (None, false) => NetStream::Tcp(TcpStream::connect((url.get_host(), url.get_port()))?),
+    };
+    connected_handshake(stream, &url, config)
+}
+/// Run [`client_handshake`] with reads timing out at [`WebSocketConfig::handshake_timeout`]
+fn connected_handshake(
+    stream: NetStream,
+    url: &Url,
+    config: WebSocketConfig,
+) -> Result<WebSocket<NetStream>> {
+    stream.set_read_timeout(config.handshake_timeout)?;
+    let websocket = client_handshake(stream, url, config)?;
+    websocket.get_ref().set_read_timeout(None)?;
+    Ok(websocket)
+}
+/// Perform the client side of the opening handshake on an already connected stream.
+///
+/// [`WebSocketConfig::handshake_timeout`] is only checked when a read times out, so
+/// the stream needs a read timeout for a silent server to make the handshake fail.
+pub fn client_handshake<S>(
+    mut stream: S,
+    url: &Url,
+    config: WebSocketConfig,
+) -> Result<WebSocket<S>>
+where
+    S: Read + Write,
+{
+    let key = base64::encode(random_bytes::<16>());
+    let protocols = match config.subprotocols.is_empty() {
+        true => String::new(),
+        false => format!(
+            "Sec-WebSocket-Protocol: {}\r\n",
+            config.subprotocols.join(", ")
+        ),
+    };
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n{}\r\n",
+        url.get_resource(),
+        url.get_authority(),
+        key,
+        protocols
+    );
+    stream.write_all(request.as_bytes())?;
+    stream.flush()?;
+
+    let deadline = config
+        .handshake_timeout
+        .map(|timeout| Instant::now() + timeout);
+    let (headers, rest) = read_http_headers(&mut stream, deadline)?;
+    let expected = ResponseKey::try_from(key.as_bytes())?;
+    let subprotocol = check_response(
+        &String::from_utf8_lossy(&headers),
+        expected.get_data(),
+        &config.subprotocols,
+    )?;
+    let handshake = Handshake {
+        path: String::from(url.get_resource()),
+        subprotocol,
+    };
+    Ok(WebSocket::from_parts(
+        stream,
+        config,
+        Role::Client,
+        handshake,
+        rest,
+    ))
+}
+/// Returns the subprotocol the server picked, which has to be one we offered
+fn check_response(
+    headers: &str,
+    expected_accept: &[u8],
+    subprotocols: &[String],
+) -> Result<Option<String>> {
+    let mut rows = headers.split("\r\n");
+    let status = rows.next().unwrap_or("");
+    if !status.starts_with("HTTP/1.1 101") {
+        return Err(KeyError::UnexpectedResponse.into());
+    }
+    let mut upgrade = None;
+    let mut accept = None;
+    let mut protocol = None;
+    rows.for_each(|row| {
+        if let Some((name, value)) = row.split_once(':') {
+            if name.eq_ignore_ascii_case("Upgrade") {
+                upgrade = Some(value.trim());
+            } else if name.eq_ignore_ascii_case("Sec-WebSocket-Accept") {
+                accept = Some(value.trim());
+            } else if name.eq_ignore_ascii_case("Sec-WebSocket-Protocol") {
+                protocol = Some(value.trim());
+            }
+        }
+    });
+    match (upgrade, accept) {
+        (Some(upgrade), Some(accept)) if upgrade.eq_ignore_ascii_case("websocket") => {
+            if accept.as_bytes() != expected_accept {
+                return Err(KeyError::AcceptMismatch.into());
+            }
+            match protocol {
+                Some(protocol) if !subprotocols.iter().any(|ours| ours == protocol) => {
+                    Err(KeyError::UnexpectedResponse.into())
+                }
+                protocol => Ok(protocol.map(String::from)),
+            }
+        }
+        _ => Err(KeyError::UnexpectedResponse.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Timeout;
+    use crate::message::{close_code, CloseFrame, Message};
+    use crate::server::tests::{serve_echo, spawn_server};
+    use crate::server::{Server, ServerConfig};
+    use crate::websocket::tests::MockStream;
+    use std::time::Duration;
+
+    #[test]
+    fn should_parse_urls() {
+        let url = Url::parse("ws://localhost:9001/chat?room=1").unwrap();
+        assert_eq!(url.get_host(), "localhost");
+        assert_eq!(url.get_port(), 9001);
+        assert_eq!(url.get_resource(), "/chat?room=1");
+        assert_eq!(url.get_authority(), "localhost:9001");
+
+        let url = Url::parse("wss://[::1]?token=a#ignored").unwrap();
+        assert!(url.is_secure());
+        assert_eq!(url.get_host(), "::1");
+        assert_eq!(url.get_port(), 443);
+        assert_eq!(url.get_resource(), "/?token=a");
+        assert_eq!(url.get_authority(), "[::1]");
+
+        let url = Url::parse("ws+unix:///tmp/app.sock:/chat?room=1").unwrap();
+        assert_eq!(url.get_unix_path(), Some("/tmp/app.sock"));
+        assert_eq!(url.get_resource(), "/chat?room=1");
+        assert_eq!(url.get_authority(), "localhost");
+        let url = Url::parse("ws+unix://@app").unwrap();
+        assert_eq!(url.get_unix_path(), Some("@app"));
+        assert_eq!(url.get_resource(), "/");
+
+        assert!(Url::parse("ws+unix://:/chat").is_err());
+        assert!(Url::parse("http://localhost").is_err());
+        assert!(Url::parse("ws://:80/").is_err());
+        assert!(Url::parse("ws://localhost:port/").is_err());
+    }
+    #[test]
+    fn should_reject_wrong_accept_keys() {
+        let response = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n";
+        let url = Url::parse("ws://localhost/").unwrap();
+        let result = client_handshake(
+            MockStream::new(response.to_vec()),
+            &url,
+            WebSocketConfig::default(),
+        );
+        assert!(matches!(
+            result,
+            Err(Error::Handshake(KeyError::AcceptMismatch))
+        ));
+
+        let response = b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n";
+        let result = client_handshake(
+            MockStream::new(response.to_vec()),
+            &url,
+            WebSocketConfig::default(),
+        );
+        assert!(matches!(
+            result,
+            Err(Error::Handshake(KeyError::UnexpectedResponse))
+        ));
+    }
+    #[test]
+    fn should_time_out_silent_servers() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        let config = WebSocketConfig {
+            handshake_timeout: Some(Duration::from_millis(20)),
+            ..WebSocketConfig::default()
+        };
+        assert!(matches!(
+            connect_with_config(&url, config),
+            Err(Error::Timeout(Timeout::Handshake))
+        ));
+    }
+    #[test]
+    fn should_talk_to_our_own_server() {
+        let (address, shutdown, echo, thread) = spawn_server(ServerConfig::default());
+        let mut websocket = connect(&format!("ws://{}/echo?client=1", address)).unwrap();
+
+        websocket
+            .write_message(Message::Text(String::from("Hello")))
+            .unwrap();
+        assert!(matches!(websocket.read_message(), Ok(Message::Text(text)) if text == "Hello"));
+        let large = vec![7; 70_000];
+        websocket
+            .write_message(Message::Binary(large.clone()))
+            .unwrap();
+        assert!(matches!(websocket.read_message(), Ok(Message::Binary(data)) if data == large));
+
+        websocket
+            .close(CloseFrame::new(close_code::NORMAL, "done"))
+            .unwrap();
+        assert!(matches!(websocket.read_message(), Ok(Message::Close)));
+        assert_eq!(
+            websocket.get_close_frame().map(|frame| frame.code),
+            Some(1000)
+        );
+
+        shutdown.shutdown();
+        thread.join().unwrap();
+        assert_eq!(*echo.events.lock().unwrap(), vec!["open", "close 1000"]);
+    }
+    fn echo_over(url: &str) {
+        let mut websocket = connect(url).unwrap();
+        websocket
+            .write_message(Message::Text(String::from("local")))
+            .unwrap();
+        assert!(matches!(websocket.read_message(), Ok(Message::Text(text)) if text == "local"));
+        websocket.write_message(Message::Close).unwrap();
+        assert!(matches!(websocket.read_message(), Ok(Message::Close)));
+    }
+    #[cfg(unix)]
+    #[test]
+    fn should_talk_over_unix_sockets() {
+        let path = std::env::temp_dir().join(format!("ws-lite-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (shutdown, echo, thread) = serve_echo(Server::bind_unix(&path).unwrap());
+
+        echo_over(&format!("ws+unix://{}:/chat", path.display()));
+
+        shutdown.shutdown();
+        thread.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(*echo.events.lock().unwrap(), vec!["open", "close 1000"]);
+    }
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn should_talk_over_abstract_unix_sockets() {
+        let name = format!("ws-lite-{}", std::process::id());
+        let (shutdown, echo, thread) = serve_echo(Server::bind_unix_abstract(&name).unwrap());
+
+        echo_over(&format!("ws+unix://@{}:/chat", name));
+
+        shutdown.shutdown();
+        thread.join().unwrap();
+        assert_eq!(*echo.events.lock().unwrap(), vec!["open", "close 1000"]);
+    }
+}
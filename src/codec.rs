@@ -0,0 +1,422 @@
+use crate::dataframe::{get_frame_length, DataFrame};
+use crate::error::{Error, Result, Timeout};
+#[cfg(feature = "tracing")]
+use crate::message::close_code;
+use crate::message::{CloseFrame, Message, WriteMessage};
+#[cfg(feature = "tracing")]
+use crate::metrics::Direction;
+use crate::metrics::{sent_frame, Metrics};
+use crate::pool::BufferPool;
+#[cfg(feature = "tracing")]
+use crate::protocol::trace_message;
+use crate::protocol::{Deadlines, Incoming, MessageAssembler, Role};
+use crate::websocket::{
+    accept_request, report_handshake, split_http_headers, WebSocketConfig, REQUEST_TIMEOUT_RESPONSE,
+};
+use bytes::{Buf, BytesMut};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder, Framed, FramedParts};
+
+/// Split one complete frame off the front of `src`, or reserve room for the rest of it
+fn decode_frame(
+    src: &mut BytesMut,
+    max_frame_size: usize,
+    pool: Option<&BufferPool>,
+) -> Result<Option<DataFrame>> {
+    let length = match get_frame_length(src) {
+        Some(length) => length,
+        None => return Ok(None),
+    };
+    if length.saturating_sub(14) > max_frame_size {
+        return Err(Error::MessageTooBig);
+    }
+    if src.len() < length {
+        src.reserve(length - src.len());
+        return Ok(None);
+    }
+    let data = match pool {
+        Some(pool) => {
+            let data = pool.copy(&src[..length]);
+            src.advance(length);
+            data
+        }
+        None => src.split_to(length).to_vec(),
+    };
+    Ok(Some(DataFrame::new(data)))
+}
+
+/// Splits a byte stream into single frames without interpreting them
+#[derive(Debug)]
+pub struct FrameCodec {
+    max_frame_size: usize,
+}
+impl FrameCodec {
+    pub fn new(max_frame_size: usize) -> Self {
+        FrameCodec { max_frame_size }
+    }
+}
+impl Default for FrameCodec {
+    fn default() -> Self {
+        FrameCodec::new(WebSocketConfig::default().max_frame_size)
+    }
+}
+impl Decoder for FrameCodec {
+    type Item = DataFrame;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<DataFrame>> {
+        decode_frame(src, self.max_frame_size, None)
+    }
+}
+impl Encoder<WriteMessage> for FrameCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: WriteMessage, dst: &mut BytesMut) -> Result<()> {
+        dst.extend_from_slice(item.as_ref());
+        Ok(())
+    }
+}
+
+/// Decodes whole messages, joining fragments, and encodes messages as single frames.
+///
+/// Pings are handed to the caller like any other message; answering them is up to it,
+/// as is sending keepalive pings: [`WebSocketConfig::ping_interval`] is ignored.
+/// Decoding fails once a fragment or idle deadline has passed, but a codec can't wake
+/// itself: callers waiting for input should sleep until [`MessageCodec::next_deadline`]
+/// and then call [`MessageCodec::check_deadlines`].
+#[derive(Debug)]
+pub struct MessageCodec {
+    max_frame_size: usize,
+    buffer_pool: Option<BufferPool>,
+    assembler: MessageAssembler,
+    close_frame: Option<CloseFrame>,
+    deadlines: Deadlines,
+    metrics: Option<Arc<dyn Metrics>>,
+    #[cfg(feature = "tracing")]
+    trace_payloads: Option<usize>,
+}
+impl MessageCodec {
+    pub fn new(config: &WebSocketConfig) -> Self {
+        MessageCodec {
+            max_frame_size: config.max_frame_size,
+            buffer_pool: config.buffer_pool.clone(),
+            assembler: MessageAssembler::new(config, Role::Server),
+            close_frame: None,
+            deadlines: Deadlines::new(config.fragment_timeout, config.idle_timeout),
+            metrics: config.metrics.clone(),
+            #[cfg(feature = "tracing")]
+            trace_payloads: config.trace_payloads,
+        }
+    }
+    /// The close frame sent by the peer, once one has been decoded
+    pub fn get_close_frame(&self) -> Option<&CloseFrame> {
+        self.close_frame.as_ref()
+    }
+    /// When the next fragment or idle deadline passes, `None` if nothing is pending
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines.next_deadline()
+    }
+    /// Fails with [`Error::Timeout`] once a deadline has passed; close with its code
+    pub fn check_deadlines(&self) -> Result<()> {
+        self.deadlines.check(Instant::now())
+    }
+}
+impl Default for MessageCodec {
+    fn default() -> Self {
+        MessageCodec::new(&WebSocketConfig::default())
+    }
+}
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>> {
+        while let Some(frame) = decode_frame(src, self.max_frame_size, self.buffer_pool.as_ref())? {
+            self.deadlines.on_frame(&frame, Instant::now())?;
+            match self.assembler.push(frame)? {
+                Some(Incoming::Message(message)) => return Ok(Some(message)),
+                Some(Incoming::Close(close_frame)) => {
+                    self.close_frame = close_frame;
+                    return Ok(Some(Message::Close));
+                }
+                None => continue,
+            }
+        }
+        Ok(None)
+    }
+}
+impl Encoder<Message> for MessageCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<()> {
+        #[cfg(feature = "tracing")]
+        match &item {
+            Message::Close => tracing::debug!(code = close_code::NORMAL, "sending close frame"),
+            message => trace_message(self.trace_payloads, Direction::Out, message),
+        }
+        Encoder::<WriteMessage>::encode(self, WriteMessage::from(item), dst)
+    }
+}
+impl Encoder<WriteMessage> for MessageCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: WriteMessage, dst: &mut BytesMut) -> Result<()> {
+        if let Some(metrics) = &self.metrics {
+            sent_frame(&**metrics, item.as_ref());
+        }
+        dst.extend_from_slice(item.as_ref());
+        Ok(())
+    }
+}
+
+async fn read_http_headers<S>(stream: &mut S) -> Result<(Vec<u8>, Vec<u8>)>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buffer: Vec<u8> = Vec::with_capacity(1024);
+    let mut chunk = [0; 1024];
+    loop {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        let search_from = buffer.len();
+        buffer.extend_from_slice(&chunk[..read]);
+        if let Some(rest) = split_http_headers(&mut buffer, search_from)? {
+            return Ok((buffer, rest));
+        }
+    }
+}
+/// Answer the handshake request on `stream`, returning what was read past it
+async fn respond<S>(stream: &mut S, config: &WebSocketConfig) -> Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let headers = match config.handshake_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, read_http_headers(&mut *stream))
+            .await
+            .unwrap_or_else(|_| Err(Timeout::Handshake.into())),
+        None => read_http_headers(&mut *stream).await,
+    };
+    let (headers, rest) = match headers {
+        Err(Error::Timeout(Timeout::Handshake)) => {
+            #[cfg(feature = "tracing")]
+            tracing::info!(timeout = %Timeout::Handshake, "timed out");
+            let _ = stream.write_all(REQUEST_TIMEOUT_RESPONSE).await;
+            let _ = stream.flush().await;
+            return Err(Timeout::Handshake.into());
+        }
+        result => result?,
+    };
+    match accept_request(&headers, config) {
+        Ok((response, _)) => {
+            stream.write_all(&response).await?;
+            stream.flush().await?;
+            Ok(rest)
+        }
+        Err(error) => {
+            let _ = stream.write_all(error.get_response()).await;
+            let _ = stream.flush().await;
+            Err(error.into())
+        }
+    }
+}
+
+/// Perform the server side of the opening handshake and frame the stream as messages.
+///
+/// The default [`WebSocketConfig::handshake_timeout`] applies, so the tokio runtime needs its
+/// time driver (`enable_time` or `enable_all`); without it tokio panics on the first accept.
+pub async fn accept_async<S>(stream: S) -> Result<Framed<S, MessageCodec>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    accept_async_with_config(stream, WebSocketConfig::default()).await
+}
+/// Like [`accept_async`]; a rejected handshake is answered with an HTTP error response.
+///
+/// A [`WebSocketConfig::handshake_timeout`] needs a tokio runtime with the time driver enabled.
+pub async fn accept_async_with_config<S>(
+    mut stream: S,
+    config: WebSocketConfig,
+) -> Result<Framed<S, MessageCodec>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let rest = respond(&mut stream, &config).await;
+    report_handshake(&config.metrics, &rest);
+    let rest = rest?;
+    let mut parts = FramedParts::new::<Message>(stream, MessageCodec::new(&config));
+    parts.read_buf = BytesMut::from(rest.as_slice());
+    Ok(Framed::from_parts(parts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dataframe::Opcode;
+    use crate::metrics::AtomicMetrics;
+    use crate::websocket::tests::{client_frame, REQUEST};
+    use futures::{SinkExt, StreamExt};
+    use std::time::Duration;
+
+    #[test]
+    fn should_wait_for_whole_frames() {
+        let frame = client_frame(Opcode::Text, b"Hello", true);
+        let mut codec = FrameCodec::default();
+        let mut buffer = BytesMut::from(&frame[..1]);
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+        buffer.extend_from_slice(&frame[1..4]);
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+        buffer.extend_from_slice(&frame[4..]);
+        let dataframe = codec.decode(&mut buffer).unwrap().unwrap();
+        assert_eq!(dataframe.get_payload(), Some(&b"Hello"[..]));
+        assert!(buffer.is_empty());
+    }
+    #[test]
+    fn should_decode_fragmented_messages() {
+        let mut codec = MessageCodec::default();
+        let mut buffer = BytesMut::new();
+        buffer.extend_from_slice(&client_frame(Opcode::Binary, &[1], false));
+        buffer.extend_from_slice(&client_frame(Opcode::Continuation, &[2], true));
+        buffer.extend_from_slice(&client_frame(Opcode::Close, &[3, 232], true));
+        assert!(
+            matches!(codec.decode(&mut buffer), Ok(Some(Message::Binary(data))) if data == [1, 2])
+        );
+        assert!(matches!(
+            codec.decode(&mut buffer),
+            Ok(Some(Message::Close))
+        ));
+        assert_eq!(codec.get_close_frame(), Some(&CloseFrame::new(1000, "")));
+    }
+    #[tokio::test]
+    async fn should_accept_async() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let mut input = REQUEST.to_vec();
+        input.extend(client_frame(Opcode::Text, b"Hello", true));
+        client.write_all(&input).await.unwrap();
+
+        let mut framed = accept_async(server).await.unwrap();
+        assert!(matches!(framed.next().await, Some(Ok(Message::Text(text))) if text == "Hello"));
+        framed
+            .send(Message::Text(String::from("World")))
+            .await
+            .unwrap();
+
+        let mut output = vec![0; 129 + 7];
+        client.read_exact(&mut output).await.unwrap();
+        assert!(output.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+        assert_eq!(&output[129..], b"\x81\x05World");
+    }
+    #[tokio::test]
+    async fn should_report_handshakes_frames_and_messages() {
+        let metrics = Arc::new(AtomicMetrics::new());
+        let config = WebSocketConfig {
+            metrics: Some(metrics.clone()),
+            ..WebSocketConfig::default()
+        };
+        let (mut client, server) = tokio::io::duplex(4096);
+        let mut input = REQUEST.to_vec();
+        input.extend(client_frame(Opcode::Text, b"Hello", true));
+        client.write_all(&input).await.unwrap();
+
+        let mut framed = accept_async_with_config(server, config).await.unwrap();
+        assert!(matches!(framed.next().await, Some(Ok(Message::Text(_)))));
+        framed.send(Message::Binary(vec![1, 2, 3])).await.unwrap();
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.handshakes_succeeded, 1);
+        assert_eq!(snapshot.frames_in["text"].frames, 1);
+        assert_eq!(snapshot.message_sizes_in.sum, 5);
+        assert_eq!(snapshot.frames_out["binary"].bytes, 5);
+        assert_eq!(snapshot.message_sizes_out.sum, 3);
+    }
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn should_trace_payloads_and_close_frames() {
+        use crate::websocket::tests::{assert_events, record_events};
+
+        let config = WebSocketConfig {
+            trace_payloads: Some(3),
+            ..WebSocketConfig::default()
+        };
+        let events = record_events(|| {
+            let mut codec = MessageCodec::new(&config);
+            let mut buffer = BytesMut::new();
+            buffer.extend_from_slice(&client_frame(Opcode::Text, b"Hello", true));
+            buffer.extend_from_slice(&client_frame(Opcode::Close, &[3, 232], true));
+            assert!(matches!(
+                codec.decode(&mut buffer),
+                Ok(Some(Message::Text(_)))
+            ));
+            assert!(matches!(
+                codec.decode(&mut buffer),
+                Ok(Some(Message::Close))
+            ));
+            let mut output = BytesMut::new();
+            codec
+                .encode(Message::Binary(b"World".to_vec()), &mut output)
+                .unwrap();
+            codec.encode(Message::Close, &mut output).unwrap();
+        });
+
+        let expected = [
+            "message=message direction=\"in\" opcode=\"text\" size=5 payload=Hel",
+            "message=received close frame code=1000",
+            "message=message direction=\"out\" opcode=\"binary\" size=5 payload=Wor",
+            "message=sending close frame code=1000",
+        ];
+        assert_events(&events, &expected);
+    }
+    #[test]
+    fn should_time_out_unfinished_fragments() {
+        let config = WebSocketConfig {
+            fragment_timeout: Some(Duration::from_millis(5)),
+            ..WebSocketConfig::default()
+        };
+        let mut codec = MessageCodec::new(&config);
+        assert!(codec.next_deadline().is_none());
+        let mut buffer = BytesMut::new();
+        buffer.extend_from_slice(&client_frame(Opcode::Text, b"Hel", false));
+        assert!(matches!(codec.decode(&mut buffer), Ok(None)));
+        assert!(codec.next_deadline().is_some());
+        assert!(codec.check_deadlines().is_ok());
+
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(matches!(
+            codec.check_deadlines(),
+            Err(Error::Timeout(Timeout::Fragment))
+        ));
+        buffer.extend_from_slice(&client_frame(Opcode::Continuation, b"lo", true));
+        assert!(matches!(
+            codec.decode(&mut buffer),
+            Err(Error::Timeout(Timeout::Fragment))
+        ));
+    }
+    #[test]
+    fn should_leave_keepalive_pings_to_the_caller() {
+        let config = WebSocketConfig {
+            ping_interval: Some(Duration::from_millis(1)),
+            ..WebSocketConfig::default()
+        };
+        let codec = MessageCodec::new(&config);
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(codec.next_deadline().is_none());
+        assert!(codec.check_deadlines().is_ok());
+    }
+    #[tokio::test]
+    async fn should_time_out_slow_handshakes() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        client.write_all(&REQUEST[..20]).await.unwrap();
+        let config = WebSocketConfig {
+            handshake_timeout: Some(Duration::from_millis(5)),
+            ..WebSocketConfig::default()
+        };
+        let result = accept_async_with_config(server, config).await;
+        assert!(matches!(result, Err(Error::Timeout(Timeout::Handshake))));
+        let mut output = Vec::new();
+        client.read_to_end(&mut output).await.unwrap();
+        assert!(output.starts_with(b"HTTP/1.1 408 Request Timeout\r\n"));
+    }
+}
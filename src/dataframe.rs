@@ -1,11 +1,13 @@
+use crate::message::CloseFrame;
 use std::borrow::Cow;
+use std::convert::TryFrom;
 
 pub fn mask_data<const N: usize>(data: &mut [u8], mask: [u8; N]) {
     for index in 0..data.len() {
         data[index] ^= mask[index % N];
     }
 }
-#[derive(PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Opcode {
     Continuation = 0,
     Text = 1,
@@ -20,6 +22,7 @@ impl From<u8> for Opcode {
         match v {
             0 => Opcode::Continuation,
             1 => Opcode::Text,
+            2 => Opcode::Binary,
             8 => Opcode::Close,
             9 => Opcode::Ping,
             10 => Opcode::Pong,
@@ -27,6 +30,23 @@ impl From<u8> for Opcode {
         }
     }
 }
+impl Opcode {
+    pub fn is_control(&self) -> bool {
+        matches!(self, Opcode::Close | Opcode::Ping | Opcode::Pong)
+    }
+    /// Lower case name, as used in metric labels
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Opcode::Continuation => "continuation",
+            Opcode::Text => "text",
+            Opcode::Binary => "binary",
+            Opcode::Close => "close",
+            Opcode::Ping => "ping",
+            Opcode::Pong => "pong",
+            Opcode::Unknown => "unknown",
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum ExtraSize {
@@ -46,6 +66,29 @@ pub mod frame_positions {
     pub const MASK_PAYLOAD_LENGTH: u8 = 0b01111111;
 }
 
+/// Length of the whole frame at the start of `buffer`, or `None` if its header isn't complete yet
+pub fn get_frame_length(buffer: &[u8]) -> Option<usize> {
+    let second = *buffer.get(1)?;
+    let mask_length = if second & frame_positions::IS_MASK == frame_positions::IS_MASK {
+        4
+    } else {
+        0
+    };
+    let (header_length, payload_length): (usize, u64) =
+        match second & frame_positions::MASK_PAYLOAD_LENGTH {
+            126 if buffer.len() >= 4 => (4, u16::from_be_bytes([buffer[2], buffer[3]]) as u64),
+            127 if buffer.len() >= 10 => {
+                let mut bytes: [u8; 8] = [0; 8];
+                bytes.copy_from_slice(&buffer[2..10]);
+                (10, u64::from_be_bytes(bytes))
+            }
+            126 | 127 => return None,
+            size => (2, size as u64),
+        };
+    let payload_length = usize::try_from(payload_length).unwrap_or(usize::MAX);
+    Some((header_length + mask_length).saturating_add(payload_length))
+}
+
 #[derive(Debug)]
 pub enum ReadMessage<'a> {
     Text(Cow<'a, str>),
@@ -55,9 +98,13 @@ pub enum ReadMessage<'a> {
     Close,
 }
 
+/// The most bytes a frame header takes: two, eight for the longest length and four for the mask
+pub(crate) const MAX_FRAME_HEADER_LENGTH: usize = 14;
+
+/// A frame, or with `[u8; MAX_FRAME_HEADER_LENGTH]` only the header of one
 #[derive(Debug)]
-pub struct DataFrame {
-    data: Vec<u8>,
+pub struct DataFrame<D = Vec<u8>> {
+    data: D,
 }
 impl DataFrame {
     pub fn new(data: Vec<u8>) -> DataFrame {
@@ -67,9 +114,33 @@ impl DataFrame {
 
         dataframe
     }
+    /// The whole frame, unmasked, so the buffer can go back to a [`crate::pool::BufferPool`]
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+    fn calculate_masked_data(&mut self) {
+        if let Some((start_payload, end_payload)) = self.get_start_and_end_payload() {
+            let mask = self.get_masking_key();
+            mask_data(&mut self.data[start_payload..end_payload], mask);
+        }
+    }
+}
+impl DataFrame<[u8; MAX_FRAME_HEADER_LENGTH]> {
+    /// Copy a complete frame header, with the payload left out, onto the stack
+    pub(crate) fn header(header: &[u8]) -> Self {
+        let mut data = [0; MAX_FRAME_HEADER_LENGTH];
+        data[..header.len()].copy_from_slice(header);
+        DataFrame { data }
+    }
+}
+impl<D> DataFrame<D>
+where
+    D: AsRef<[u8]>,
+{
     #[inline(always)]
     pub fn is_fin(&self) -> bool {
         self.data
+            .as_ref()
             .get(0)
             .map(|frame| (frame & frame_positions::FIN) == frame_positions::FIN)
             .unwrap_or(false)
@@ -77,6 +148,7 @@ impl DataFrame {
     #[inline(always)]
     pub fn is_rsv1(&self) -> bool {
         self.data
+            .as_ref()
             .get(0)
             .map(|frame| (frame & frame_positions::RSV1) == frame_positions::RSV1)
             .unwrap_or(false)
@@ -84,6 +156,7 @@ impl DataFrame {
     #[inline(always)]
     pub fn is_rsv2(&self) -> bool {
         self.data
+            .as_ref()
             .get(0)
             .map(|frame| (frame & frame_positions::RSV2) == frame_positions::RSV2)
             .unwrap_or(false)
@@ -91,6 +164,7 @@ impl DataFrame {
     #[inline(always)]
     pub fn is_rsv3(&self) -> bool {
         self.data
+            .as_ref()
             .get(0)
             .map(|frame| (frame & frame_positions::RSV3) == frame_positions::RSV3)
             .unwrap_or(false)
@@ -100,6 +174,7 @@ impl DataFrame {
     pub fn get_opcode(&self) -> u8 {
         // default to close
         self.data
+            .as_ref()
             .get(0)
             .map(|frame| frame & frame_positions::MASK_OPCODE)
             .unwrap_or(8)
@@ -107,6 +182,7 @@ impl DataFrame {
     #[inline(always)]
     pub fn is_mask(&self) -> bool {
         self.data
+            .as_ref()
             .get(1)
             .map(|frame| (frame & frame_positions::IS_MASK) == frame_positions::IS_MASK)
             .unwrap_or(false)
@@ -115,6 +191,7 @@ impl DataFrame {
     #[inline(always)]
     fn get_short_payload_length(&self) -> u8 {
         self.data
+            .as_ref()
             .get(1)
             .map(|frame| frame & frame_positions::MASK_PAYLOAD_LENGTH)
             .unwrap_or(0)
@@ -132,14 +209,14 @@ impl DataFrame {
     pub fn get_payload_length(&self) -> usize {
         match self.get_extra_payload_bytes() {
             ExtraSize::Zero(size) => size as usize,
-            ExtraSize::Two if self.data.len() > 4 => {
+            ExtraSize::Two if self.data.as_ref().len() > 4 => {
                 let mut bytes: [u8; 2] = [0; 2];
-                bytes.copy_from_slice(&self.data[2..4]);
+                bytes.copy_from_slice(&self.data.as_ref()[2..4]);
                 u16::from_be_bytes(bytes) as usize
             }
-            ExtraSize::Eight if self.data.len() > 8 => {
+            ExtraSize::Eight if self.data.as_ref().len() > 8 => {
                 let mut bytes: [u8; 8] = [0; 8];
-                bytes.copy_from_slice(&self.data[2..10]);
+                bytes.copy_from_slice(&self.data.as_ref()[2..10]);
                 u64::from_be_bytes(bytes) as usize
             }
             _ => 0,
@@ -147,11 +224,13 @@ impl DataFrame {
     }
 
     fn get_payload_start_pos(&self) -> usize {
-        match self.get_extra_payload_bytes() {
-            ExtraSize::Zero(_) => 6,
-            ExtraSize::Two => 8,
-            ExtraSize::Eight => 14,
-        }
+        let mask_length = if self.is_mask() { 4 } else { 0 };
+        mask_length
+            + match self.get_extra_payload_bytes() {
+                ExtraSize::Zero(_) => 2,
+                ExtraSize::Two => 4,
+                ExtraSize::Eight => 10,
+            }
     }
     pub fn get_full_frame_length(&self) -> usize {
         self.get_payload_start_pos() + self.get_payload_length()
@@ -168,9 +247,9 @@ impl DataFrame {
     pub fn get_masking_key(&self) -> [u8; 4] {
         let start = self.get_masking_key_start() as usize;
         let end = start + 4;
-        if self.is_mask() && self.data.len() >= end {
+        if self.is_mask() && self.data.as_ref().len() >= end {
             let mut buffer: [u8; 4] = [0; 4];
-            buffer.copy_from_slice(&self.data[start..end]);
+            buffer.copy_from_slice(&self.data.as_ref()[start..end]);
             buffer
         } else {
             // masking key [0, 0, 0, 0] is ok because 1 ^ 0 == 1, 0 ^ 0 == 0
@@ -181,19 +260,33 @@ impl DataFrame {
         let start_payload = self.get_payload_start_pos();
         let payload_length = self.get_payload_length();
 
-        if start_payload > self.data.len() {
+        if start_payload > self.data.as_ref().len() {
             return None;
         }
         let end_payload = start_payload + payload_length;
 
-        if end_payload > self.data.len() {
+        if end_payload > self.data.as_ref().len() {
             return None;
         }
 
         Some((start_payload, end_payload))
     }
     pub fn get_full_payload(&self) -> &[u8] {
-        &self.data
+        self.data.as_ref()
+    }
+    /// The unmasked payload regardless of opcode; empty if the frame is incomplete
+    pub fn get_payload_data(&self) -> &[u8] {
+        match self.get_start_and_end_payload() {
+            Some((start, end)) => &self.data.as_ref()[start..end],
+            None => &[],
+        }
+    }
+    /// Status code and reason of a close frame, `None` if it didn't carry any
+    pub fn get_close_frame(&self) -> Option<CloseFrame> {
+        if !self.is_closed() {
+            return None;
+        }
+        CloseFrame::from_payload(self.get_payload_data())
     }
     #[inline(always)]
     pub fn get_payload(&self) -> Option<&[u8]> {
@@ -207,7 +300,7 @@ impl DataFrame {
         };
 
         if end > start {
-            Some(&self.data[start..end])
+            Some(&self.data.as_ref()[start..end])
         } else {
             None
         }
@@ -222,12 +315,6 @@ impl DataFrame {
         let opcode = Opcode::from(self.get_opcode());
         matches!(opcode, Opcode::Close)
     }
-    fn calculate_masked_data(&mut self) {
-        if let Some((start_payload, end_payload)) = self.get_start_and_end_payload() {
-            let mask = self.get_masking_key();
-            mask_data(&mut self.data[start_payload..end_payload], mask);
-        }
-    }
     pub fn get_message<'b>(&'b self) -> Option<ReadMessage<'b>> {
         match Opcode::from(self.get_opcode()) {
             Opcode::Ping => self.binary().map(ReadMessage::Ping),
@@ -243,6 +330,7 @@ impl DataFrame {
 mod tests {
     use super::*;
     use crate::message::{Message, WriteMessage};
+    use crate::protocol::peek_frame_header;
 
     #[test]
     fn test_from_message_a() {
@@ -259,6 +347,16 @@ mod tests {
         assert_eq!(data, &vec![129, 2, 97, 97]);
     }
     #[test]
+    fn test_unmasked_payload() {
+        let mut buffer = vec![130, 126, 0, 200];
+        buffer.extend_from_slice(&[7; 200]);
+        let dataframe = DataFrame::new(buffer);
+
+        assert!(!dataframe.is_mask());
+        assert_eq!(dataframe.get_full_frame_length(), 204);
+        assert_eq!(dataframe.get_payload(), Some(&[7; 200][..]));
+    }
+    #[test]
     fn test_buffer_hello_world() {
         let str = "Hello World";
         let buffer: Vec<u8> = vec![
@@ -442,6 +540,46 @@ mod tests {
         assert_eq!(input, str);
     }
     #[test]
+    fn test_binary_opcode() {
+        let buffer: Vec<u8> = vec![
+            130, // FIN(128) + Opcode(2)
+            130, // MASK(128) + PayloadLength(2)
+            1, 2, 3, 4, // Masking key
+            0, 0,
+        ];
+        let dataframe = DataFrame::new(buffer);
+        assert_eq!(Opcode::from(dataframe.get_opcode()), Opcode::Binary);
+        assert!(matches!(
+            dataframe.get_message(),
+            Some(ReadMessage::Binary(&[1, 2]))
+        ));
+    }
+    #[test]
+    fn test_frame_length() {
+        assert_eq!(get_frame_length(&[129]), None);
+        assert_eq!(get_frame_length(&[129, 1]), Some(3));
+        assert_eq!(get_frame_length(&[129, 129]), Some(7));
+        assert_eq!(get_frame_length(&[129, 254, 0]), None);
+        assert_eq!(get_frame_length(&[129, 254, 1, 0]), Some(264));
+        assert_eq!(
+            get_frame_length(&[129, 127, 0, 0, 0, 0, 0, 1, 0, 0]),
+            Some(65546)
+        );
+    }
+    #[test]
+    fn test_close_frame_with_status() {
+        let buffer: Vec<u8> = vec![
+            136, // FIN(128) + Opcode(8)
+            130, // MASK(128) + PayloadLength(2)
+            0, 0, 0, 0, // Masking key
+            3, 233, // 1001
+        ];
+        let dataframe = DataFrame::new(buffer);
+        assert_eq!(dataframe.get_payload(), None);
+        assert_eq!(dataframe.get_payload_data(), &[3, 233]);
+        assert_eq!(dataframe.get_close_frame(), Some(CloseFrame::new(1001, "")));
+    }
+    #[test]
     fn test_mask_data() {
         let masking_key: [u8; 5] = [1, 0, 0, 1, 1];
         let expected_result = vec![128, 254, 5, 1, 153];
@@ -496,4 +634,27 @@ mod tests {
         });
         assert_eq!(pt_alloc, 0);
     }
+    #[test]
+    fn test_header_of_masked_frame() {
+        let mut buffer: Vec<u8> = vec![2, 254, 1, 0, 9, 8, 7, 6];
+        buffer.extend_from_slice(&[0; 256]);
+        let (header, header_length, payload_length) = peek_frame_header(&buffer).unwrap();
+
+        assert!(!header.is_fin());
+        assert!(header.is_mask());
+        assert_eq!(header.get_opcode(), Opcode::Binary as u8);
+        assert_eq!(header.get_payload_length(), 256);
+        assert_eq!(header.get_masking_key(), [9, 8, 7, 6]);
+        assert_eq!((header_length, payload_length), (8, 256));
+        assert!(peek_frame_header(&buffer[..7]).is_none());
+    }
+    #[cfg(feature = "count-allocations")]
+    #[test]
+    fn peek_header_no_allocations() {
+        let buffer = vec![130, 255, 0, 0, 0, 0, 0, 1, 0, 0, 1, 2, 3, 4];
+        let pt_alloc = allocation_counter::count(|| {
+            assert!(peek_frame_header(&buffer).is_some());
+        });
+        assert_eq!(pt_alloc, 0);
+    }
 }
@@ -0,0 +1,161 @@
+use crate::accept::keys::KeyError;
+use crate::message::close_code;
+use std::fmt;
+use std::io;
+
+#[derive(Debug, PartialEq)]
+pub enum ProtocolError {
+    UnmaskedFrame,
+    MaskedFrame,
+    ReservedBitsSet,
+    UnknownOpcode,
+    FragmentedControlFrame,
+    ControlFrameTooBig,
+    UnexpectedContinuation,
+    ExpectedContinuation,
+    InvalidCloseFrame,
+    InvalidUtf8,
+    HeadersTooLarge,
+}
+impl ProtocolError {
+    pub fn close_code(&self) -> u16 {
+        match self {
+            ProtocolError::InvalidUtf8 => close_code::INVALID_PAYLOAD,
+            _ => close_code::PROTOCOL_ERROR,
+        }
+    }
+}
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            ProtocolError::UnmaskedFrame => "received an unmasked frame from a client",
+            ProtocolError::MaskedFrame => "received a masked frame from a server",
+            ProtocolError::ReservedBitsSet => "reserved bits are set without an extension",
+            ProtocolError::UnknownOpcode => "unknown opcode",
+            ProtocolError::FragmentedControlFrame => "control frames must not be fragmented",
+            ProtocolError::ControlFrameTooBig => "control frame payload is larger than 125 bytes",
+            ProtocolError::UnexpectedContinuation => {
+                "continuation frame without a message to continue"
+            }
+            ProtocolError::ExpectedContinuation => {
+                "new data frame while a fragmented message is pending"
+            }
+            ProtocolError::InvalidCloseFrame => "invalid close frame",
+            ProtocolError::InvalidUtf8 => "text payload is not valid UTF-8",
+            ProtocolError::HeadersTooLarge => "handshake headers are too large",
+        };
+        f.write_str(description)
+    }
+}
+
+/// Which deadline ran out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timeout {
+    /// The opening handshake wasn't complete in time
+    Handshake,
+    /// A fragmented message wasn't finished in time
+    Fragment,
+    /// The peer didn't send any message in time
+    Idle,
+    /// The peer didn't answer a keepalive ping in time
+    Keepalive,
+}
+impl Timeout {
+    /// The close code to send; handshake timeouts are answered with HTTP 408 instead
+    pub fn close_code(&self) -> Option<u16> {
+        match self {
+            Timeout::Handshake => None,
+            Timeout::Fragment => Some(close_code::POLICY_VIOLATION),
+            Timeout::Idle | Timeout::Keepalive => Some(close_code::GOING_AWAY),
+        }
+    }
+}
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Timeout::Handshake => f.write_str("handshake"),
+            Timeout::Fragment => f.write_str("fragmented message"),
+            Timeout::Idle => f.write_str("idle connection"),
+            Timeout::Keepalive => f.write_str("keepalive"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Handshake(KeyError),
+    Protocol(ProtocolError),
+    #[cfg(feature = "rustls")]
+    Tls(rustls::Error),
+    MessageTooBig,
+    Timeout(Timeout),
+    /// The url isn't a `ws://` or `ws+unix://` address, or `wss://` without the `rustls` feature
+    InvalidUrl,
+    /// The close handshake has completed; nothing more can be read or written
+    ConnectionClosed,
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "io error: {}", error),
+            Error::Handshake(error) => write!(f, "handshake failed: {:?}", error),
+            Error::Protocol(error) => write!(f, "protocol error: {}", error),
+            #[cfg(feature = "rustls")]
+            Error::Tls(error) => write!(f, "tls error: {}", error),
+            Error::MessageTooBig => f.write_str("message is larger than the configured limit"),
+            Error::Timeout(timeout) => write!(f, "{} timed out", timeout),
+            Error::InvalidUrl => f.write_str("invalid websocket url"),
+            Error::ConnectionClosed => f.write_str("connection is closed"),
+        }
+    }
+}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            #[cfg(feature = "rustls")]
+            Error::Tls(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+impl From<KeyError> for Error {
+    fn from(error: KeyError) -> Self {
+        Error::Handshake(error)
+    }
+}
+#[cfg(feature = "rustls")]
+impl From<rustls::Error> for Error {
+    fn from(error: rustls::Error) -> Self {
+        Error::Tls(error)
+    }
+}
+impl From<Timeout> for Error {
+    fn from(timeout: Timeout) -> Self {
+        Error::Timeout(timeout)
+    }
+}
+impl From<ProtocolError> for Error {
+    fn from(error: ProtocolError) -> Self {
+        Error::Protocol(error)
+    }
+}
+/// For the `Read` and `Write` adapters; io errors are passed on as they are
+impl From<Error> for io::Error {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Io(error) => error,
+            Error::Timeout(_) => io::Error::new(io::ErrorKind::TimedOut, error),
+            Error::ConnectionClosed => io::Error::new(io::ErrorKind::ConnectionAborted, error),
+            error => io::Error::new(io::ErrorKind::InvalidData, error),
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
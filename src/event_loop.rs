@@ -0,0 +1,787 @@
+use crate::dataframe::Opcode;
+use crate::error::{Error, Result, Timeout};
+use crate::keepalive::{Keepalive, KeepaliveAction};
+use crate::message::{close_code, CloseFrame, Message, WriteMessage};
+#[cfg(feature = "tracing")]
+use crate::metrics::Direction;
+use crate::metrics::{sent_frame, Metrics};
+#[cfg(feature = "tracing")]
+use crate::protocol::trace_message;
+use crate::protocol::{close_code_for, take_frame, Deadlines, Incoming, MessageAssembler, Role};
+use crate::websocket::{
+    accept_request, read_into, report_handshake_failure, split_http_headers, State,
+    WebSocketConfig, READ_CHUNK_SIZE, REQUEST_TIMEOUT_RESPONSE,
+};
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Registry, Token};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const LISTENER: Token = Token(0);
+/// Bytes read from one connection before the others get their turn
+const MAX_READ_PER_EVENT: usize = 16 * READ_CHUNK_SIZE;
+/// How long to wait before accepting again after the listener failed, like without file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+
+pub type ConnectionId = usize;
+
+/// Callbacks for every connection driven by an [`EventLoop`]
+pub trait EventHandler {
+    fn on_open(&mut self, _connection: &mut ConnectionRef<'_>) {}
+    fn on_message(&mut self, connection: &mut ConnectionRef<'_>, message: Message);
+    fn on_close(&mut self, _id: ConnectionId, _close_frame: Option<&CloseFrame>) {}
+    fn on_error(&mut self, _id: ConnectionId, _error: &Error) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    Handshake,
+    Open,
+    /// The handshake was rejected; the connection is dropped once the response is written
+    Rejected,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Filled {
+    /// Everything the socket had was read
+    WouldBlock,
+    /// The peer closed its side
+    Eof,
+    /// The limit was reached and more may be waiting
+    Limit,
+}
+
+struct Connection {
+    stream: TcpStream,
+    phase: Phase,
+    state: State,
+    read_buffer: Vec<u8>,
+    /// How much of the read buffer has been searched for the end of the handshake request
+    searched: usize,
+    write_buffer: Vec<u8>,
+    assembler: MessageAssembler,
+    close_frame: Option<CloseFrame>,
+    writable_interest: bool,
+    /// When an unfinished handshake is answered with `408 Request Timeout`
+    handshake_deadline: Option<Instant>,
+    deadlines: Deadlines,
+    keepalive: Keepalive,
+    metrics: Option<Arc<dyn Metrics>>,
+    #[cfg(feature = "tracing")]
+    trace_payloads: Option<usize>,
+}
+impl Connection {
+    fn next_deadline(&self) -> Option<Instant> {
+        match (self.phase, self.state) {
+            (Phase::Handshake, _) => self.handshake_deadline,
+            (Phase::Open, State::Open) | (Phase::Open, State::CloseSent) => self
+                .deadlines
+                .next_deadline()
+                .into_iter()
+                .chain(self.keepalive.next_deadline())
+                .min(),
+            _ => None,
+        }
+    }
+    /// Ping an idle peer, or give up on it if it didn't answer the last ping
+    fn check_keepalive(&mut self, now: Instant) -> Result<()> {
+        match self.keepalive.poll(now) {
+            KeepaliveAction::Wait => Ok(()),
+            KeepaliveAction::Ping => {
+                if self.state == State::Open {
+                    self.queue(&WriteMessage::with_opcode(Opcode::Ping, []));
+                }
+                Ok(())
+            }
+            KeepaliveAction::TimedOut => Err(Timeout::Keepalive.into()),
+        }
+    }
+    fn queue(&mut self, message: &WriteMessage) {
+        if let Some(metrics) = &self.metrics {
+            sent_frame(&**metrics, message.as_ref());
+        }
+        self.write_buffer.extend_from_slice(message.as_ref());
+    }
+    fn queue_close(&mut self, frame: Option<&CloseFrame>) {
+        if self.state == State::Open {
+            #[cfg(feature = "tracing")]
+            tracing::debug!(code = frame.map(|frame| frame.code), "sending close frame");
+            self.queue(&WriteMessage::close(frame));
+            self.state = State::CloseSent;
+        }
+    }
+    /// Write as much of the write buffer as the socket takes without blocking
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.write_buffer.is_empty() {
+            match self.stream.write(&self.write_buffer) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(written) => {
+                    self.write_buffer.drain(..written);
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(())
+    }
+    /// Read until the socket would block or `limit` bytes have been read
+    fn fill_read_buffer(&mut self, limit: usize) -> io::Result<Filled> {
+        let mut total = 0;
+        while total < limit {
+            match read_into(&mut self.stream, &mut self.read_buffer, READ_CHUNK_SIZE) {
+                Ok(0) => return Ok(Filled::Eof),
+                Ok(read) => total += read,
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Filled::WouldBlock)
+                }
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(Filled::Limit)
+    }
+}
+
+/// The connection a callback was invoked for; sent messages are queued and written when the socket allows
+pub struct ConnectionRef<'a> {
+    id: ConnectionId,
+    connection: &'a mut Connection,
+}
+impl<'a> ConnectionRef<'a> {
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+    pub fn send(&mut self, message: Message) {
+        match message {
+            Message::Close => self.close(CloseFrame::new(close_code::NORMAL, "")),
+            message => {
+                if self.connection.state == State::Open {
+                    #[cfg(feature = "tracing")]
+                    trace_message(self.connection.trace_payloads, Direction::Out, &message);
+                    self.connection.queue(&WriteMessage::from(message));
+                }
+            }
+        }
+    }
+    pub fn close(&mut self, frame: CloseFrame) {
+        self.connection.queue_close(Some(&frame));
+    }
+}
+
+/// A single threaded, readiness based websocket server
+pub struct EventLoop<H> {
+    poll: Poll,
+    listener: TcpListener,
+    connections: HashMap<Token, Connection>,
+    /// Connections that hit [`MAX_READ_PER_EVENT`] and are read again without a new event
+    unread: Vec<Token>,
+    /// When to accept again after a failed accept left connections waiting in the backlog
+    accept_retry: Option<Instant>,
+    next_token: usize,
+    config: WebSocketConfig,
+    handler: H,
+}
+impl<H> EventLoop<H>
+where
+    H: EventHandler,
+{
+    pub fn bind(address: SocketAddr, handler: H) -> io::Result<Self> {
+        Self::bind_with_config(address, WebSocketConfig::default(), handler)
+    }
+    pub fn bind_with_config(
+        address: SocketAddr,
+        config: WebSocketConfig,
+        handler: H,
+    ) -> io::Result<Self> {
+        let poll = Poll::new()?;
+        let mut listener = TcpListener::bind(address)?;
+        poll.registry()
+            .register(&mut listener, LISTENER, Interest::READABLE)?;
+        Ok(EventLoop {
+            poll,
+            listener,
+            connections: HashMap::new(),
+            unread: Vec::new(),
+            accept_retry: None,
+            next_token: LISTENER.0 + 1,
+            config,
+            handler,
+        })
+    }
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+    pub fn get_handler(&self) -> &H {
+        &self.handler
+    }
+    pub fn get_handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+    /// Queue a message for a connection from outside of the callbacks
+    pub fn send(&mut self, id: ConnectionId, message: Message) -> bool {
+        let token = Token(id);
+        let sent = match self.connections.get_mut(&token) {
+            Some(connection) if connection.phase == Phase::Open => {
+                ConnectionRef { id, connection }.send(message);
+                true
+            }
+            _ => false,
+        };
+        if sent {
+            self.after_event(token);
+        }
+        sent
+    }
+    pub fn run(&mut self) -> io::Result<()> {
+        loop {
+            self.run_once(None)?;
+        }
+    }
+    /// Wait for and handle one round of events
+    pub fn run_once(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        let mut events = Events::with_capacity(1024);
+        // Readiness is edge triggered, so connections with data left don't wait for a new event
+        let timeout = match self.unread.is_empty() {
+            true => self.until_next_deadline(timeout),
+            false => Some(Duration::from_secs(0)),
+        };
+        let unread = std::mem::take(&mut self.unread);
+        if let Err(error) = self.poll.poll(&mut events, timeout) {
+            if error.kind() == io::ErrorKind::Interrupted {
+                return Ok(());
+            }
+            return Err(error);
+        }
+        for event in events.iter() {
+            match event.token() {
+                LISTENER => self.accept(),
+                token => {
+                    self.handle_event(token, event.is_readable() || event.is_read_closed());
+                    self.after_event(token);
+                }
+            }
+        }
+        for token in unread {
+            self.handle_event(token, true);
+            self.after_event(token);
+        }
+        if matches!(self.accept_retry, Some(retry) if retry <= Instant::now()) {
+            self.accept();
+        }
+        self.check_deadlines();
+        Ok(())
+    }
+    /// Shorten `timeout` to wake up for the first handshake, fragment or idle deadline
+    fn until_next_deadline(&self, timeout: Option<Duration>) -> Option<Duration> {
+        let deadline = self
+            .connections
+            .values()
+            .filter_map(Connection::next_deadline)
+            .chain(self.accept_retry)
+            .min();
+        let until = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        match (timeout, until) {
+            (Some(timeout), Some(until)) => Some(timeout.min(until)),
+            (timeout, None) | (None, timeout) => timeout,
+        }
+    }
+    /// Answer late handshakes with `408 Request Timeout`, ping idle peers and close timed out connections
+    fn check_deadlines(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<Token> = self
+            .connections
+            .iter()
+            .filter(|(_, connection)| matches!(connection.next_deadline(), Some(deadline) if deadline <= now))
+            .map(|(token, _)| *token)
+            .collect();
+        for token in expired {
+            if let Some(connection) = self.connections.get_mut(&token) {
+                match connection.phase {
+                    Phase::Handshake => {
+                        connection
+                            .write_buffer
+                            .extend_from_slice(REQUEST_TIMEOUT_RESPONSE);
+                        connection.phase = Phase::Rejected;
+                        #[cfg(feature = "tracing")]
+                        tracing::info!(timeout = %Timeout::Handshake, "timed out");
+                        report_handshake_failure(&self.config, Timeout::Handshake.into());
+                    }
+                    _ => {
+                        let result = connection.deadlines.check(now);
+                        if let Err(error) = result.and_then(|_| connection.check_keepalive(now)) {
+                            fail(token.0, connection, &mut self.handler, error);
+                        }
+                    }
+                }
+            }
+            self.after_event(token);
+        }
+    }
+    /// Accept every waiting connection; failures only pause accepting, the open connections go on
+    fn accept(&mut self) {
+        self.accept_retry = None;
+        loop {
+            let (mut stream, _) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => return,
+                Err(error)
+                    if matches!(
+                        error.kind(),
+                        io::ErrorKind::Interrupted | io::ErrorKind::ConnectionAborted
+                    ) =>
+                {
+                    continue
+                }
+                Err(_) => {
+                    self.accept_retry = Some(Instant::now() + ACCEPT_BACKOFF);
+                    return;
+                }
+            };
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            let registered = self
+                .poll
+                .registry()
+                .register(&mut stream, token, Interest::READABLE);
+            if let Err(error) = registered {
+                self.handler.on_error(token.0, &error.into());
+                continue;
+            }
+            self.connections.insert(
+                token,
+                Connection {
+                    stream,
+                    phase: Phase::Handshake,
+                    state: State::Open,
+                    read_buffer: Vec::new(),
+                    searched: 0,
+                    write_buffer: Vec::new(),
+                    assembler: MessageAssembler::new(&self.config, Role::Server),
+                    close_frame: None,
+                    writable_interest: false,
+                    handshake_deadline: self
+                        .config
+                        .handshake_timeout
+                        .map(|timeout| Instant::now() + timeout),
+                    deadlines: Deadlines::new(
+                        self.config.fragment_timeout,
+                        self.config.idle_timeout,
+                    ),
+                    keepalive: Keepalive::new(
+                        self.config.ping_interval,
+                        self.config.keepalive_timeout,
+                    ),
+                    metrics: self.config.metrics.clone(),
+                    #[cfg(feature = "tracing")]
+                    trace_payloads: self.config.trace_payloads,
+                },
+            );
+        }
+    }
+    fn handle_event(&mut self, token: Token, readable: bool) {
+        let EventLoop {
+            connections,
+            unread,
+            config,
+            handler,
+            ..
+        } = self;
+        let connection = match connections.get_mut(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+        if !readable {
+            return;
+        }
+        let result = connection
+            .fill_read_buffer(MAX_READ_PER_EVENT)
+            .map_err(Error::from)
+            .and_then(|filled| {
+                // Header and frame size limits apply before more is read
+                process(token.0, connection, config, handler)?;
+                match filled {
+                    Filled::Eof => connection.state = State::Closed,
+                    Filled::Limit if !unread.contains(&token) => unread.push(token),
+                    _ => {}
+                }
+                Ok(())
+            });
+        if let Err(error) = result {
+            fail(token.0, connection, handler, error);
+        }
+    }
+    /// Write whatever is queued and drop the connection once it is done
+    fn after_event(&mut self, token: Token) {
+        let registry = self.poll.registry();
+        let connection = match self.connections.get_mut(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+        let flushed = connection.flush();
+        let finished = connection.phase == Phase::Rejected || connection.state == State::Closed;
+        if flushed.is_err() || (finished && connection.write_buffer.is_empty()) {
+            if let Some(mut connection) = self.connections.remove(&token) {
+                let _ = registry.deregister(&mut connection.stream);
+                if connection.phase == Phase::Open {
+                    self.handler
+                        .on_close(token.0, connection.close_frame.as_ref());
+                }
+            }
+            return;
+        }
+        if let Err(error) = update_interest(registry, token, connection) {
+            if connection.phase == Phase::Open {
+                self.handler.on_error(token.0, &error.into());
+            }
+        }
+    }
+}
+fn update_interest(
+    registry: &Registry,
+    token: Token,
+    connection: &mut Connection,
+) -> io::Result<()> {
+    let writable_interest = !connection.write_buffer.is_empty();
+    if writable_interest == connection.writable_interest {
+        return Ok(());
+    }
+    let interest = if writable_interest {
+        Interest::READABLE | Interest::WRITABLE
+    } else {
+        Interest::READABLE
+    };
+    connection.writable_interest = writable_interest;
+    registry.reregister(&mut connection.stream, token, interest)
+}
+/// Send a close frame matching `error` if the connection is open, then give up on it
+fn fail<H>(id: ConnectionId, connection: &mut Connection, handler: &mut H, error: Error)
+where
+    H: EventHandler,
+{
+    if let (Some(code), Phase::Open) = (close_code_for(&error), connection.phase) {
+        connection.queue_close(Some(&CloseFrame::new(code, "")));
+    }
+    connection.state = State::Closed;
+    match (connection.phase, &connection.metrics) {
+        (Phase::Open, _) => handler.on_error(id, &error),
+        (Phase::Handshake, Some(metrics)) => metrics.handshake_failed(&error),
+        _ => {}
+    }
+}
+/// Run the handshake or decode frames from whatever has been read so far
+fn process<H>(
+    id: ConnectionId,
+    connection: &mut Connection,
+    config: &WebSocketConfig,
+    handler: &mut H,
+) -> Result<()>
+where
+    H: EventHandler,
+{
+    if connection.phase == Phase::Handshake {
+        let rest = match split_http_headers(&mut connection.read_buffer, connection.searched)? {
+            Some(rest) => rest,
+            None => {
+                connection.searched = connection.read_buffer.len();
+                return Ok(());
+            }
+        };
+        let headers = std::mem::replace(&mut connection.read_buffer, rest);
+        match accept_request(&headers, config) {
+            Ok((response, _)) => {
+                connection.write_buffer.extend_from_slice(&response);
+                connection.phase = Phase::Open;
+                if let Some(metrics) = &config.metrics {
+                    metrics.handshake_succeeded();
+                }
+                connection.deadlines = Deadlines::new(config.fragment_timeout, config.idle_timeout);
+                connection.keepalive =
+                    Keepalive::new(config.ping_interval, config.keepalive_timeout);
+                handler.on_open(&mut ConnectionRef { id, connection });
+            }
+            Err(error) => {
+                connection
+                    .write_buffer
+                    .extend_from_slice(error.get_response());
+                connection.phase = Phase::Rejected;
+                report_handshake_failure(config, error.into());
+                return Ok(());
+            }
+        }
+    }
+    if connection.phase != Phase::Open {
+        return Ok(());
+    }
+    while connection.state != State::Closed {
+        let frame = match take_frame(
+            &mut connection.read_buffer,
+            config.max_frame_size,
+            config.buffer_pool.as_ref(),
+        )? {
+            Some(frame) => frame,
+            None => break,
+        };
+        let now = Instant::now();
+        connection.deadlines.on_frame(&frame, now)?;
+        connection.keepalive.on_traffic(now);
+        if frame.get_opcode() == Opcode::Pong as u8 {
+            connection.keepalive.on_pong(now);
+        }
+        match connection.assembler.push(frame)? {
+            Some(Incoming::Message(message)) => {
+                if let Message::Ping(payload) = &message {
+                    if connection.state == State::Open {
+                        connection.queue(&WriteMessage::with_opcode(Opcode::Pong, payload));
+                    }
+                }
+                handler.on_message(&mut ConnectionRef { id, connection }, message);
+            }
+            Some(Incoming::Close(close_frame)) => {
+                let reply = close_frame
+                    .as_ref()
+                    .map(|frame| CloseFrame::new(frame.code, ""));
+                connection.queue_close(reply.as_ref());
+                connection.state = State::Closed;
+                connection.close_frame = close_frame;
+            }
+            None => continue,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::AtomicMetrics;
+    use crate::websocket::tests::{client_frame, REQUEST};
+    use std::io::Read;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[derive(Default)]
+    struct Echo {
+        opened: Vec<ConnectionId>,
+        closed: Vec<(ConnectionId, Option<CloseFrame>)>,
+    }
+    impl EventHandler for Echo {
+        fn on_open(&mut self, connection: &mut ConnectionRef<'_>) {
+            self.opened.push(connection.id());
+        }
+        fn on_message(&mut self, connection: &mut ConnectionRef<'_>, message: Message) {
+            match message {
+                Message::Text(_) | Message::Binary(_) => connection.send(message),
+                _ => {}
+            }
+        }
+        fn on_close(&mut self, id: ConnectionId, close_frame: Option<&CloseFrame>) {
+            self.closed.push((id, close_frame.cloned()));
+        }
+    }
+
+    fn run_until_done<F>(event_loop: &mut EventLoop<Echo>, client: F)
+    where
+        F: FnOnce(std::net::TcpStream) + Send + 'static,
+    {
+        let address = event_loop.local_addr().unwrap();
+        let done = Arc::new(AtomicBool::new(false));
+        let client_done = done.clone();
+        let client = thread::spawn(move || {
+            client(std::net::TcpStream::connect(address).unwrap());
+            client_done.store(true, Ordering::SeqCst);
+        });
+        while !done.load(Ordering::SeqCst) {
+            event_loop
+                .run_once(Some(Duration::from_millis(10)))
+                .unwrap();
+        }
+        client.join().unwrap();
+        // Let the loop see the connection going away
+        for _ in 0..10 {
+            event_loop
+                .run_once(Some(Duration::from_millis(10)))
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn should_echo_over_loopback() {
+        let mut event_loop =
+            EventLoop::bind("127.0.0.1:0".parse().unwrap(), Echo::default()).unwrap();
+        run_until_done(&mut event_loop, |mut stream| {
+            // Dribble the handshake to exercise the incremental parsing
+            stream.write_all(&REQUEST[..20]).unwrap();
+            thread::sleep(Duration::from_millis(20));
+            stream.write_all(&REQUEST[20..]).unwrap();
+            let mut response = [0; 129];
+            stream.read_exact(&mut response).unwrap();
+            assert!(response.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+
+            stream
+                .write_all(&client_frame(Opcode::Text, b"Hello", true))
+                .unwrap();
+            let mut reply = [0; 7];
+            stream.read_exact(&mut reply).unwrap();
+            assert_eq!(&reply, b"\x81\x05Hello");
+
+            stream
+                .write_all(&client_frame(Opcode::Close, &[3, 232], true))
+                .unwrap();
+            let mut close = [0; 4];
+            stream.read_exact(&mut close).unwrap();
+            assert_eq!(close, [136, 2, 3, 232]);
+        });
+        let handler = event_loop.get_handler();
+        assert_eq!(handler.opened.len(), 1);
+        assert_eq!(
+            handler.closed,
+            vec![(handler.opened[0], Some(CloseFrame::new(1000, "")))]
+        );
+    }
+    #[test]
+    fn should_resume_partial_writes() {
+        let mut event_loop =
+            EventLoop::bind("127.0.0.1:0".parse().unwrap(), Echo::default()).unwrap();
+        run_until_done(&mut event_loop, |mut stream| {
+            stream.write_all(REQUEST).unwrap();
+            let mut response = [0; 129];
+            stream.read_exact(&mut response).unwrap();
+
+            let payload = vec![7; 4 << 20];
+            let mut writer = stream.try_clone().unwrap();
+            let frame = client_frame(Opcode::Binary, &payload, true);
+            let sending = thread::spawn(move || writer.write_all(&frame).unwrap());
+
+            let mut reply = vec![0; 10 + payload.len()];
+            stream.read_exact(&mut reply).unwrap();
+            sending.join().unwrap();
+            assert_eq!(reply[..2], [130, 127]);
+            assert!(reply[10..] == payload[..]);
+        });
+        assert_eq!(event_loop.get_handler().closed.len(), 1);
+    }
+    #[test]
+    fn should_reject_handshakes_without_key() {
+        let mut event_loop =
+            EventLoop::bind("127.0.0.1:0".parse().unwrap(), Echo::default()).unwrap();
+        run_until_done(&mut event_loop, |mut stream| {
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).unwrap();
+            assert!(response.starts_with(b"HTTP/1.1 400 Bad Request\r\n"));
+        });
+        assert!(event_loop.get_handler().opened.is_empty());
+        assert!(event_loop.get_handler().closed.is_empty());
+    }
+    #[test]
+    fn should_report_handshakes_frames_and_messages() {
+        let metrics = Arc::new(AtomicMetrics::new());
+        let config = WebSocketConfig {
+            metrics: Some(metrics.clone()),
+            ..WebSocketConfig::default()
+        };
+        let bind = "127.0.0.1:0".parse().unwrap();
+        let mut event_loop = EventLoop::bind_with_config(bind, config, Echo::default()).unwrap();
+        let address = event_loop.local_addr().unwrap();
+        run_until_done(&mut event_loop, move |mut stream| {
+            stream.write_all(REQUEST).unwrap();
+            let mut response = [0; 129];
+            stream.read_exact(&mut response).unwrap();
+            stream
+                .write_all(&client_frame(Opcode::Text, b"Hello", true))
+                .unwrap();
+            let mut reply = [0; 7];
+            stream.read_exact(&mut reply).unwrap();
+
+            let mut rejected = std::net::TcpStream::connect(address).unwrap();
+            rejected
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            rejected.read_to_end(&mut Vec::new()).unwrap();
+        });
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.handshakes_succeeded, 1);
+        assert_eq!(snapshot.handshakes_failed["invalid_request"], 1);
+        assert_eq!(snapshot.frames_in["text"].frames, 1);
+        assert_eq!(snapshot.message_sizes_in.sum, 5);
+        assert_eq!(snapshot.frames_out["text"].frames, 1);
+        assert_eq!(snapshot.message_sizes_out.sum, 5);
+    }
+    #[test]
+    fn should_time_out_slow_handshakes_and_idle_connections() {
+        let config = WebSocketConfig {
+            handshake_timeout: Some(Duration::from_millis(50)),
+            idle_timeout: Some(Duration::from_millis(50)),
+            ..WebSocketConfig::default()
+        };
+        let bind = "127.0.0.1:0".parse().unwrap();
+        let mut event_loop = EventLoop::bind_with_config(bind, config, Echo::default()).unwrap();
+        let address = event_loop.local_addr().unwrap();
+        run_until_done(&mut event_loop, move |mut stream| {
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).unwrap();
+            assert!(response.starts_with(b"HTTP/1.1 408 Request Timeout\r\n"));
+
+            let mut stream = std::net::TcpStream::connect(address).unwrap();
+            stream.write_all(REQUEST).unwrap();
+            let mut response = [0; 129];
+            stream.read_exact(&mut response).unwrap();
+            let mut close = [0; 4];
+            stream.read_exact(&mut close).unwrap();
+            assert_eq!(close, [136, 2, 3, 233]);
+        });
+        assert_eq!(event_loop.get_handler().opened.len(), 1);
+    }
+    #[test]
+    fn should_ping_idle_peers_and_drop_silent_ones() {
+        let config = WebSocketConfig {
+            ping_interval: Some(Duration::from_millis(30)),
+            keepalive_timeout: Duration::from_millis(30),
+            ..WebSocketConfig::default()
+        };
+        let bind = "127.0.0.1:0".parse().unwrap();
+        let mut event_loop = EventLoop::bind_with_config(bind, config, Echo::default()).unwrap();
+        run_until_done(&mut event_loop, |mut stream| {
+            stream.write_all(REQUEST).unwrap();
+            let mut response = [0; 129];
+            stream.read_exact(&mut response).unwrap();
+
+            let mut ping = [0; 2];
+            stream.read_exact(&mut ping).unwrap();
+            assert_eq!(ping, [137, 0]);
+            stream
+                .write_all(&client_frame(Opcode::Pong, &[], true))
+                .unwrap();
+            stream.read_exact(&mut ping).unwrap();
+            assert_eq!(ping, [137, 0]);
+            let mut close = [0; 4];
+            stream.read_exact(&mut close).unwrap();
+            assert_eq!(close, [136, 2, 3, 233]);
+        });
+        assert_eq!(event_loop.get_handler().closed.len(), 1);
+    }
+    #[test]
+    fn should_drop_oversized_headers_while_they_arrive() {
+        let mut event_loop =
+            EventLoop::bind("127.0.0.1:0".parse().unwrap(), Echo::default()).unwrap();
+        run_until_done(&mut event_loop, |mut stream| {
+            let mut writer = stream.try_clone().unwrap();
+            // The loop stops reading long before all of this is sent
+            let sending = thread::spawn(move || {
+                let _ = writer.write_all(&vec![b'a'; 16 << 20]);
+            });
+            let mut response = Vec::new();
+            let _ = stream.read_to_end(&mut response);
+            assert!(response.is_empty());
+            sending.join().unwrap();
+        });
+        assert!(event_loop.get_handler().opened.is_empty());
+    }
+}
@@ -0,0 +1,526 @@
+use crate::dataframe::{Opcode, ReadMessage};
+use crate::message::{close_code, Message};
+use crate::server::Connection;
+use crate::websocket::WebSocket;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{Read, Write};
+
+/// How typed values are turned into message payloads and back
+pub trait Format {
+    /// The kind of message the payloads are sent in
+    const OPCODE: Opcode;
+    type Error: std::error::Error + Send + Sync + 'static;
+    fn encode<T>(value: &T) -> Result<Vec<u8>, Self::Error>
+    where
+        T: Serialize + ?Sized;
+    fn decode<T>(payload: &[u8]) -> Result<T, Self::Error>
+    where
+        T: DeserializeOwned;
+}
+/// A [`Format`] that can decode strings and bytes by borrowing them from the payload
+pub trait BorrowFormat: Format {
+    fn decode_borrowed<'a, T>(payload: &'a [u8]) -> Result<T, Self::Error>
+    where
+        T: Deserialize<'a>;
+}
+
+/// JSON in text messages
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Json;
+impl Format for Json {
+    const OPCODE: Opcode = Opcode::Text;
+    type Error = serde_json::Error;
+    fn encode<T>(value: &T) -> Result<Vec<u8>, Self::Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        serde_json::to_vec(value)
+    }
+    fn decode<T>(payload: &[u8]) -> Result<T, Self::Error>
+    where
+        T: DeserializeOwned,
+    {
+        serde_json::from_slice(payload)
+    }
+}
+impl BorrowFormat for Json {
+    fn decode_borrowed<'a, T>(payload: &'a [u8]) -> Result<T, Self::Error>
+    where
+        T: Deserialize<'a>,
+    {
+        serde_json::from_slice(payload)
+    }
+}
+
+/// Why a typed value couldn't be sent or received
+#[derive(Debug)]
+pub enum FormatError<E> {
+    /// The value couldn't be encoded
+    Encode(E),
+    /// The payload doesn't decode to the requested type
+    Decode(E),
+    /// The message is of another kind than the format uses, like binary for JSON
+    UnexpectedMessage(Opcode),
+    /// A text format encoded the value to bytes that aren't UTF-8
+    InvalidUtf8,
+    /// Reading or writing the message failed
+    Connection(crate::error::Error),
+}
+impl<E> FormatError<E> {
+    /// The close code for refusing a received message: 1007 for bad payloads, 1003 for unexpected messages.
+    ///
+    /// Errors in sending are our own and have none. Nothing is closed automatically; pass it
+    /// to `close` to give up on the peer.
+    pub fn close_code(&self) -> Option<u16> {
+        match self {
+            FormatError::Decode(_) => Some(close_code::INVALID_PAYLOAD),
+            FormatError::UnexpectedMessage(_) => Some(close_code::UNSUPPORTED_DATA),
+            FormatError::Encode(_) | FormatError::InvalidUtf8 | FormatError::Connection(_) => None,
+        }
+    }
+}
+impl<E> fmt::Display for FormatError<E>
+where
+    E: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::Encode(error) => write!(f, "encoding failed: {}", error),
+            FormatError::Decode(error) => write!(f, "decoding failed: {}", error),
+            FormatError::UnexpectedMessage(opcode) => {
+                write!(f, "unexpected {} message", opcode.get_name())
+            }
+            FormatError::InvalidUtf8 => f.write_str("encoded text is not valid UTF-8"),
+            FormatError::Connection(error) => error.fmt(f),
+        }
+    }
+}
+impl<E> std::error::Error for FormatError<E>
+where
+    E: std::error::Error + 'static,
+{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FormatError::Encode(error) | FormatError::Decode(error) => Some(error),
+            FormatError::UnexpectedMessage(_) | FormatError::InvalidUtf8 => None,
+            FormatError::Connection(error) => Some(error),
+        }
+    }
+}
+impl<E> From<crate::error::Error> for FormatError<E> {
+    fn from(error: crate::error::Error) -> Self {
+        FormatError::Connection(error)
+    }
+}
+
+pub type JsonError = FormatError<serde_json::Error>;
+
+/// The error of a format with separate error types for encoding and decoding
+#[derive(Debug)]
+pub enum CodecError<E, D> {
+    Encode(E),
+    Decode(D),
+}
+impl<E, D> fmt::Display for CodecError<E, D>
+where
+    E: fmt::Display,
+    D: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Encode(error) => write!(f, "encoding failed: {}", error),
+            CodecError::Decode(error) => write!(f, "decoding failed: {}", error),
+        }
+    }
+}
+impl<E, D> std::error::Error for CodecError<E, D>
+where
+    E: std::error::Error + 'static,
+    D: std::error::Error + 'static,
+{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CodecError::Encode(error) => Some(error),
+            CodecError::Decode(error) => Some(error),
+        }
+    }
+}
+
+/// MessagePack in binary messages, with structs encoded as maps
+#[cfg(feature = "rmp-serde")]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MessagePack;
+#[cfg(feature = "rmp-serde")]
+impl Format for MessagePack {
+    const OPCODE: Opcode = Opcode::Binary;
+    type Error = CodecError<rmp_serde::encode::Error, rmp_serde::decode::Error>;
+    fn encode<T>(value: &T) -> Result<Vec<u8>, Self::Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        rmp_serde::to_vec_named(value).map_err(CodecError::Encode)
+    }
+    fn decode<T>(payload: &[u8]) -> Result<T, Self::Error>
+    where
+        T: DeserializeOwned,
+    {
+        rmp_serde::from_slice(payload).map_err(CodecError::Decode)
+    }
+}
+#[cfg(feature = "rmp-serde")]
+impl BorrowFormat for MessagePack {
+    fn decode_borrowed<'a, T>(payload: &'a [u8]) -> Result<T, Self::Error>
+    where
+        T: Deserialize<'a>,
+    {
+        rmp_serde::from_slice(payload).map_err(CodecError::Decode)
+    }
+}
+
+/// CBOR in binary messages; ciborium can't borrow from the payload, so it's no [`BorrowFormat`]
+#[cfg(feature = "ciborium")]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Cbor;
+#[cfg(feature = "ciborium")]
+impl Format for Cbor {
+    const OPCODE: Opcode = Opcode::Binary;
+    type Error =
+        CodecError<ciborium::ser::Error<std::io::Error>, ciborium::de::Error<std::io::Error>>;
+    fn encode<T>(value: &T) -> Result<Vec<u8>, Self::Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        let mut payload = Vec::new();
+        ciborium::into_writer(value, &mut payload).map_err(CodecError::Encode)?;
+        Ok(payload)
+    }
+    fn decode<T>(payload: &[u8]) -> Result<T, Self::Error>
+    where
+        T: DeserializeOwned,
+    {
+        ciborium::from_reader(payload).map_err(CodecError::Decode)
+    }
+}
+
+impl Message {
+    /// Encode `value` as a text or binary message, depending on `F`
+    pub fn encode<F, T>(value: &T) -> Result<Message, FormatError<F::Error>>
+    where
+        F: Format,
+        T: Serialize + ?Sized,
+    {
+        let payload = F::encode(value).map_err(FormatError::Encode)?;
+        match F::OPCODE {
+            Opcode::Text => String::from_utf8(payload)
+                .map(Message::Text)
+                .map_err(|_| FormatError::InvalidUtf8),
+            _ => Ok(Message::Binary(payload)),
+        }
+    }
+    pub fn json<T>(value: &T) -> Result<Message, serde_json::Error>
+    where
+        T: Serialize + ?Sized,
+    {
+        serde_json::to_string(value).map(Message::Text)
+    }
+    /// Decode the payload of a text or binary message, depending on `F`
+    pub fn decode<F, T>(&self) -> Result<T, FormatError<F::Error>>
+    where
+        F: Format,
+        T: DeserializeOwned,
+    {
+        match (self, F::OPCODE) {
+            (Message::Text(text), Opcode::Text) => F::decode(text.as_bytes()),
+            (Message::Binary(data), Opcode::Binary) => F::decode(data),
+            (message, _) => return Err(FormatError::UnexpectedMessage(message.get_opcode())),
+        }
+        .map_err(FormatError::Decode)
+    }
+}
+
+impl<'a> ReadMessage<'a> {
+    fn get_opcode(&self) -> Opcode {
+        match self {
+            ReadMessage::Text(_) => Opcode::Text,
+            ReadMessage::Binary(_) => Opcode::Binary,
+            ReadMessage::Ping(_) => Opcode::Ping,
+            ReadMessage::Pong(_) => Opcode::Pong,
+            ReadMessage::Close => Opcode::Close,
+        }
+    }
+    /// The payload if this is the kind of message `F` uses
+    fn get_format_payload<F>(&self) -> Result<&[u8], FormatError<F::Error>>
+    where
+        F: Format,
+    {
+        match (self, F::OPCODE) {
+            (ReadMessage::Text(text), Opcode::Text) => Ok(text.as_bytes()),
+            (ReadMessage::Binary(data), Opcode::Binary) => Ok(data),
+            (message, _) => Err(FormatError::UnexpectedMessage(message.get_opcode())),
+        }
+    }
+    /// Decode the payload with `F` into an owned value
+    pub fn decode<F, T>(&self) -> Result<T, FormatError<F::Error>>
+    where
+        F: Format,
+        T: DeserializeOwned,
+    {
+        let payload = self.get_format_payload::<F>()?;
+        F::decode(payload).map_err(FormatError::Decode)
+    }
+    /// Decode the payload with `F`, borrowing strings and bytes from it
+    pub fn parse<'b, F, T>(&'b self) -> Result<T, FormatError<F::Error>>
+    where
+        F: BorrowFormat,
+        T: Deserialize<'b>,
+    {
+        let payload = self.get_format_payload::<F>()?;
+        F::decode_borrowed(payload).map_err(FormatError::Decode)
+    }
+    /// Decode a text payload as JSON; `&str` fields borrow from the frame unless they contain escapes
+    pub fn parse_json<'b, T>(&'b self) -> Result<T, JsonError>
+    where
+        T: Deserialize<'b>,
+    {
+        self.parse::<Json, T>()
+    }
+}
+
+impl<S> WebSocket<S>
+where
+    S: Read + Write,
+{
+    pub fn send_as<F, T>(&mut self, value: &T) -> Result<(), FormatError<F::Error>>
+    where
+        F: Format,
+        T: Serialize + ?Sized,
+    {
+        let message = Message::encode::<F, T>(value)?;
+        Ok(self.write_message(message)?)
+    }
+    pub fn send_json<T>(&mut self, value: &T) -> Result<(), JsonError>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.send_as::<Json, T>(value)
+    }
+    /// Read the next text or binary message and decode it with `F`, skipping pings and pongs.
+    ///
+    /// A close message of the peer ends it with [`crate::error::Error::ConnectionClosed`],
+    /// its close frame is kept in [`WebSocket::get_close_frame`].
+    pub fn recv_as<F, T>(&mut self) -> Result<T, FormatError<F::Error>>
+    where
+        F: Format,
+        T: DeserializeOwned,
+    {
+        loop {
+            match self.read_message()? {
+                Message::Ping(_) | Message::Pong(_) => continue,
+                Message::Close => return Err(crate::error::Error::ConnectionClosed.into()),
+                message => return message.decode::<F, T>(),
+            }
+        }
+    }
+    pub fn recv_json<T>(&mut self) -> Result<T, JsonError>
+    where
+        T: DeserializeOwned,
+    {
+        self.recv_as::<Json, T>()
+    }
+}
+
+impl Connection {
+    pub fn send_as<F, T>(&mut self, value: &T) -> Result<(), FormatError<F::Error>>
+    where
+        F: Format,
+        T: Serialize + ?Sized,
+    {
+        self.get_websocket_mut().send_as::<F, T>(value)
+    }
+    pub fn send_json<T>(&mut self, value: &T) -> Result<(), JsonError>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.send_as::<Json, T>(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dataframe::DataFrame;
+    use crate::message::CloseFrame;
+    use crate::websocket::tests::{client_frame, MockStream, REQUEST};
+    use std::borrow::Cow;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Chat<'a> {
+        room: u32,
+        text: &'a str,
+        from: Cow<'a, str>,
+    }
+
+    #[test]
+    fn should_borrow_json_from_read_messages() {
+        let frame = DataFrame::new(client_frame(
+            Opcode::Text,
+            br#"{"room":1,"text":"hi","from":"a\"b"}"#,
+            true,
+        ));
+        let message = frame.get_message().unwrap();
+        let chat: Chat<'_> = message.parse_json().unwrap();
+        assert_eq!(chat.text, "hi");
+        assert!(matches!(chat.from, Cow::Owned(ref from) if from == "a\"b"));
+
+        let frame = DataFrame::new(client_frame(Opcode::Text, br#"{"room":"1"}"#, true));
+        let error = frame
+            .get_message()
+            .unwrap()
+            .parse_json::<Chat<'_>>()
+            .unwrap_err();
+        assert!(matches!(error, FormatError::Decode(_)));
+        assert_eq!(error.close_code(), Some(close_code::INVALID_PAYLOAD));
+
+        let frame = DataFrame::new(client_frame(Opcode::Binary, b"{}", true));
+        let error = frame
+            .get_message()
+            .unwrap()
+            .parse_json::<Chat<'_>>()
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            FormatError::UnexpectedMessage(Opcode::Binary)
+        ));
+        assert_eq!(error.close_code(), Some(close_code::UNSUPPORTED_DATA));
+    }
+    #[test]
+    fn should_send_and_receive_json() {
+        let chat = Chat {
+            room: 7,
+            text: "hello",
+            from: Cow::Borrowed("ann"),
+        };
+        let json = Message::json(&chat).unwrap();
+        assert!(
+            matches!(json, Message::Text(ref text) if text == r#"{"room":7,"text":"hello","from":"ann"}"#)
+        );
+
+        let mut input = REQUEST.to_vec();
+        input.extend_from_slice(&client_frame(Opcode::Ping, b"", true));
+        input.extend_from_slice(&client_frame(Opcode::Text, json.as_ref(), true));
+        input.extend_from_slice(&client_frame(Opcode::Text, b"[1, 2", true));
+        let mut websocket = WebSocket::accept(MockStream::new(input)).unwrap();
+        let received: serde_json::Value = websocket.recv_json().unwrap();
+        assert_eq!(received["text"], "hello");
+
+        let error = websocket.recv_json::<serde_json::Value>().unwrap_err();
+        let code = error.close_code().unwrap();
+        websocket.close(CloseFrame::new(code, "")).unwrap();
+        websocket.send_json(&chat).unwrap_err();
+        let written = &websocket.get_ref().output;
+        assert!(written.ends_with(&[136, 2, 3, 239]));
+    }
+    #[test]
+    fn should_end_receiving_at_the_close_of_the_peer() {
+        let mut input = REQUEST.to_vec();
+        input.extend_from_slice(&client_frame(Opcode::Close, &[3, 232], true));
+        let mut websocket = WebSocket::accept(MockStream::new(input)).unwrap();
+        let error = websocket.recv_json::<serde_json::Value>().unwrap_err();
+        assert!(matches!(
+            error,
+            FormatError::Connection(crate::error::Error::ConnectionClosed)
+        ));
+        assert_eq!(error.close_code(), None);
+        assert_eq!(
+            websocket.get_close_frame(),
+            Some(&CloseFrame::new(close_code::NORMAL, ""))
+        );
+    }
+    #[test]
+    fn should_refuse_text_formats_that_encode_invalid_utf8() {
+        struct Latin1;
+        impl Format for Latin1 {
+            const OPCODE: Opcode = Opcode::Text;
+            type Error = serde_json::Error;
+            fn encode<T>(value: &T) -> Result<Vec<u8>, Self::Error>
+            where
+                T: Serialize + ?Sized,
+            {
+                let text: String = serde_json::from_value(serde_json::to_value(value)?)?;
+                Ok(text.chars().map(|char| char as u8).collect())
+            }
+            fn decode<T>(payload: &[u8]) -> Result<T, Self::Error>
+            where
+                T: DeserializeOwned,
+            {
+                serde_json::from_slice(payload)
+            }
+        }
+        assert!(matches!(
+            Message::encode::<Latin1, _>("abc"),
+            Ok(Message::Text(ref text)) if text == "abc"
+        ));
+        let error = Message::encode::<Latin1, _>("caf\u{e9}").unwrap_err();
+        assert!(matches!(error, FormatError::InvalidUtf8));
+        assert_eq!(error.close_code(), None);
+        let error = Message::encode::<Latin1, _>(&1).unwrap_err();
+        assert!(matches!(error, FormatError::Encode(_)));
+        assert_eq!(error.close_code(), None);
+    }
+    #[cfg(any(feature = "rmp-serde", feature = "ciborium"))]
+    fn should_round_trip_binary<F>()
+    where
+        F: Format,
+    {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Position {
+            x: i32,
+            y: i32,
+            name: String,
+        }
+        let position = Position {
+            x: -3,
+            y: 4,
+            name: String::from("home"),
+        };
+        let message = Message::encode::<F, _>(&position).unwrap();
+        assert!(matches!(message, Message::Binary(_)));
+        assert_eq!(message.decode::<F, Position>().unwrap(), position);
+        let frame = DataFrame::new(client_frame(Opcode::Binary, message.as_ref(), true));
+        let read = frame.get_message().unwrap();
+        assert_eq!(read.decode::<F, Position>().unwrap(), position);
+
+        let error = Message::Binary(vec![0xc1])
+            .decode::<F, Position>()
+            .unwrap_err();
+        assert_eq!(error.close_code(), Some(close_code::INVALID_PAYLOAD));
+        let error = Message::Text(String::from("{}"))
+            .decode::<F, Position>()
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            FormatError::UnexpectedMessage(Opcode::Text)
+        ));
+    }
+    #[cfg(feature = "rmp-serde")]
+    #[test]
+    fn should_encode_message_pack_and_borrow_from_it() {
+        should_round_trip_binary::<MessagePack>();
+        let chat = Chat {
+            room: 2,
+            text: "borrowed",
+            from: Cow::Borrowed("bob"),
+        };
+        let message = Message::encode::<MessagePack, _>(&chat).unwrap();
+        let frame = DataFrame::new(client_frame(Opcode::Binary, message.as_ref(), true));
+        let read = frame.get_message().unwrap();
+        assert_eq!(read.parse::<MessagePack, Chat<'_>>().unwrap(), chat);
+    }
+    #[cfg(feature = "ciborium")]
+    #[test]
+    fn should_encode_cbor() {
+        should_round_trip_binary::<Cbor>();
+    }
+}
@@ -0,0 +1,185 @@
+use crate::message::{close_code, Message, WriteMessage};
+use crate::queue::{FullPolicy, SendQueue};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// What a broadcast does for a subscriber whose send queue is full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LagPolicy {
+    /// The lagging subscriber misses this message
+    Skip,
+    /// The lagging subscriber misses its oldest queued messages instead
+    DropOldest,
+    /// Close the lagging subscriber's connection with this code
+    Disconnect(u16),
+}
+impl From<LagPolicy> for FullPolicy {
+    fn from(policy: LagPolicy) -> Self {
+        match policy {
+            LagPolicy::Skip => FullPolicy::DropNewest,
+            LagPolicy::DropOldest => FullPolicy::DropOldest,
+            LagPolicy::Disconnect(code) => FullPolicy::Disconnect(code),
+        }
+    }
+}
+impl Default for LagPolicy {
+    fn default() -> Self {
+        LagPolicy::Disconnect(close_code::TRY_AGAIN_LATER)
+    }
+}
+
+/// Named rooms of connections for fan-out.
+///
+/// Members are the [`SendQueue`]s of their connections. A broadcast encodes the
+/// message once and puts the same frame in every member's queue without waiting
+/// for any of them; closed queues leave their rooms on the next broadcast.
+#[derive(Debug, Clone, Default)]
+pub struct Hub {
+    rooms: Arc<Mutex<HashMap<String, Vec<SendQueue>>>>,
+    policy: LagPolicy,
+}
+impl Hub {
+    pub fn new(policy: LagPolicy) -> Self {
+        Hub {
+            rooms: Arc::default(),
+            policy,
+        }
+    }
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Vec<SendQueue>>> {
+        self.rooms
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    pub fn get_policy(&self) -> LagPolicy {
+        self.policy
+    }
+    /// Add `queue` to `room`; joining twice has no effect
+    pub fn join(&self, room: &str, queue: &SendQueue) {
+        let mut rooms = self.lock();
+        let members = rooms.entry(String::from(room)).or_default();
+        if !members.iter().any(|member| member.same_queue(queue)) {
+            members.push(queue.clone());
+        }
+    }
+    pub fn leave(&self, room: &str, queue: &SendQueue) {
+        let mut rooms = self.lock();
+        if let Some(members) = rooms.get_mut(room) {
+            members.retain(|member| !member.same_queue(queue));
+            if members.is_empty() {
+                rooms.remove(room);
+            }
+        }
+    }
+    /// Remove `queue` from every room, usually when its connection closes
+    pub fn leave_all(&self, queue: &SendQueue) {
+        let mut rooms = self.lock();
+        rooms
+            .values_mut()
+            .for_each(|members| members.retain(|member| !member.same_queue(queue)));
+        rooms.retain(|_, members| !members.is_empty());
+    }
+    pub fn rooms(&self) -> Vec<String> {
+        self.lock().keys().cloned().collect()
+    }
+    pub fn member_count(&self, room: &str) -> usize {
+        self.lock().get(room).map(Vec::len).unwrap_or(0)
+    }
+    /// Encode `message` once and queue it for every member of `room`.
+    ///
+    /// Returns how many members it was queued for.
+    pub fn broadcast(&self, room: &str, message: Message) -> usize {
+        self.broadcast_frame(room, Arc::new(WriteMessage::from(message)))
+    }
+    /// Like [`Hub::broadcast`] for a frame that is already encoded
+    pub fn broadcast_frame(&self, room: &str, frame: Arc<WriteMessage>) -> usize {
+        let policy = FullPolicy::from(self.policy);
+        let mut rooms = self.lock();
+        let members = match rooms.get_mut(room) {
+            Some(members) => members,
+            None => return 0,
+        };
+        let mut queued = 0;
+        members.retain(
+            |member| match member.send_with_policy(frame.clone(), policy) {
+                Ok(sent) => {
+                    queued += sent as usize;
+                    true
+                }
+                Err(_) => false,
+            },
+        );
+        if members.is_empty() {
+            rooms.remove(room);
+        }
+        queued
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::QueueConfig;
+
+    fn queue() -> SendQueue {
+        SendQueue::new(QueueConfig {
+            high_watermark: 16,
+            low_watermark: 0,
+            ..QueueConfig::default()
+        })
+    }
+
+    #[test]
+    fn should_share_one_frame_between_members() {
+        let hub = Hub::default();
+        let (first, second, outsider) = (queue(), queue(), queue());
+        hub.join("lobby", &first);
+        hub.join("lobby", &second);
+        hub.join("lobby", &second);
+        hub.join("other", &outsider);
+        assert_eq!(hub.member_count("lobby"), 2);
+
+        assert_eq!(hub.broadcast("lobby", Message::Text(String::from("Hi"))), 2);
+        let (first, second) = (first.pop().unwrap(), second.pop().unwrap());
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(first.get_output(), b"\x81\x02Hi");
+        assert_eq!(outsider.depth(), 0);
+        assert_eq!(
+            hub.broadcast("nobody", Message::Text(String::from("Hi"))),
+            0
+        );
+    }
+    #[test]
+    fn should_leave_rooms() {
+        let hub = Hub::default();
+        let (first, second) = (queue(), queue());
+        hub.join("a", &first);
+        hub.join("b", &first);
+        hub.join("b", &second);
+        hub.leave("a", &first);
+        assert_eq!(hub.rooms(), vec![String::from("b")]);
+        hub.leave_all(&first);
+        assert_eq!(hub.member_count("b"), 1);
+        assert_eq!(hub.broadcast("b", Message::Binary(vec![1])), 1);
+        assert_eq!(first.depth(), 0);
+    }
+    #[test]
+    fn should_handle_lagging_members_by_policy() {
+        let message = || Message::Binary(vec![0; 14]);
+        let skip = Hub::new(LagPolicy::Skip);
+        let (lagging, quick) = (queue(), queue());
+        skip.join("room", &lagging);
+        skip.join("room", &quick);
+        assert_eq!(skip.broadcast("room", message()), 2);
+        quick.pop();
+        assert_eq!(skip.broadcast("room", message()), 1);
+        assert_eq!((lagging.depth(), lagging.dropped()), (1, 1));
+
+        let disconnect = Hub::new(LagPolicy::Disconnect(close_code::POLICY_VIOLATION));
+        disconnect.join("room", &lagging);
+        disconnect.join("room", &quick);
+        quick.pop();
+        assert_eq!(disconnect.broadcast("room", message()), 1);
+        assert_eq!(disconnect.member_count("room"), 1);
+        assert!(lagging.is_closed());
+    }
+}
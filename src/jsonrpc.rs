@@ -0,0 +1,741 @@
+use crate::error::Error;
+use crate::message::Message;
+use crate::server::{Connection, Handler};
+use crate::websocket::{is_timeout, WebSocket};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The error codes reserved by JSON-RPC 2.0
+pub mod error_code {
+    /// The message isn't valid JSON
+    pub const PARSE_ERROR: i64 = -32700;
+    /// The JSON isn't a request object
+    pub const INVALID_REQUEST: i64 = -32600;
+    pub const METHOD_NOT_FOUND: i64 = -32601;
+    pub const INVALID_PARAMS: i64 = -32602;
+    pub const INTERNAL_ERROR: i64 = -32603;
+}
+
+/// An error object, sent in place of a result
+#[derive(Debug, Clone, PartialEq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    pub data: Option<Value>,
+}
+impl RpcError {
+    pub fn new(code: i64, message: &str) -> Self {
+        RpcError {
+            code,
+            message: String::from(message),
+            data: None,
+        }
+    }
+    pub fn with_data(mut self, data: Value) -> Self {
+        self.data = Some(data);
+        self
+    }
+    pub fn parse_error() -> Self {
+        RpcError::new(error_code::PARSE_ERROR, "Parse error")
+    }
+    pub fn invalid_request() -> Self {
+        RpcError::new(error_code::INVALID_REQUEST, "Invalid Request")
+    }
+    pub fn method_not_found() -> Self {
+        RpcError::new(error_code::METHOD_NOT_FOUND, "Method not found")
+    }
+    /// `reason` is sent along as `data`
+    pub fn invalid_params(reason: &str) -> Self {
+        RpcError::new(error_code::INVALID_PARAMS, "Invalid params").with_data(Value::from(reason))
+    }
+    pub fn internal_error() -> Self {
+        RpcError::new(error_code::INTERNAL_ERROR, "Internal error")
+    }
+    fn to_value(&self) -> Value {
+        let mut object = Map::new();
+        object.insert(String::from("code"), Value::from(self.code));
+        object.insert(String::from("message"), Value::from(self.message.as_str()));
+        if let Some(data) = &self.data {
+            object.insert(String::from("data"), data.clone());
+        }
+        Value::Object(object)
+    }
+    fn from_value(value: &Value) -> Option<Self> {
+        Some(RpcError {
+            code: value.get("code")?.as_i64()?,
+            message: String::from(value.get("message")?.as_str()?),
+            data: value.get("data").cloned(),
+        })
+    }
+}
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+impl std::error::Error for RpcError {}
+
+/// The id that pairs a response with its request
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Id {
+    Number(i64),
+    String(String),
+    /// Used for responses to requests whose id couldn't be read
+    Null,
+}
+impl Id {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Number(number) => number.as_i64().map(Id::Number),
+            Value::String(string) => Some(Id::String(string.clone())),
+            Value::Null => Some(Id::Null),
+            _ => None,
+        }
+    }
+    fn to_value(&self) -> Value {
+        match self {
+            Id::Number(number) => Value::from(*number),
+            Id::String(string) => Value::from(string.as_str()),
+            Id::Null => Value::Null,
+        }
+    }
+}
+
+/// A method call, or a notification if it has no id
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: String,
+    /// An array or an object
+    pub params: Option<Value>,
+    pub id: Option<Id>,
+}
+impl Request {
+    pub fn is_notification(&self) -> bool {
+        self.id.is_none()
+    }
+    /// Deserialize the params, treating missing params as `null`
+    pub fn parse_params<P>(&self) -> Result<P, RpcError>
+    where
+        P: DeserializeOwned,
+    {
+        let params = self.params.clone().unwrap_or(Value::Null);
+        serde_json::from_value(params).map_err(|error| RpcError::invalid_params(&error.to_string()))
+    }
+    fn to_value(&self) -> Value {
+        let mut object = Map::new();
+        object.insert(String::from("jsonrpc"), Value::from("2.0"));
+        object.insert(String::from("method"), Value::from(self.method.as_str()));
+        if let Some(params) = &self.params {
+            object.insert(String::from("params"), params.clone());
+        }
+        if let Some(id) = &self.id {
+            object.insert(String::from("id"), id.to_value());
+        }
+        Value::Object(object)
+    }
+    fn from_value(value: &Value) -> Result<Self, RpcError> {
+        let id = match value.get("id") {
+            Some(id) => Some(Id::from_value(id).ok_or_else(RpcError::invalid_request)?),
+            None => None,
+        };
+        let params = value.get("params").cloned();
+        if value.get("jsonrpc") != Some(&Value::from("2.0"))
+            || !matches!(
+                params,
+                None | Some(Value::Array(_)) | Some(Value::Object(_))
+            )
+        {
+            return Err(RpcError::invalid_request());
+        }
+        match value.get("method") {
+            Some(Value::String(method)) => Ok(Request {
+                method: method.clone(),
+                params,
+                id,
+            }),
+            _ => Err(RpcError::invalid_request()),
+        }
+    }
+}
+
+/// The answer to a [`Request`] that has an id
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub id: Id,
+    pub result: Result<Value, RpcError>,
+}
+impl Response {
+    fn to_value(&self) -> Value {
+        let mut object = Map::new();
+        object.insert(String::from("jsonrpc"), Value::from("2.0"));
+        match &self.result {
+            Ok(result) => object.insert(String::from("result"), result.clone()),
+            Err(error) => object.insert(String::from("error"), error.to_value()),
+        };
+        object.insert(String::from("id"), self.id.to_value());
+        Value::Object(object)
+    }
+    fn from_value(value: &Value) -> Option<Self> {
+        let id = Id::from_value(value.get("id")?)?;
+        let result = match (value.get("result"), value.get("error")) {
+            (Some(result), None) => Ok(result.clone()),
+            (None, Some(error)) => Err(RpcError::from_value(error)?),
+            _ => return None,
+        };
+        Some(Response { id, result })
+    }
+}
+
+/// The most requests a batch can hold; longer batches are answered with [`error_code::INVALID_REQUEST`]
+pub const MAX_BATCH_LENGTH: usize = 100;
+/// The most server requests a [`Client`] keeps for [`Client::take_requests`], dropping the oldest
+pub const MAX_STORED_REQUESTS: usize = 1024;
+
+type Method = Arc<dyn Fn(&Request) -> Result<Value, RpcError> + Send + Sync>;
+
+/// The methods of a JSON-RPC server.
+///
+/// Serve them with [`crate::server::Server::serve`], where every text message is
+/// handled as a request or a batch, or on a single connection with [`Methods::serve`].
+#[derive(Clone, Default)]
+pub struct Methods {
+    methods: HashMap<String, Method>,
+}
+impl Methods {
+    pub fn new() -> Self {
+        Methods::default()
+    }
+    /// Answer calls to `name`; params that don't deserialize to `P` are answered with [`error_code::INVALID_PARAMS`]
+    pub fn register<F, P, R>(mut self, name: &str, method: F) -> Self
+    where
+        F: Fn(P) -> Result<R, RpcError> + Send + Sync + 'static,
+        P: DeserializeOwned,
+        R: Serialize,
+    {
+        let method: Method = Arc::new(move |request: &Request| {
+            let result = method(request.parse_params()?)?;
+            serde_json::to_value(result).map_err(|error| {
+                RpcError::internal_error().with_data(Value::from(error.to_string()))
+            })
+        });
+        self.methods.insert(String::from(name), method);
+        self
+    }
+    /// The reply to a request, notification or batch, `None` when there is nothing to answer
+    pub fn handle(&self, payload: &str) -> Option<String> {
+        let reply = match serde_json::from_str::<Value>(payload) {
+            Ok(Value::Array(batch)) if !batch.is_empty() && batch.len() <= MAX_BATCH_LENGTH => {
+                let responses: Vec<Value> = batch
+                    .iter()
+                    .filter_map(|value| self.call(value))
+                    .map(|response| response.to_value())
+                    .collect();
+                match responses.is_empty() {
+                    true => return None,
+                    false => Value::Array(responses),
+                }
+            }
+            Ok(Value::Array(_)) => Response {
+                id: Id::Null,
+                result: Err(RpcError::invalid_request()),
+            }
+            .to_value(),
+            Ok(value) => self.call(&value)?.to_value(),
+            Err(_) => Response {
+                id: Id::Null,
+                result: Err(RpcError::parse_error()),
+            }
+            .to_value(),
+        };
+        Some(reply.to_string())
+    }
+    fn call(&self, value: &Value) -> Option<Response> {
+        let request = match Request::from_value(value) {
+            Ok(request) => request,
+            Err(error) => {
+                let id = value.get("id").and_then(Id::from_value);
+                return Some(Response {
+                    id: id.unwrap_or(Id::Null),
+                    result: Err(error),
+                });
+            }
+        };
+        let result = match self.methods.get(&request.method) {
+            Some(method) => method(&request),
+            None => Err(RpcError::method_not_found()),
+        };
+        request.id.map(|id| Response { id, result })
+    }
+    /// Answer the requests on `websocket` until the peer closes it
+    pub fn serve<S>(&self, websocket: &mut WebSocket<S>) -> crate::error::Result<()>
+    where
+        S: Read + Write,
+    {
+        loop {
+            match websocket.read_message()? {
+                Message::Text(text) => {
+                    if let Some(reply) = self.handle(&text) {
+                        websocket.write_message(Message::Text(reply))?;
+                    }
+                }
+                Message::Close => return Ok(()),
+                _ => continue,
+            }
+        }
+    }
+}
+impl Handler for Methods {
+    fn on_message(&self, connection: &mut Connection, message: Message) {
+        if let Message::Text(text) = message {
+            if let Some(reply) = self.handle(&text) {
+                let _ = connection.send(Message::Text(reply));
+            }
+        }
+    }
+}
+
+/// Why a call didn't return a result
+#[derive(Debug)]
+pub enum CallError {
+    /// The server answered with an error object
+    Rpc(RpcError),
+    /// No response arrived in time
+    Timeout,
+    /// The params couldn't be encoded, or the result doesn't decode to the requested type
+    Json(serde_json::Error),
+    /// Reading or writing a message failed
+    Connection(Error),
+}
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallError::Rpc(error) => write!(f, "rpc error: {}", error),
+            CallError::Timeout => f.write_str("no response in time"),
+            CallError::Json(error) => write!(f, "json error: {}", error),
+            CallError::Connection(error) => write!(f, "{}", error),
+        }
+    }
+}
+impl std::error::Error for CallError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CallError::Rpc(error) => Some(error),
+            CallError::Timeout => None,
+            CallError::Json(error) => Some(error),
+            CallError::Connection(error) => Some(error),
+        }
+    }
+}
+impl From<RpcError> for CallError {
+    fn from(error: RpcError) -> Self {
+        CallError::Rpc(error)
+    }
+}
+impl From<serde_json::Error> for CallError {
+    fn from(error: serde_json::Error) -> Self {
+        CallError::Json(error)
+    }
+}
+impl From<Error> for CallError {
+    fn from(error: Error) -> Self {
+        CallError::Connection(error)
+    }
+}
+
+/// Calls and notifications sent together with [`Client::batch`]
+#[derive(Debug, Clone, Default)]
+pub struct Batch {
+    requests: Vec<(String, Value, bool)>,
+}
+impl Batch {
+    pub fn new() -> Self {
+        Batch::default()
+    }
+    /// `params` has to be an array, an object or `null` for none
+    pub fn call(mut self, method: &str, params: Value) -> Self {
+        self.requests.push((String::from(method), params, false));
+        self
+    }
+    pub fn notify(mut self, method: &str, params: Value) -> Self {
+        self.requests.push((String::from(method), params, true));
+        self
+    }
+}
+
+/// Makes calls over a websocket and pairs the responses with them by id.
+///
+/// The timeout is checked whenever a read returns, so give the stream a read timeout
+/// (like `TcpStream::set_read_timeout`) or a call can wait for the next message forever.
+/// Requests and notifications from the server are kept for [`Client::take_requests`],
+/// up to [`MAX_STORED_REQUESTS`] of the latest ones. An error response without an id,
+/// like the server's answer to a request it couldn't parse, fails the calls in flight.
+pub struct Client<S> {
+    websocket: WebSocket<S>,
+    timeout: Duration,
+    next_id: i64,
+    pending: HashSet<Id>,
+    responses: HashMap<Id, Result<Value, RpcError>>,
+    requests: VecDeque<Request>,
+}
+impl<S> Client<S>
+where
+    S: Read + Write,
+{
+    /// A client whose calls time out after 30 seconds
+    pub fn new(websocket: WebSocket<S>) -> Self {
+        Client {
+            websocket,
+            timeout: Duration::from_secs(30),
+            next_id: 1,
+            pending: HashSet::new(),
+            responses: HashMap::new(),
+            requests: VecDeque::new(),
+        }
+    }
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    pub fn get_websocket(&self) -> &WebSocket<S> {
+        &self.websocket
+    }
+    pub fn get_websocket_mut(&mut self) -> &mut WebSocket<S> {
+        &mut self.websocket
+    }
+    pub fn into_inner(self) -> WebSocket<S> {
+        self.websocket
+    }
+    /// Call `method` and wait for its result; `params` has to serialize to an array, an object or `()` for none
+    pub fn call<P, R>(&mut self, method: &str, params: P) -> Result<R, CallError>
+    where
+        P: Serialize,
+        R: DeserializeOwned,
+    {
+        let id = self.next_id();
+        let request = Request {
+            method: String::from(method),
+            params: to_params(serde_json::to_value(params)?),
+            id: Some(id.clone()),
+        };
+        self.send(request.to_value())?;
+        let mut results = self.wait(&[id])?;
+        let result = results.pop().unwrap_or(Err(RpcError::internal_error()))?;
+        Ok(serde_json::from_value(result)?)
+    }
+    /// Call `method` without waiting for, or getting, a response
+    pub fn notify<P>(&mut self, method: &str, params: P) -> Result<(), CallError>
+    where
+        P: Serialize,
+    {
+        let request = Request {
+            method: String::from(method),
+            params: to_params(serde_json::to_value(params)?),
+            id: None,
+        };
+        self.send(request.to_value())
+    }
+    /// Send all requests in one message, returning the results of the calls in the order they were added
+    pub fn batch(&mut self, batch: Batch) -> Result<Vec<Result<Value, RpcError>>, CallError> {
+        if batch.requests.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut ids = Vec::new();
+        let mut requests = Vec::new();
+        for (method, params, notification) in batch.requests {
+            let id = match notification {
+                true => None,
+                false => Some(self.next_id()),
+            };
+            ids.extend(id.clone());
+            let request = Request {
+                method,
+                params: to_params(params),
+                id,
+            };
+            requests.push(request.to_value());
+        }
+        self.send(Value::Array(requests))?;
+        self.wait(&ids)
+    }
+    /// The requests and notifications the server sent while calls were waiting
+    pub fn take_requests(&mut self) -> Vec<Request> {
+        self.requests.drain(..).collect()
+    }
+    fn next_id(&mut self) -> Id {
+        let id = Id::Number(self.next_id);
+        self.next_id += 1;
+        id
+    }
+    fn send(&mut self, value: Value) -> Result<(), CallError> {
+        self.websocket
+            .write_message(Message::Text(value.to_string()))?;
+        Ok(())
+    }
+    /// Read until every id has a response, keeping the ones for other calls
+    fn wait(&mut self, ids: &[Id]) -> Result<Vec<Result<Value, RpcError>>, CallError> {
+        self.pending.extend(ids.iter().cloned());
+        let deadline = Instant::now() + self.timeout;
+        let outcome = loop {
+            if ids.iter().all(|id| self.responses.contains_key(id)) {
+                break Ok(());
+            }
+            if Instant::now() >= deadline {
+                break Err(CallError::Timeout);
+            }
+            match self.websocket.read_message() {
+                Ok(Message::Text(text)) => self.receive(&text),
+                Ok(Message::Close) => break Err(CallError::Connection(Error::ConnectionClosed)),
+                Ok(_) => continue,
+                Err(Error::Io(error)) if is_timeout(&error) => continue,
+                Err(error) => break Err(CallError::Connection(error)),
+            }
+        };
+        // Responses that show up after giving up on a call are dropped
+        for id in ids {
+            self.pending.remove(id);
+        }
+        let results = ids.iter().filter_map(|id| self.responses.remove(id));
+        outcome.map(|_| results.collect())
+    }
+    fn receive(&mut self, text: &str) {
+        let values = match serde_json::from_str::<Value>(text) {
+            Ok(Value::Array(values)) => values,
+            Ok(value) => vec![value],
+            Err(_) => return,
+        };
+        for value in values {
+            if value.get("method").is_some() {
+                if let Ok(request) = Request::from_value(&value) {
+                    if self.requests.len() == MAX_STORED_REQUESTS {
+                        self.requests.pop_front();
+                    }
+                    self.requests.push_back(request);
+                }
+            } else if let Some(response) = Response::from_value(&value) {
+                match (response.id, response.result) {
+                    // The server couldn't read the request, and only the call or batch in flight is pending
+                    (Id::Null, Err(error)) => {
+                        for id in &self.pending {
+                            self.responses
+                                .entry(id.clone())
+                                .or_insert_with(|| Err(error.clone()));
+                        }
+                    }
+                    (id, result) if self.pending.contains(&id) => {
+                        self.responses.insert(id, result);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// Params that serialize to `null` are left out
+fn to_params(params: Value) -> Option<Value> {
+    match params {
+        Value::Null => None,
+        params => Some(params),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{client_handshake, Url};
+    use crate::net::{duplex, Duplex};
+    use crate::websocket::WebSocketConfig;
+    use serde_json::json;
+    use std::thread;
+
+    fn methods() -> Methods {
+        Methods::new()
+            .register("add", |(a, b): (i64, i64)| Ok(a + b))
+            .register("fail", |_: ()| -> Result<(), RpcError> {
+                Err(RpcError::new(7, "nope").with_data(json!("details")))
+            })
+            .register("sleep", |(millis,): (u64,)| {
+                thread::sleep(Duration::from_millis(millis));
+                Ok(millis)
+            })
+    }
+    fn reply(methods: &Methods, payload: &str) -> Option<Value> {
+        methods
+            .handle(payload)
+            .map(|reply| serde_json::from_str(&reply).unwrap())
+    }
+
+    #[test]
+    fn should_answer_requests_notifications_and_batches() {
+        let methods = methods();
+        assert_eq!(
+            reply(
+                &methods,
+                r#"{"jsonrpc":"2.0","method":"add","params":[1,2],"id":1}"#
+            ),
+            Some(json!({"jsonrpc": "2.0", "result": 3, "id": 1}))
+        );
+        assert_eq!(
+            reply(
+                &methods,
+                r#"{"jsonrpc":"2.0","method":"add","params":[1,2]}"#
+            ),
+            None
+        );
+        assert_eq!(
+            reply(&methods, r#"{"jsonrpc":"2.0","method":"fail","id":"a"}"#),
+            Some(json!({
+                "jsonrpc": "2.0",
+                "error": {"code": 7, "message": "nope", "data": "details"},
+                "id": "a"
+            }))
+        );
+        let code = |payload: &str| reply(&methods, payload).unwrap()["error"]["code"].clone();
+        assert_eq!(
+            code(r#"{"jsonrpc":"2.0","method""#),
+            json!(error_code::PARSE_ERROR)
+        );
+        assert_eq!(
+            code(r#"{"method":"add","id":1}"#),
+            json!(error_code::INVALID_REQUEST)
+        );
+        assert_eq!(code("[]"), json!(error_code::INVALID_REQUEST));
+        assert_eq!(
+            code(r#"{"jsonrpc":"2.0","method":"sub","id":1}"#),
+            json!(error_code::METHOD_NOT_FOUND)
+        );
+        assert_eq!(
+            code(r#"{"jsonrpc":"2.0","method":"add","params":["1"],"id":1}"#),
+            json!(error_code::INVALID_PARAMS)
+        );
+
+        let batch = r#"[
+            {"jsonrpc":"2.0","method":"add","params":[1,2],"id":1},
+            {"jsonrpc":"2.0","method":"add","params":[3,4]},
+            1,
+            {"jsonrpc":"2.0","method":"add","params":[5,6],"id":2}
+        ]"#;
+        assert_eq!(
+            reply(&methods, batch),
+            Some(json!([
+                {"jsonrpc": "2.0", "result": 3, "id": 1},
+                {"jsonrpc": "2.0", "error": {"code": -32600, "message": "Invalid Request"}, "id": null},
+                {"jsonrpc": "2.0", "result": 11, "id": 2}
+            ]))
+        );
+        let notifications = r#"[{"jsonrpc":"2.0","method":"add","params":[1,2]}]"#;
+        assert_eq!(reply(&methods, notifications), None);
+        let call = json!({"jsonrpc": "2.0", "method": "add", "params": [1, 2], "id": 1});
+        let batch = Value::Array(vec![call; MAX_BATCH_LENGTH + 1]).to_string();
+        assert_eq!(code(&batch), json!(error_code::INVALID_REQUEST));
+    }
+    #[test]
+    fn should_keep_only_the_latest_server_requests() {
+        let (stream, _) = duplex();
+        let mut client = Client::new(WebSocket::from_raw_stream(
+            stream,
+            WebSocketConfig::default(),
+        ));
+        for id in 0..MAX_STORED_REQUESTS + 2 {
+            let request = json!({"jsonrpc": "2.0", "method": "tick", "id": id});
+            client.receive(&request.to_string());
+        }
+        let requests = client.take_requests();
+        assert_eq!(requests.len(), MAX_STORED_REQUESTS);
+        assert_eq!(requests[0].id, Some(Id::Number(2)));
+        assert!(client.take_requests().is_empty());
+    }
+    #[test]
+    fn should_call_methods_over_a_duplex() {
+        let (client, server) = duplex();
+        let thread = thread::spawn(move || {
+            let mut websocket = WebSocket::accept(server).unwrap();
+            methods().serve(&mut websocket).unwrap();
+        });
+        let url = Url::parse("ws://localhost/rpc").unwrap();
+        let mut websocket: WebSocket<Duplex> =
+            client_handshake(client, &url, WebSocketConfig::default()).unwrap();
+        websocket
+            .get_mut()
+            .set_read_timeout(Some(Duration::from_millis(10)));
+        let mut client = Client::new(websocket).with_timeout(Duration::from_millis(200));
+
+        assert_eq!(client.call::<_, i64>("add", (2, 3)).unwrap(), 5);
+        client.notify("add", (2, 3)).unwrap();
+        assert!(matches!(
+            client.call::<_, ()>("fail", ()),
+            Err(CallError::Rpc(error)) if error.code == 7
+        ));
+        assert!(matches!(
+            client.call::<_, i64>("sub", (2, 3)),
+            Err(CallError::Rpc(error)) if error.code == error_code::METHOD_NOT_FOUND
+        ));
+
+        let batch = Batch::new()
+            .call("add", json!([1, 1]))
+            .notify("add", json!([0, 0]))
+            .call("sub", json!([1, 1]))
+            .call("add", json!([2, 2]));
+        let results = client.batch(batch).unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0], Ok(json!(2)));
+        assert!(matches!(&results[1], Err(error) if error.code == error_code::METHOD_NOT_FOUND));
+        assert_eq!(results[2], Ok(json!(4)));
+
+        assert!(matches!(
+            client.call::<_, u64>("sleep", (300,)),
+            Err(CallError::Timeout)
+        ));
+        // The late answer to the call that timed out isn't mistaken for this one
+        assert_eq!(client.call::<_, i64>("add", (4, 4)).unwrap(), 8);
+        assert!(client.take_requests().is_empty());
+
+        let mut websocket = client.into_inner();
+        websocket.write_message(Message::Close).unwrap();
+        thread.join().unwrap();
+    }
+    #[test]
+    fn should_fail_calls_on_errors_without_an_id() {
+        let (client, server) = duplex();
+        let thread = thread::spawn(move || {
+            let mut websocket = WebSocket::accept(server).unwrap();
+            let error = Response {
+                id: Id::Null,
+                result: Err(RpcError::parse_error()),
+            };
+            while let Ok(Message::Text(_)) = websocket.read_message() {
+                let reply = Message::Text(error.to_value().to_string());
+                websocket.write_message(reply).unwrap();
+            }
+        });
+        let url = Url::parse("ws://localhost/rpc").unwrap();
+        let mut websocket: WebSocket<Duplex> =
+            client_handshake(client, &url, WebSocketConfig::default()).unwrap();
+        websocket
+            .get_mut()
+            .set_read_timeout(Some(Duration::from_millis(10)));
+        let mut client = Client::new(websocket).with_timeout(Duration::from_secs(30));
+
+        let start = Instant::now();
+        assert!(matches!(
+            client.call::<_, i64>("add", (2, 3)),
+            Err(CallError::Rpc(error)) if error.code == error_code::PARSE_ERROR
+        ));
+        let batch = Batch::new()
+            .call("add", json!([1, 1]))
+            .call("add", json!([2, 2]));
+        let results = client.batch(batch).unwrap();
+        assert_eq!(results, vec![Err(RpcError::parse_error()); 2]);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let mut websocket = client.into_inner();
+        websocket.write_message(Message::Close).unwrap();
+        thread.join().unwrap();
+    }
+}
@@ -0,0 +1,138 @@
+use std::time::{Duration, Instant};
+
+/// What the keepalive did on a connection, reported to handlers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeepaliveEvent {
+    PingSent,
+    /// The pong for our last ping arrived after this round trip time
+    PongReceived(Duration),
+    /// Nothing arrived within the timeout after a ping; the connection is closed
+    TimedOut,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum KeepaliveAction {
+    Wait,
+    Ping,
+    TimedOut,
+}
+
+/// Decides when to ping an idle peer and when to give up on it
+#[derive(Debug)]
+pub(crate) struct Keepalive {
+    interval: Option<Duration>,
+    timeout: Duration,
+    last_seen: Instant,
+    ping_sent: Option<Instant>,
+}
+impl Keepalive {
+    pub fn new(interval: Option<Duration>, timeout: Duration) -> Self {
+        Keepalive {
+            interval,
+            timeout,
+            last_seen: Instant::now(),
+            ping_sent: None,
+        }
+    }
+    /// Any frame from the peer shows it is still there
+    pub fn on_traffic(&mut self, now: Instant) {
+        self.last_seen = now;
+    }
+    pub fn on_pong(&mut self, now: Instant) -> Option<KeepaliveEvent> {
+        self.ping_sent
+            .take()
+            .map(|sent| KeepaliveEvent::PongReceived(now.saturating_duration_since(sent)))
+    }
+    /// When [`Keepalive::poll`] has something to do next, `None` without an interval
+    #[cfg(any(feature = "futures", feature = "mio"))]
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let interval = self.interval?;
+        match self.ping_sent {
+            Some(sent) if self.last_seen < sent => Some(sent + self.timeout),
+            Some(sent) => Some((self.last_seen + interval).max(sent + interval)),
+            None => Some(self.last_seen + interval),
+        }
+    }
+    pub fn poll(&mut self, now: Instant) -> KeepaliveAction {
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => return KeepaliveAction::Wait,
+        };
+        if let Some(sent) = self.ping_sent {
+            if self.last_seen < sent {
+                return if now.saturating_duration_since(sent) >= self.timeout {
+                    KeepaliveAction::TimedOut
+                } else {
+                    KeepaliveAction::Wait
+                };
+            }
+            if now.saturating_duration_since(sent) < interval {
+                return KeepaliveAction::Wait;
+            }
+        }
+        if now.saturating_duration_since(self.last_seen) >= interval {
+            self.ping_sent = Some(now);
+            return KeepaliveAction::Ping;
+        }
+        KeepaliveAction::Wait
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_ping_idle_peers_and_time_out() {
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+        let mut keepalive = Keepalive::new(Some(second * 10), second * 5);
+        keepalive.on_traffic(start);
+
+        assert_eq!(keepalive.poll(start + second * 9), KeepaliveAction::Wait);
+        assert_eq!(keepalive.poll(start + second * 10), KeepaliveAction::Ping);
+        assert_eq!(keepalive.poll(start + second * 14), KeepaliveAction::Wait);
+        assert_eq!(
+            keepalive.poll(start + second * 15),
+            KeepaliveAction::TimedOut
+        );
+    }
+    #[test]
+    fn should_accept_any_traffic_as_an_answer() {
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+        let mut keepalive = Keepalive::new(Some(second * 10), second * 5);
+        keepalive.on_traffic(start);
+
+        assert_eq!(keepalive.poll(start + second * 10), KeepaliveAction::Ping);
+        keepalive.on_traffic(start + second * 12);
+        assert_eq!(keepalive.poll(start + second * 20), KeepaliveAction::Wait);
+        assert_eq!(keepalive.poll(start + second * 22), KeepaliveAction::Ping);
+        assert_eq!(
+            keepalive.on_pong(start + second * 23),
+            Some(KeepaliveEvent::PongReceived(second))
+        );
+        assert_eq!(keepalive.on_pong(start + second * 24), None);
+    }
+    #[cfg(any(feature = "futures", feature = "mio"))]
+    #[test]
+    fn should_know_when_to_poll_next() {
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+        let mut keepalive = Keepalive::new(Some(second * 10), second * 5);
+        keepalive.on_traffic(start);
+
+        assert_eq!(keepalive.next_deadline(), Some(start + second * 10));
+        assert_eq!(keepalive.poll(start + second * 10), KeepaliveAction::Ping);
+        assert_eq!(keepalive.next_deadline(), Some(start + second * 15));
+        keepalive.on_traffic(start + second * 12);
+        assert_eq!(keepalive.next_deadline(), Some(start + second * 22));
+        assert!(Keepalive::new(None, second).next_deadline().is_none());
+    }
+    #[test]
+    fn should_stay_quiet_without_an_interval() {
+        let mut keepalive = Keepalive::new(None, Duration::from_secs(1));
+        let later = Instant::now() + Duration::from_secs(3600);
+        assert_eq!(keepalive.poll(later), KeepaliveAction::Wait);
+    }
+}
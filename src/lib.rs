@@ -4,5 +4,31 @@
 #![feature(const_generics)]
 
 pub mod accept;
+pub mod client;
+#[cfg(feature = "tokio")]
+pub mod codec;
 pub mod dataframe;
+pub mod error;
+#[cfg(feature = "mio")]
+pub mod event_loop;
+#[cfg(feature = "serde")]
+pub mod format;
+pub mod hub;
+#[cfg(feature = "serde")]
+pub mod jsonrpc;
+pub mod keepalive;
 pub mod message;
+pub mod metrics;
+pub mod net;
+pub mod pool;
+mod protocol;
+pub mod queue;
+pub mod rate_limit;
+pub mod registry;
+pub mod router;
+pub mod server;
+#[cfg(feature = "futures")]
+pub mod stream;
+#[cfg(feature = "rustls")]
+pub mod tls;
+pub mod websocket;
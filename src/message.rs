@@ -1,3 +1,57 @@
+use crate::dataframe::{frame_positions, mask_data, Opcode};
+use crate::pool::BufferPool;
+use std::convert::TryInto;
+
+pub mod close_code {
+    pub const NORMAL: u16 = 1000;
+    pub const GOING_AWAY: u16 = 1001;
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    pub const UNSUPPORTED_DATA: u16 = 1003;
+    pub const NO_STATUS: u16 = 1005;
+    pub const ABNORMAL: u16 = 1006;
+    pub const INVALID_PAYLOAD: u16 = 1007;
+    pub const POLICY_VIOLATION: u16 = 1008;
+    pub const MESSAGE_TOO_BIG: u16 = 1009;
+    pub const INTERNAL_ERROR: u16 = 1011;
+    pub const TRY_AGAIN_LATER: u16 = 1013;
+
+    /// Whether the code may be sent in a close frame; 1005, 1006 and 1015 are reserved for reporting
+    pub fn is_valid(code: u16) -> bool {
+        matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+impl CloseFrame {
+    pub fn new<R>(code: u16, reason: R) -> Self
+    where
+        R: Into<String>,
+    {
+        CloseFrame {
+            code,
+            reason: reason.into(),
+        }
+    }
+    /// Parse the payload of a close frame, `None` if it doesn't carry a status code
+    pub fn from_payload(payload: &[u8]) -> Option<CloseFrame> {
+        let code: [u8; 2] = payload.get(0..2)?.try_into().ok()?;
+        Some(CloseFrame {
+            code: u16::from_be_bytes(code),
+            reason: String::from_utf8_lossy(&payload[2..]).into_owned(),
+        })
+    }
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(2 + self.reason.len());
+        payload.extend_from_slice(&self.code.to_be_bytes());
+        payload.extend_from_slice(self.reason.as_bytes());
+        payload
+    }
+}
+
 #[derive(Debug)]
 pub enum Message {
     Text(String),
@@ -10,6 +64,23 @@ impl Message {
     pub fn is_closed(&self) -> bool {
         matches!(self, Message::Close)
     }
+    pub fn get_opcode(&self) -> Opcode {
+        match self {
+            Message::Text(_) => Opcode::Text,
+            Message::Binary(_) => Opcode::Binary,
+            Message::Ping(_) => Opcode::Ping,
+            Message::Pong(_) => Opcode::Pong,
+            Message::Close => Opcode::Close,
+        }
+    }
+    /// The buffer holding the payload, so it can go back to a [`BufferPool`]
+    pub fn into_payload(self) -> Vec<u8> {
+        match self {
+            Message::Text(text) => text.into_bytes(),
+            Message::Binary(data) | Message::Ping(data) | Message::Pong(data) => data,
+            Message::Close => Vec::new(),
+        }
+    }
 }
 impl AsRef<[u8]> for Message {
     fn as_ref(&self) -> &[u8] {
@@ -35,9 +106,49 @@ impl WriteMessage {
             output: message_to_tcp_write_data(D::as_ref(&input)),
         }
     }
+    pub fn with_opcode<D>(opcode: Opcode, input: D) -> WriteMessage
+    where
+        D: AsRef<[u8]>,
+    {
+        WriteMessage::with_mask(opcode, input, None)
+    }
+    /// A single frame masked with `mask`, as clients have to send them
+    pub fn with_mask<D>(opcode: Opcode, input: D, mask: Option<[u8; 4]>) -> WriteMessage
+    where
+        D: AsRef<[u8]>,
+    {
+        WriteMessage {
+            output: encode_frame(opcode, D::as_ref(&input), true, mask),
+        }
+    }
+    /// Like [`WriteMessage::with_mask`], encoded into a buffer taken from `pool`
+    pub fn with_pool<D>(
+        pool: &BufferPool,
+        opcode: Opcode,
+        input: D,
+        mask: Option<[u8; 4]>,
+    ) -> WriteMessage
+    where
+        D: AsRef<[u8]>,
+    {
+        let input = D::as_ref(&input);
+        let mut output = pool.take(input.len() + 14);
+        encode_frame_into(&mut output, opcode, input, true, mask);
+        WriteMessage { output }
+    }
+    pub fn close(frame: Option<&CloseFrame>) -> WriteMessage {
+        match frame {
+            Some(frame) => WriteMessage::with_opcode(Opcode::Close, frame.to_payload()),
+            None => WriteMessage::with_opcode(Opcode::Close, []),
+        }
+    }
     pub fn get_output(&self) -> &Vec<u8> {
         &self.output
     }
+    /// The encoded frame, so the buffer can go back to a [`BufferPool`]
+    pub fn into_output(self) -> Vec<u8> {
+        self.output
+    }
 }
 impl AsRef<[u8]> for WriteMessage {
     fn as_ref(&self) -> &[u8] {
@@ -48,37 +159,76 @@ fn message_to_tcp_write_data<D>(data: D) -> Vec<u8>
 where
     D: AsRef<[u8]>,
 {
-    let data = D::as_ref(&data);
-    let mut buffer: Vec<u8> = Vec::with_capacity(data.len() + 10);
-    buffer.push(129);
+    encode_frame(Opcode::Text, D::as_ref(&data), true, None)
+}
+/// Write the header of a single frame; the mask bit is set when a masking key is given
+pub fn write_frame_header(
+    buffer: &mut Vec<u8>,
+    opcode: Opcode,
+    payload_length: usize,
+    fin: bool,
+    mask: Option<[u8; 4]>,
+) {
+    let fin_bit = if fin { frame_positions::FIN } else { 0 };
+    let mask_bit = if mask.is_some() {
+        frame_positions::IS_MASK
+    } else {
+        0
+    };
+    buffer.push(fin_bit | opcode as u8);
 
-    match data.len() as u64 {
+    match payload_length as u64 {
         size @ 0..=125 => {
-            buffer.push(size as u8);
+            buffer.push(mask_bit | size as u8);
         }
-        size if size <= u32::MAX as u64 => {
+        size if size <= u16::MAX as u64 => {
             let new_bytes: [u8; 2] = (size as u16).to_be_bytes();
 
-            buffer.push(126);
+            buffer.push(mask_bit | 126);
             buffer.extend_from_slice(&new_bytes);
         }
-        size if size > u32::MAX as u64 => {
-            let new_bytes: [u8; 8] = (size as u64).to_be_bytes();
+        size => {
+            let new_bytes: [u8; 8] = size.to_be_bytes();
 
-            buffer.push(127);
+            buffer.push(mask_bit | 127);
             buffer.extend_from_slice(&new_bytes);
         }
-        _ => panic!("Don't know what to do here..."),
     };
 
-    buffer.extend_from_slice(data);
-
+    if let Some(mask) = mask {
+        buffer.extend_from_slice(&mask);
+    }
+}
+pub fn encode_frame(opcode: Opcode, payload: &[u8], fin: bool, mask: Option<[u8; 4]>) -> Vec<u8> {
+    let mut buffer: Vec<u8> = Vec::with_capacity(payload.len() + 14);
+    encode_frame_into(&mut buffer, opcode, payload, fin, mask);
     buffer
 }
+/// Append a whole frame to `buffer`
+pub fn encode_frame_into(
+    buffer: &mut Vec<u8>,
+    opcode: Opcode,
+    payload: &[u8],
+    fin: bool,
+    mask: Option<[u8; 4]>,
+) {
+    write_frame_header(buffer, opcode, payload.len(), fin, mask);
+    let payload_start = buffer.len();
+    buffer.extend_from_slice(payload);
+    if let Some(mask) = mask {
+        mask_data(&mut buffer[payload_start..], mask);
+    }
+}
 
 impl From<Message> for WriteMessage {
     fn from(message: Message) -> Self {
-        WriteMessage::new(message.as_ref())
+        match message {
+            Message::Text(text) => WriteMessage::with_opcode(Opcode::Text, text),
+            Message::Binary(data) => WriteMessage::with_opcode(Opcode::Binary, data),
+            Message::Ping(data) => WriteMessage::with_opcode(Opcode::Ping, data),
+            Message::Pong(data) => WriteMessage::with_opcode(Opcode::Pong, data),
+            Message::Close => WriteMessage::close(Some(&CloseFrame::new(close_code::NORMAL, ""))),
+        }
     }
 }
 #[cfg(test)]
@@ -91,4 +241,39 @@ mod tests {
         let result = message.as_ref();
         assert_eq!(result, expected_result);
     }
+    #[test]
+    fn test_frame_opcodes() {
+        let binary = WriteMessage::from(Message::Binary(vec![1, 2]));
+        assert_eq!(binary.get_output(), &vec![130, 2, 1, 2]);
+        let ping = WriteMessage::from(Message::Ping(vec![]));
+        assert_eq!(ping.get_output(), &vec![137, 0]);
+        let close = WriteMessage::from(Message::Close);
+        assert_eq!(close.get_output(), &vec![136, 2, 3, 232]);
+    }
+    #[test]
+    fn test_extended_payload_lengths() {
+        let message = WriteMessage::new(vec![0; 65535]);
+        assert_eq!(message.get_output()[..4], [129, 126, 255, 255]);
+        assert_eq!(message.get_output().len(), 65535 + 4);
+
+        let message = WriteMessage::new(vec![0; 65536]);
+        assert_eq!(
+            message.get_output()[..10],
+            [129, 127, 0, 0, 0, 0, 0, 1, 0, 0]
+        );
+        assert_eq!(message.get_output().len(), 65536 + 10);
+    }
+    #[test]
+    fn test_masked_frame() {
+        let output = encode_frame(Opcode::Text, b"Hello", true, Some([1, 2, 3, 4]));
+        assert_eq!(output, vec![129, 133, 1, 2, 3, 4, 73, 103, 111, 104, 110]);
+    }
+    #[test]
+    fn test_close_frame_payload() {
+        let frame = CloseFrame::new(close_code::GOING_AWAY, "bye");
+        let payload = frame.to_payload();
+        assert_eq!(payload, vec![3, 233, 98, 121, 101]);
+        assert_eq!(CloseFrame::from_payload(&payload), Some(frame));
+        assert_eq!(CloseFrame::from_payload(&[3]), None);
+    }
 }
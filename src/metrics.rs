@@ -0,0 +1,503 @@
+use crate::accept::keys::KeyError;
+use crate::dataframe::{frame_positions, Opcode};
+use crate::error::{Error, ProtocolError};
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Upper bounds of the message size buckets, in payload bytes
+pub const MESSAGE_SIZE_BUCKETS: [u64; 8] = [64, 256, 1024, 4096, 16384, 65536, 262144, 1048576];
+const OPCODES: [Opcode; 7] = [
+    Opcode::Continuation,
+    Opcode::Text,
+    Opcode::Binary,
+    Opcode::Close,
+    Opcode::Ping,
+    Opcode::Pong,
+    Opcode::Unknown,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    /// Received from the peer
+    In,
+    /// Sent to the peer
+    Out,
+}
+impl Direction {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Direction::In => "in",
+            Direction::Out => "out",
+        }
+    }
+}
+
+/// Receives what happens on connections, see [`crate::websocket::WebSocketConfig::metrics`].
+///
+/// Handshakes, frames and messages are reported by every transport made with the config;
+/// connections and queue depths only by [`crate::server::Server`]. Every method does
+/// nothing by default and is called on the connection's thread, so keep them cheap.
+pub trait Metrics: fmt::Debug + Send + Sync {
+    fn handshake_succeeded(&self) {}
+    fn handshake_failed(&self, _error: &Error) {}
+    fn connection_opened(&self) {}
+    /// The code of the peer's close frame, 1006 if there was none
+    fn connection_closed(&self, _code: u16) {}
+    /// `bytes` is the whole frame including its header
+    fn frame(&self, _direction: Direction, _opcode: Opcode, _bytes: usize) {}
+    /// Payload size of a text or binary message, after joining its fragments
+    fn message(&self, _direction: Direction, _size: usize) {}
+    /// A connection's send queue went from `previous` to `depth` waiting frames; it
+    /// goes back to 0 when the connection closes, so the changes add up across connections
+    fn queue_depth(&self, _previous: usize, _depth: usize) {}
+    /// The metrics in the Prometheus text format, served at [`crate::server::ServerConfig::metrics_path`]
+    fn to_prometheus(&self) -> Option<String> {
+        None
+    }
+}
+
+/// A short label for why a handshake failed
+pub fn failure_reason(error: &Error) -> &'static str {
+    match error {
+        Error::Io(_) => "io",
+        Error::Handshake(KeyError::OriginNotAllowed) => "origin_not_allowed",
+        Error::Handshake(KeyError::UnexpectedResponse) => "unexpected_response",
+        Error::Handshake(KeyError::AcceptMismatch) => "accept_mismatch",
+        Error::Handshake(KeyError::NotFound) => "not_found",
+        Error::Handshake(_) => "invalid_request",
+        Error::Protocol(ProtocolError::HeadersTooLarge) => "headers_too_large",
+        Error::Protocol(_) => "protocol",
+        #[cfg(feature = "rustls")]
+        Error::Tls(_) => "tls",
+        Error::Timeout(_) => "timeout",
+        Error::MessageTooBig | Error::InvalidUrl | Error::ConnectionClosed => "other",
+    }
+}
+/// Report an encoded frame that is sent, and its message unless it is a fragment
+pub(crate) fn sent_frame(metrics: &dyn Metrics, frame: &[u8]) {
+    let opcode = Opcode::from(frame[0] & frame_positions::MASK_OPCODE);
+    metrics.frame(Direction::Out, opcode, frame.len());
+    let fin = frame[0] & frame_positions::FIN == frame_positions::FIN;
+    if fin && matches!(opcode, Opcode::Text | Opcode::Binary) {
+        metrics.message(Direction::Out, payload_length(frame));
+    }
+}
+/// Payload length of an encoded frame, without its header and masking key
+fn payload_length(frame: &[u8]) -> usize {
+    let second = frame.get(1).copied().unwrap_or(0);
+    let header = match second & frame_positions::MASK_PAYLOAD_LENGTH {
+        126 => 4,
+        127 => 10,
+        _ => 2,
+    } + match second & frame_positions::IS_MASK {
+        0 => 0,
+        _ => 4,
+    };
+    frame.len().saturating_sub(header)
+}
+fn opcode_index(opcode: Opcode) -> usize {
+    OPCODES
+        .iter()
+        .position(|candidate| *candidate == opcode)
+        .unwrap_or(OPCODES.len() - 1)
+}
+fn direction_index(direction: Direction) -> usize {
+    match direction {
+        Direction::In => 0,
+        Direction::Out => 1,
+    }
+}
+
+#[derive(Debug, Default)]
+struct FrameCounters {
+    frames: [AtomicU64; 7],
+    bytes: [AtomicU64; 7],
+}
+#[derive(Debug, Default)]
+struct SizeCounters {
+    /// One per bucket plus one for larger messages
+    buckets: [AtomicU64; 9],
+    sum: AtomicU64,
+}
+
+/// In-memory counters for [`Metrics`], read with [`AtomicMetrics::snapshot`]
+#[derive(Debug, Default)]
+pub struct AtomicMetrics {
+    handshakes_succeeded: AtomicU64,
+    handshakes_failed: Mutex<BTreeMap<&'static str, u64>>,
+    connections_opened: AtomicU64,
+    connections_closed: Mutex<BTreeMap<u16, u64>>,
+    frames: [FrameCounters; 2],
+    message_sizes: [SizeCounters; 2],
+    queue_depth: AtomicU64,
+    max_queue_depth: AtomicU64,
+}
+impl AtomicMetrics {
+    pub fn new() -> Self {
+        AtomicMetrics::default()
+    }
+    fn increment<K: Ord>(map: &Mutex<BTreeMap<K, u64>>, key: K) {
+        *map.lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .entry(key)
+            .or_insert(0) += 1;
+    }
+    fn copy<K: Ord + Copy>(map: &Mutex<BTreeMap<K, u64>>) -> BTreeMap<K, u64> {
+        map.lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+    /// The counters as they are now; concurrent updates may show up in some fields only
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let frames = |direction: Direction| {
+            let counters = &self.frames[direction_index(direction)];
+            OPCODES
+                .iter()
+                .enumerate()
+                .map(|(index, opcode)| {
+                    let stats = FrameStats {
+                        frames: counters.frames[index].load(Ordering::Relaxed),
+                        bytes: counters.bytes[index].load(Ordering::Relaxed),
+                    };
+                    (opcode.get_name(), stats)
+                })
+                .filter(|(_, stats)| stats.frames > 0)
+                .collect()
+        };
+        let sizes = |direction: Direction| {
+            let counters = &self.message_sizes[direction_index(direction)];
+            let mut count = 0;
+            let buckets = MESSAGE_SIZE_BUCKETS
+                .iter()
+                .zip(counters.buckets.iter())
+                .map(|(bound, bucket)| {
+                    count += bucket.load(Ordering::Relaxed);
+                    (*bound, count)
+                })
+                .collect();
+            SizeHistogram {
+                buckets,
+                count: count + counters.buckets[MESSAGE_SIZE_BUCKETS.len()].load(Ordering::Relaxed),
+                sum: counters.sum.load(Ordering::Relaxed),
+            }
+        };
+        MetricsSnapshot {
+            handshakes_succeeded: self.handshakes_succeeded.load(Ordering::Relaxed),
+            handshakes_failed: Self::copy(&self.handshakes_failed),
+            connections_opened: self.connections_opened.load(Ordering::Relaxed),
+            connections_closed: Self::copy(&self.connections_closed),
+            frames_in: frames(Direction::In),
+            frames_out: frames(Direction::Out),
+            message_sizes_in: sizes(Direction::In),
+            message_sizes_out: sizes(Direction::Out),
+            queue_depth: self.queue_depth.load(Ordering::Relaxed),
+            max_queue_depth: self.max_queue_depth.load(Ordering::Relaxed),
+        }
+    }
+}
+impl Metrics for AtomicMetrics {
+    fn handshake_succeeded(&self) {
+        self.handshakes_succeeded.fetch_add(1, Ordering::Relaxed);
+    }
+    fn handshake_failed(&self, error: &Error) {
+        Self::increment(&self.handshakes_failed, failure_reason(error));
+    }
+    fn connection_opened(&self) {
+        self.connections_opened.fetch_add(1, Ordering::Relaxed);
+    }
+    fn connection_closed(&self, code: u16) {
+        Self::increment(&self.connections_closed, code);
+    }
+    fn frame(&self, direction: Direction, opcode: Opcode, bytes: usize) {
+        let (counters, index) = (
+            &self.frames[direction_index(direction)],
+            opcode_index(opcode),
+        );
+        counters.frames[index].fetch_add(1, Ordering::Relaxed);
+        counters.bytes[index].fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+    fn message(&self, direction: Direction, size: usize) {
+        let counters = &self.message_sizes[direction_index(direction)];
+        let bucket = MESSAGE_SIZE_BUCKETS
+            .iter()
+            .position(|bound| size as u64 <= *bound)
+            .unwrap_or(MESSAGE_SIZE_BUCKETS.len());
+        counters.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        counters.sum.fetch_add(size as u64, Ordering::Relaxed);
+    }
+    fn queue_depth(&self, previous: usize, depth: usize) {
+        match depth >= previous {
+            true => self
+                .queue_depth
+                .fetch_add((depth - previous) as u64, Ordering::Relaxed),
+            false => self
+                .queue_depth
+                .fetch_sub((previous - depth) as u64, Ordering::Relaxed),
+        };
+        self.max_queue_depth
+            .fetch_max(depth as u64, Ordering::Relaxed);
+    }
+    fn to_prometheus(&self) -> Option<String> {
+        Some(self.snapshot().to_prometheus())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FrameStats {
+    pub frames: u64,
+    pub bytes: u64,
+}
+
+/// Message sizes by bucket, cumulative like a Prometheus histogram
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SizeHistogram {
+    /// Upper bound of each bucket and how many messages were at most that big
+    pub buckets: Vec<(u64, u64)>,
+    pub count: u64,
+    pub sum: u64,
+}
+
+/// A copy of the counters of [`AtomicMetrics`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MetricsSnapshot {
+    pub handshakes_succeeded: u64,
+    /// Failed handshakes by [`failure_reason`]
+    pub handshakes_failed: BTreeMap<&'static str, u64>,
+    pub connections_opened: u64,
+    /// Closed connections by close code
+    pub connections_closed: BTreeMap<u16, u64>,
+    /// Frames by [`Opcode::get_name`], leaving out opcodes never seen
+    pub frames_in: BTreeMap<&'static str, FrameStats>,
+    pub frames_out: BTreeMap<&'static str, FrameStats>,
+    pub message_sizes_in: SizeHistogram,
+    pub message_sizes_out: SizeHistogram,
+    /// Frames waiting in the send queues of all connections
+    pub queue_depth: u64,
+    /// The most frames a single send queue has held
+    pub max_queue_depth: u64,
+}
+impl MetricsSnapshot {
+    pub fn open_connections(&self) -> u64 {
+        let closed: u64 = self.connections_closed.values().sum();
+        self.connections_opened.saturating_sub(closed)
+    }
+    /// Export in the Prometheus text format
+    pub fn to_prometheus(&self) -> String {
+        let mut output = String::new();
+        // Writing to a String can't fail
+        let _ = self.write_prometheus(&mut output);
+        output
+    }
+    fn write_prometheus(&self, output: &mut String) -> fmt::Result {
+        writeln!(output, "# TYPE websocket_handshakes_total counter")?;
+        writeln!(
+            output,
+            "websocket_handshakes_total{{result=\"success\"}} {}",
+            self.handshakes_succeeded
+        )?;
+        for (reason, count) in &self.handshakes_failed {
+            writeln!(
+                output,
+                "websocket_handshakes_total{{result=\"failure\",reason=\"{}\"}} {}",
+                reason, count
+            )?;
+        }
+        writeln!(output, "# TYPE websocket_connections_opened_total counter")?;
+        writeln!(
+            output,
+            "websocket_connections_opened_total {}",
+            self.connections_opened
+        )?;
+        writeln!(output, "# TYPE websocket_connections_open gauge")?;
+        writeln!(
+            output,
+            "websocket_connections_open {}",
+            self.open_connections()
+        )?;
+        writeln!(output, "# TYPE websocket_connections_closed_total counter")?;
+        for (code, count) in &self.connections_closed {
+            writeln!(
+                output,
+                "websocket_connections_closed_total{{code=\"{}\"}} {}",
+                code, count
+            )?;
+        }
+        let frames = [
+            (Direction::In, &self.frames_in),
+            (Direction::Out, &self.frames_out),
+        ];
+        writeln!(output, "# TYPE websocket_frames_total counter")?;
+        for (direction, frames) in &frames {
+            for (opcode, stats) in frames.iter() {
+                writeln!(
+                    output,
+                    "websocket_frames_total{{direction=\"{}\",opcode=\"{}\"}} {}",
+                    direction.get_name(),
+                    opcode,
+                    stats.frames
+                )?;
+            }
+        }
+        writeln!(output, "# TYPE websocket_frame_bytes_total counter")?;
+        for (direction, frames) in &frames {
+            for (opcode, stats) in frames.iter() {
+                writeln!(
+                    output,
+                    "websocket_frame_bytes_total{{direction=\"{}\",opcode=\"{}\"}} {}",
+                    direction.get_name(),
+                    opcode,
+                    stats.bytes
+                )?;
+            }
+        }
+        writeln!(output, "# TYPE websocket_message_size_bytes histogram")?;
+        let sizes = [
+            (Direction::In, &self.message_sizes_in),
+            (Direction::Out, &self.message_sizes_out),
+        ];
+        for (direction, histogram) in &sizes {
+            let direction = direction.get_name();
+            for (bound, count) in &histogram.buckets {
+                writeln!(
+                    output,
+                    "websocket_message_size_bytes_bucket{{direction=\"{}\",le=\"{}\"}} {}",
+                    direction, bound, count
+                )?;
+            }
+            writeln!(
+                output,
+                "websocket_message_size_bytes_bucket{{direction=\"{}\",le=\"+Inf\"}} {}",
+                direction, histogram.count
+            )?;
+            writeln!(
+                output,
+                "websocket_message_size_bytes_sum{{direction=\"{}\"}} {}",
+                direction, histogram.sum
+            )?;
+            writeln!(
+                output,
+                "websocket_message_size_bytes_count{{direction=\"{}\"}} {}",
+                direction, histogram.count
+            )?;
+        }
+        writeln!(output, "# TYPE websocket_send_queue_depth gauge")?;
+        writeln!(output, "websocket_send_queue_depth {}", self.queue_depth)?;
+        writeln!(output, "# TYPE websocket_send_queue_depth_max gauge")?;
+        writeln!(
+            output,
+            "websocket_send_queue_depth_max {}",
+            self.max_queue_depth
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{encode_frame, CloseFrame, Message};
+    use crate::server::tests::serve_echo;
+    use crate::server::{Server, ServerConfig};
+    use crate::websocket::WebSocketConfig;
+    use std::io::Write as _;
+    use std::net::TcpStream;
+    use std::sync::Arc;
+
+    #[test]
+    fn should_count_into_buckets_and_export() {
+        let metrics = AtomicMetrics::new();
+        metrics.handshake_succeeded();
+        metrics.handshake_failed(&Error::Handshake(KeyError::OriginNotAllowed));
+        metrics.connection_opened();
+        metrics.connection_opened();
+        metrics.connection_closed(1000);
+        metrics.frame(Direction::In, Opcode::Text, 7);
+        metrics.frame(Direction::In, Opcode::Text, 9);
+        metrics.frame(Direction::Out, Opcode::Ping, 2);
+        metrics.message(Direction::In, 10);
+        metrics.message(Direction::In, 300);
+        metrics.message(Direction::In, 2 << 20);
+        metrics.queue_depth(0, 3);
+        metrics.queue_depth(0, 2);
+        metrics.queue_depth(3, 1);
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.open_connections(), 1);
+        assert_eq!(
+            snapshot.frames_in.get("text"),
+            Some(&FrameStats {
+                frames: 2,
+                bytes: 16
+            })
+        );
+        assert_eq!(snapshot.frames_out.len(), 1);
+        let sizes = &snapshot.message_sizes_in;
+        assert_eq!(&sizes.buckets[..3], &[(64, 1), (256, 1), (1024, 2)]);
+        assert_eq!(sizes.buckets.last(), Some(&(1048576, 2)));
+        assert_eq!((sizes.count, sizes.sum), (3, 310 + (2 << 20)));
+        assert_eq!((snapshot.queue_depth, snapshot.max_queue_depth), (3, 3));
+
+        let text = snapshot.to_prometheus();
+        for line in &[
+            "websocket_handshakes_total{result=\"failure\",reason=\"origin_not_allowed\"} 1",
+            "websocket_connections_closed_total{code=\"1000\"} 1",
+            "websocket_frames_total{direction=\"out\",opcode=\"ping\"} 1",
+            "websocket_frame_bytes_total{direction=\"in\",opcode=\"text\"} 16",
+            "websocket_message_size_bytes_bucket{direction=\"in\",le=\"+Inf\"} 3",
+            "websocket_message_size_bytes_count{direction=\"out\"} 0",
+        ] {
+            assert!(text.lines().any(|candidate| candidate == *line), "{}", line);
+        }
+    }
+    #[test]
+    fn should_measure_server_connections() {
+        let metrics = Arc::new(AtomicMetrics::new());
+        let config = ServerConfig {
+            websocket: WebSocketConfig {
+                metrics: Some(metrics.clone()),
+                ..WebSocketConfig::default()
+            },
+            ..ServerConfig::default()
+        };
+        let server = Server::bind("127.0.0.1:0").unwrap().with_config(config);
+        let address = server.local_addr().unwrap();
+        let (shutdown, _echo, thread) = serve_echo(server);
+
+        let mut rejected = TcpStream::connect(address).unwrap();
+        rejected.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut websocket = crate::client::connect(&format!("ws://{}/", address)).unwrap();
+        websocket
+            .write_message(Message::Text(String::from("Hello")))
+            .unwrap();
+        assert!(matches!(websocket.read_message(), Ok(Message::Text(text)) if text == "Hello"));
+        websocket.close(CloseFrame::new(1000, "")).unwrap();
+        assert!(matches!(websocket.read_message(), Ok(Message::Close)));
+        shutdown.shutdown();
+        thread.join().unwrap();
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.handshakes_succeeded, 1);
+        assert_eq!(snapshot.handshakes_failed.get("invalid_request"), Some(&1));
+        assert_eq!(snapshot.connections_opened, 1);
+        assert_eq!(snapshot.connections_closed.get(&1000), Some(&1));
+        let hello = encode_frame(Opcode::Text, b"Hello", true, Some([0; 4])).len();
+        assert_eq!(
+            snapshot.frames_in.get("text"),
+            Some(&FrameStats {
+                frames: 1,
+                bytes: hello as u64
+            })
+        );
+        assert_eq!(
+            snapshot.frames_out.get("text").map(|stats| stats.frames),
+            Some(1)
+        );
+        assert_eq!(
+            snapshot.frames_in.get("close").map(|stats| stats.frames),
+            Some(1)
+        );
+        assert_eq!(snapshot.message_sizes_in.sum, 5);
+        assert_eq!(snapshot.message_sizes_out.sum, 5);
+    }
+}
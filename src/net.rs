@@ -0,0 +1,212 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+/// The streams the blocking [`crate::server::Server`] and [`crate::client`] run over
+#[derive(Debug)]
+pub enum NetStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(feature = "rustls")]
+    TlsServer(Box<rustls::StreamOwned<rustls::ServerConnection, TcpStream>>),
+    #[cfg(feature = "rustls")]
+    TlsClient(Box<rustls::StreamOwned<rustls::ClientConnection, TcpStream>>),
+}
+impl NetStream {
+    /// The TCP stream underneath, `None` for unix sockets
+    pub fn get_tcp(&self) -> Option<&TcpStream> {
+        match self {
+            NetStream::Tcp(stream) => Some(stream),
+            #[cfg(unix)]
+            NetStream::Unix(_) => None,
+            #[cfg(feature = "rustls")]
+            NetStream::TlsServer(stream) => Some(stream.get_ref()),
+            #[cfg(feature = "rustls")]
+            NetStream::TlsClient(stream) => Some(stream.get_ref()),
+        }
+    }
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match (self, self.get_tcp()) {
+            #[cfg(unix)]
+            (NetStream::Unix(stream), _) => stream.set_read_timeout(timeout),
+            (_, Some(stream)) => stream.set_read_timeout(timeout),
+            _ => Ok(()),
+        }
+    }
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match (self, self.get_tcp()) {
+            #[cfg(unix)]
+            (NetStream::Unix(stream), _) => stream.set_nonblocking(nonblocking),
+            (_, Some(stream)) => stream.set_nonblocking(nonblocking),
+            _ => Ok(()),
+        }
+    }
+    pub fn is_tls(&self) -> bool {
+        #[cfg(feature = "rustls")]
+        {
+            matches!(self, NetStream::TlsServer(_) | NetStream::TlsClient(_))
+        }
+        #[cfg(not(feature = "rustls"))]
+        {
+            false
+        }
+    }
+}
+impl From<TcpStream> for NetStream {
+    fn from(stream: TcpStream) -> Self {
+        NetStream::Tcp(stream)
+    }
+}
+#[cfg(unix)]
+impl From<UnixStream> for NetStream {
+    fn from(stream: UnixStream) -> Self {
+        NetStream::Unix(stream)
+    }
+}
+impl Read for NetStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            NetStream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            NetStream::Unix(stream) => stream.read(buf),
+            #[cfg(feature = "rustls")]
+            NetStream::TlsServer(stream) => stream.read(buf),
+            #[cfg(feature = "rustls")]
+            NetStream::TlsClient(stream) => stream.read(buf),
+        }
+    }
+}
+impl Write for NetStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            NetStream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            NetStream::Unix(stream) => stream.write(buf),
+            #[cfg(feature = "rustls")]
+            NetStream::TlsServer(stream) => stream.write(buf),
+            #[cfg(feature = "rustls")]
+            NetStream::TlsClient(stream) => stream.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            NetStream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            NetStream::Unix(stream) => stream.flush(),
+            #[cfg(feature = "rustls")]
+            NetStream::TlsServer(stream) => stream.flush(),
+            #[cfg(feature = "rustls")]
+            NetStream::TlsClient(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Where a connection came from
+#[derive(Debug, Clone, PartialEq)]
+pub enum PeerAddr {
+    Tcp(SocketAddr),
+    /// Unix socket clients are usually unnamed, so the path is rarely known
+    Unix(Option<PathBuf>),
+}
+impl PeerAddr {
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            PeerAddr::Tcp(address) => Some(address.ip()),
+            PeerAddr::Unix(_) => None,
+        }
+    }
+}
+impl From<SocketAddr> for PeerAddr {
+    fn from(address: SocketAddr) -> Self {
+        PeerAddr::Tcp(address)
+    }
+}
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Tcp(address) => write!(f, "{}", address),
+            PeerAddr::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+            PeerAddr::Unix(None) => f.write_str("unix:unnamed"),
+        }
+    }
+}
+
+/// Make a connected pair of in-memory streams, handy for running both ends of a protocol in tests
+pub fn duplex() -> (Duplex, Duplex) {
+    let (left_sender, left_receiver) = mpsc::channel();
+    let (right_sender, right_receiver) = mpsc::channel();
+    (
+        Duplex::new(left_sender, right_receiver),
+        Duplex::new(right_sender, left_receiver),
+    )
+}
+
+/// One end of an in-memory stream made by [`duplex`].
+///
+/// Reads return end of file once the other end is dropped, and `WouldBlock` when
+/// the read timeout runs out.
+#[derive(Debug)]
+pub struct Duplex {
+    sender: Sender<Vec<u8>>,
+    receiver: Receiver<Vec<u8>>,
+    buffer: Vec<u8>,
+    position: usize,
+    read_timeout: Option<Duration>,
+}
+impl Duplex {
+    fn new(sender: Sender<Vec<u8>>, receiver: Receiver<Vec<u8>>) -> Self {
+        Duplex {
+            sender,
+            receiver,
+            buffer: Vec::new(),
+            position: 0,
+            read_timeout: None,
+        }
+    }
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+}
+impl Read for Duplex {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.buffer.len() {
+            let received = match self.read_timeout {
+                Some(timeout) => self.receiver.recv_timeout(timeout),
+                None => self
+                    .receiver
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            self.buffer = match received {
+                Ok(bytes) => bytes,
+                Err(RecvTimeoutError::Timeout) => return Err(io::ErrorKind::WouldBlock.into()),
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            };
+            self.position = 0;
+        }
+        let length = buf.len().min(self.buffer.len() - self.position);
+        buf[..length].copy_from_slice(&self.buffer[self.position..self.position + length]);
+        self.position += length;
+        Ok(length)
+    }
+}
+impl Write for Duplex {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.sender
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
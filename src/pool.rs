@@ -0,0 +1,133 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Capacities the pool hands out, smallest first
+pub const SIZE_CLASSES: [usize; 8] = [64, 256, 1024, 4096, 16384, 65536, 262144, 1048576];
+
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// Buffers kept per size class; more are freed when they come back
+    pub max_buffers_per_class: usize,
+}
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_buffers_per_class: 16,
+        }
+    }
+}
+
+/// Recycles the buffers frames are read into and encoded in, see [`crate::websocket::WebSocketConfig::buffer_pool`].
+///
+/// Clones share their buffers, so one pool can serve every connection or each
+/// connection can get its own. Buffers are grouped by [`SIZE_CLASSES`]; larger
+/// ones are allocated and freed as usual.
+#[derive(Clone)]
+pub struct BufferPool {
+    config: PoolConfig,
+    classes: Arc<Vec<Mutex<Vec<Vec<u8>>>>>,
+}
+impl BufferPool {
+    pub fn new() -> Self {
+        BufferPool::with_config(PoolConfig::default())
+    }
+    pub fn with_config(config: PoolConfig) -> Self {
+        // Room for every buffer up front, so giving one back never allocates
+        let classes = SIZE_CLASSES
+            .iter()
+            .map(|_| Mutex::new(Vec::with_capacity(config.max_buffers_per_class)))
+            .collect();
+        BufferPool {
+            config,
+            classes: Arc::new(classes),
+        }
+    }
+    /// An empty buffer with room for at least `capacity` bytes
+    pub fn take(&self, capacity: usize) -> Vec<u8> {
+        let class = match SIZE_CLASSES.iter().position(|size| *size >= capacity) {
+            Some(class) => class,
+            None => return Vec::with_capacity(capacity),
+        };
+        let buffer = self.classes[class]
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .pop();
+        buffer.unwrap_or_else(|| Vec::with_capacity(SIZE_CLASSES[class]))
+    }
+    /// A pooled copy of `data`
+    pub fn copy(&self, data: &[u8]) -> Vec<u8> {
+        let mut buffer = self.take(data.len());
+        buffer.extend_from_slice(data);
+        buffer
+    }
+    /// Hand a buffer back for reuse; it joins the largest class it has room for
+    pub fn recycle(&self, mut buffer: Vec<u8>) {
+        let capacity = buffer.capacity();
+        if capacity > SIZE_CLASSES[SIZE_CLASSES.len() - 1] {
+            return;
+        }
+        let class = match SIZE_CLASSES.iter().rposition(|size| *size <= capacity) {
+            Some(class) => class,
+            None => return,
+        };
+        let mut buffers = self.classes[class]
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if buffers.len() < self.config.max_buffers_per_class {
+            buffer.clear();
+            buffers.push(buffer);
+        }
+    }
+    /// Buffers waiting to be reused, per size class
+    pub fn get_pooled(&self) -> Vec<usize> {
+        self.classes
+            .iter()
+            .map(|buffers| {
+                buffers
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .len()
+            })
+            .collect()
+    }
+}
+impl Default for BufferPool {
+    fn default() -> Self {
+        BufferPool::new()
+    }
+}
+impl fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufferPool")
+            .field("config", &self.config)
+            .field("pooled", &self.get_pooled())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_hand_out_buffers_by_size_class() {
+        let pool = BufferPool::with_config(PoolConfig {
+            max_buffers_per_class: 1,
+        });
+        let buffer = pool.take(100);
+        assert_eq!(buffer.capacity(), 256);
+        let address = buffer.as_ptr();
+        pool.recycle(buffer);
+        pool.recycle(Vec::with_capacity(300));
+        assert_eq!(pool.get_pooled(), [0, 1, 0, 0, 0, 0, 0, 0]);
+
+        let buffer = pool.copy(b"Hello");
+        assert_eq!(buffer, b"Hello");
+        let buffer = pool.take(200);
+        assert_eq!(buffer.as_ptr(), address);
+        pool.recycle(Vec::with_capacity(10));
+        pool.recycle(Vec::with_capacity(2 << 20));
+        assert_eq!(pool.take(2 << 20).capacity(), 2 << 20);
+        assert_eq!(pool.get_pooled(), [0; 8]);
+    }
+}
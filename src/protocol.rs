@@ -0,0 +1,472 @@
+use crate::dataframe::{
+    frame_positions, get_frame_length, DataFrame, Opcode, MAX_FRAME_HEADER_LENGTH,
+};
+use crate::error::{Error, ProtocolError, Result, Timeout};
+use crate::message::{close_code, CloseFrame, Message};
+use crate::metrics::{Direction, Metrics};
+use crate::pool::BufferPool;
+use crate::websocket::WebSocketConfig;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Which end of the connection we are; clients mask what they send, servers don't
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Role {
+    Server,
+    Client,
+}
+impl Role {
+    /// The masking key for the next frame we send
+    pub fn get_mask(&self) -> Option<[u8; 4]> {
+        match self {
+            Role::Server => None,
+            Role::Client => Some(random_bytes()),
+        }
+    }
+}
+
+/// Unpredictable bytes for masking keys and handshake nonces, seeded by the std hasher keys
+pub(crate) fn random_bytes<const N: usize>() -> [u8; N] {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut output = [0; N];
+    for chunk in output.chunks_mut(8) {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+        chunk.copy_from_slice(&hasher.finish().to_le_bytes()[..chunk.len()]);
+    }
+    output
+}
+
+/// What a complete frame amounted to once it went through [`MessageAssembler::push`]
+#[derive(Debug)]
+pub(crate) enum Incoming {
+    Message(Message),
+    Close(Option<CloseFrame>),
+}
+
+/// Split one complete frame off the front of `buffer`, `None` until all of it has been read.
+///
+/// With a pool the frame is copied into a pooled buffer, otherwise the rest of `buffer` moves to a new one.
+pub(crate) fn take_frame(
+    buffer: &mut Vec<u8>,
+    max_frame_size: usize,
+    pool: Option<&BufferPool>,
+) -> Result<Option<DataFrame>> {
+    let length = match get_frame_length(buffer) {
+        Some(length) => length,
+        None => return Ok(None),
+    };
+    if length.saturating_sub(14) > max_frame_size {
+        return Err(Error::MessageTooBig);
+    }
+    if buffer.len() < length {
+        return Ok(None);
+    }
+    let data = match pool {
+        Some(pool) => {
+            let data = pool.copy(&buffer[..length]);
+            buffer.drain(..length);
+            data
+        }
+        None => {
+            let rest = buffer.split_off(length);
+            std::mem::replace(buffer, rest)
+        }
+    };
+    Ok(Some(DataFrame::new(data)))
+}
+/// A frame header copied out of the read buffer
+pub(crate) type FrameHeader = DataFrame<[u8; MAX_FRAME_HEADER_LENGTH]>;
+
+/// The header of the frame at the front of `buffer`, with its length and the payload length.
+///
+/// `None` until the whole header has been read; the payload may still be missing.
+pub(crate) fn peek_frame_header(buffer: &[u8]) -> Option<(FrameHeader, usize, usize)> {
+    let second = *buffer.get(1)?;
+    let extra_length = match second & frame_positions::MASK_PAYLOAD_LENGTH {
+        126 => 2,
+        127 => 8,
+        _ => 0,
+    };
+    let mask_length = match second & frame_positions::IS_MASK == frame_positions::IS_MASK {
+        true => 4,
+        false => 0,
+    };
+    let header_length = 2 + extra_length + mask_length;
+    if buffer.len() < header_length {
+        return None;
+    }
+    let payload_length = get_frame_length(buffer)? - header_length;
+    let header = DataFrame::header(&buffer[..header_length]);
+    Some((header, header_length, payload_length))
+}
+/// Validate the parts of a frame that don't depend on earlier frames
+pub(crate) fn check_frame<D>(frame: &DataFrame<D>, role: Role) -> Result<Opcode>
+where
+    D: AsRef<[u8]>,
+{
+    match (role, frame.is_mask()) {
+        (Role::Server, false) => return Err(ProtocolError::UnmaskedFrame.into()),
+        (Role::Client, true) => return Err(ProtocolError::MaskedFrame.into()),
+        _ => {}
+    }
+    if frame.is_rsv1() || frame.is_rsv2() || frame.is_rsv3() {
+        return Err(ProtocolError::ReservedBitsSet.into());
+    }
+    let opcode = Opcode::from(frame.get_opcode());
+    if opcode == Opcode::Unknown {
+        return Err(ProtocolError::UnknownOpcode.into());
+    }
+    if opcode.is_control() {
+        if !frame.is_fin() {
+            return Err(ProtocolError::FragmentedControlFrame.into());
+        }
+        if frame.get_payload_length() > 125 {
+            return Err(ProtocolError::ControlFrameTooBig.into());
+        }
+    }
+    Ok(opcode)
+}
+pub(crate) fn parse_close_payload(payload: &[u8]) -> Result<Option<CloseFrame>> {
+    match payload.len() {
+        0 => Ok(None),
+        1 => Err(ProtocolError::InvalidCloseFrame.into()),
+        _ => {
+            if std::str::from_utf8(&payload[2..]).is_err() {
+                return Err(ProtocolError::InvalidUtf8.into());
+            }
+            match CloseFrame::from_payload(payload) {
+                Some(frame) if !close_code::is_valid(frame.code) => {
+                    Err(ProtocolError::InvalidCloseFrame.into())
+                }
+                frame => Ok(frame),
+            }
+        }
+    }
+}
+/// The close code to send when giving up on a connection because of `error`
+pub(crate) fn close_code_for(error: &Error) -> Option<u16> {
+    let code = match error {
+        Error::Protocol(error) => Some(error.close_code()),
+        Error::MessageTooBig => Some(close_code::MESSAGE_TOO_BIG),
+        Error::Timeout(timeout) => timeout.close_code(),
+        _ => None,
+    };
+    #[cfg(feature = "tracing")]
+    match (error, code) {
+        (Error::Timeout(timeout), _) => tracing::info!(%timeout, code, "timed out"),
+        (_, Some(code)) => tracing::warn!(%error, code, "protocol violation"),
+        _ => {}
+    }
+    code
+}
+/// Log a message at `TRACE` level when [`WebSocketConfig::trace_payloads`] is set
+#[cfg(feature = "tracing")]
+pub(crate) fn trace_message(limit: Option<usize>, direction: Direction, message: &Message) {
+    if let Some(limit) = limit {
+        let payload: &[u8] = message.as_ref();
+        tracing::trace!(
+            direction = direction.get_name(),
+            opcode = message.get_opcode().get_name(),
+            size = payload.len(),
+            payload = %String::from_utf8_lossy(&payload[..payload.len().min(limit)]),
+            "message"
+        );
+    }
+}
+fn finish_message(opcode: Opcode, data: Vec<u8>) -> Result<Message> {
+    match opcode {
+        Opcode::Text => String::from_utf8(data)
+            .map(Message::Text)
+            .map_err(|_| ProtocolError::InvalidUtf8.into()),
+        _ => Ok(Message::Binary(data)),
+    }
+}
+
+/// Turns frames into messages, joining fragments and validating them on the way
+#[derive(Debug)]
+pub(crate) struct MessageAssembler {
+    max_message_size: usize,
+    role: Role,
+    fragments: Option<(Opcode, Vec<u8>)>,
+    /// Where message payloads come from and where frames go once they are done with
+    pool: Option<BufferPool>,
+    /// Where received frames and messages are counted, for every transport
+    metrics: Option<Arc<dyn Metrics>>,
+    #[cfg(feature = "tracing")]
+    trace_payloads: Option<usize>,
+}
+impl MessageAssembler {
+    pub fn new(config: &WebSocketConfig, role: Role) -> Self {
+        MessageAssembler {
+            max_message_size: config.max_message_size,
+            role,
+            fragments: None,
+            pool: config.buffer_pool.clone(),
+            metrics: config.metrics.clone(),
+            #[cfg(feature = "tracing")]
+            trace_payloads: config.trace_payloads,
+        }
+    }
+    pub fn push(&mut self, frame: DataFrame) -> Result<Option<Incoming>> {
+        if let Some(metrics) = &self.metrics {
+            let opcode = Opcode::from(frame.get_opcode());
+            metrics.frame(Direction::In, opcode, frame.get_full_frame_length());
+        }
+        let incoming = self.push_frame(&frame);
+        if let (Some(metrics), Ok(Some(Incoming::Message(message)))) = (&self.metrics, &incoming) {
+            if matches!(message, Message::Text(_) | Message::Binary(_)) {
+                metrics.message(Direction::In, message.as_ref().len());
+            }
+        }
+        #[cfg(feature = "tracing")]
+        match &incoming {
+            Ok(Some(Incoming::Message(message))) => {
+                trace_message(self.trace_payloads, Direction::In, message)
+            }
+            Ok(Some(Incoming::Close(close_frame))) => tracing::debug!(
+                code = close_frame.as_ref().map(|frame| frame.code),
+                "received close frame"
+            ),
+            _ => {}
+        }
+        if let Some(pool) = &self.pool {
+            pool.recycle(frame.into_data());
+        }
+        incoming
+    }
+    fn copy(&self, payload: &[u8]) -> Vec<u8> {
+        match &self.pool {
+            Some(pool) => pool.copy(payload),
+            None => payload.to_vec(),
+        }
+    }
+    fn push_frame(&mut self, frame: &DataFrame) -> Result<Option<Incoming>> {
+        let opcode = check_frame(frame, self.role)?;
+        let payload = frame.get_payload_data();
+        match opcode {
+            Opcode::Ping => Ok(Some(Incoming::Message(Message::Ping(self.copy(payload))))),
+            Opcode::Pong => Ok(Some(Incoming::Message(Message::Pong(self.copy(payload))))),
+            Opcode::Close => parse_close_payload(payload).map(|frame| Some(Incoming::Close(frame))),
+            Opcode::Text | Opcode::Binary => {
+                if self.fragments.is_some() {
+                    return Err(ProtocolError::ExpectedContinuation.into());
+                }
+                if payload.len() > self.max_message_size {
+                    return Err(Error::MessageTooBig);
+                }
+                if frame.is_fin() {
+                    return finish_message(opcode, self.copy(payload))
+                        .map(|message| Some(Incoming::Message(message)));
+                }
+                self.fragments = Some((opcode, self.copy(payload)));
+                Ok(None)
+            }
+            Opcode::Continuation => {
+                let (opcode, mut data) = self
+                    .fragments
+                    .take()
+                    .ok_or(ProtocolError::UnexpectedContinuation)?;
+                if data.len() + payload.len() > self.max_message_size {
+                    return Err(Error::MessageTooBig);
+                }
+                data.extend_from_slice(payload);
+                if frame.is_fin() {
+                    return finish_message(opcode, data)
+                        .map(|message| Some(Incoming::Message(message)));
+                }
+                self.fragments = Some((opcode, data));
+                Ok(None)
+            }
+            Opcode::Unknown => Err(ProtocolError::UnknownOpcode.into()),
+        }
+    }
+}
+
+/// Validates UTF-8 that arrives in pieces, which may split characters
+#[derive(Debug, Default)]
+pub(crate) struct Utf8Validator {
+    partial: [u8; 4],
+    partial_length: usize,
+}
+impl Utf8Validator {
+    pub fn push(&mut self, mut data: &[u8]) -> Result<()> {
+        // Finish the character the last piece ended in first
+        while self.partial_length > 0 && !data.is_empty() {
+            self.partial[self.partial_length] = data[0];
+            self.partial_length += 1;
+            data = &data[1..];
+            match std::str::from_utf8(&self.partial[..self.partial_length]) {
+                Ok(_) => self.partial_length = 0,
+                Err(error) if error.error_len().is_some() => {
+                    return Err(ProtocolError::InvalidUtf8.into())
+                }
+                Err(_) => {}
+            }
+        }
+        match std::str::from_utf8(data) {
+            Ok(_) => Ok(()),
+            Err(error) if error.error_len().is_none() => {
+                let rest = &data[error.valid_up_to()..];
+                self.partial[..rest.len()].copy_from_slice(rest);
+                self.partial_length = rest.len();
+                Ok(())
+            }
+            Err(_) => Err(ProtocolError::InvalidUtf8.into()),
+        }
+    }
+    /// Fails if the text ended in the middle of a character
+    pub fn finish(&self) -> Result<()> {
+        match self.partial_length {
+            0 => Ok(()),
+            _ => Err(ProtocolError::InvalidUtf8.into()),
+        }
+    }
+}
+
+/// A message whose payload is handed out as it arrives instead of being joined first
+#[derive(Debug)]
+pub(crate) struct StreamedMessage {
+    opcode: Opcode,
+    fin: bool,
+    /// Payload bytes of the current frame that haven't been read yet
+    remaining: usize,
+    mask: [u8; 4],
+    /// Position in the payload of the current frame, for unmasking
+    offset: usize,
+    size: usize,
+    utf8: Option<Utf8Validator>,
+}
+impl StreamedMessage {
+    /// Start a message from the header of its first frame
+    pub fn new(header: &FrameHeader, payload_length: usize, role: Role) -> Result<Self> {
+        let opcode = match check_frame(header, role)? {
+            opcode @ (Opcode::Text | Opcode::Binary) => opcode,
+            _ => return Err(ProtocolError::UnexpectedContinuation.into()),
+        };
+        Ok(StreamedMessage {
+            opcode,
+            fin: header.is_fin(),
+            remaining: payload_length,
+            mask: header.get_masking_key(),
+            offset: 0,
+            size: 0,
+            utf8: match opcode {
+                Opcode::Text => Some(Utf8Validator::default()),
+                _ => None,
+            },
+        })
+    }
+    /// Go on with the header of the next frame once the current one has been read
+    pub fn next_frame(
+        &mut self,
+        header: &FrameHeader,
+        payload_length: usize,
+        role: Role,
+    ) -> Result<()> {
+        if check_frame(header, role)? != Opcode::Continuation {
+            return Err(ProtocolError::ExpectedContinuation.into());
+        }
+        self.fin = header.is_fin();
+        self.remaining = payload_length;
+        self.mask = header.get_masking_key();
+        self.offset = 0;
+        Ok(())
+    }
+    pub fn get_opcode(&self) -> Opcode {
+        self.opcode
+    }
+    pub fn get_remaining(&self) -> usize {
+        self.remaining
+    }
+    /// Payload bytes read so far
+    pub fn get_size(&self) -> usize {
+        self.size
+    }
+    pub fn is_finished(&self) -> bool {
+        self.fin && self.remaining == 0
+    }
+    /// Unmask `data`, the next payload bytes of the current frame
+    pub fn unmask(&mut self, data: &mut [u8]) -> Result<()> {
+        for byte in data.iter_mut() {
+            *byte ^= self.mask[self.offset % 4];
+            self.offset += 1;
+        }
+        self.remaining -= data.len();
+        self.size += data.len();
+        match &mut self.utf8 {
+            Some(utf8) => utf8.push(data),
+            None => Ok(()),
+        }
+    }
+    /// Check the message once all of it has been read
+    pub fn finish(&self) -> Result<()> {
+        match &self.utf8 {
+            Some(utf8) => utf8.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Deadlines for finishing fragmented messages and for idle peers
+#[derive(Debug)]
+pub(crate) struct Deadlines {
+    fragment_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    last_message: Instant,
+    fragment_started: Option<Instant>,
+}
+impl Deadlines {
+    pub fn new(fragment_timeout: Option<Duration>, idle_timeout: Option<Duration>) -> Self {
+        Deadlines {
+            fragment_timeout,
+            idle_timeout,
+            last_message: Instant::now(),
+            fragment_started: None,
+        }
+    }
+    /// Check the deadlines, then account for a frame that just arrived
+    pub fn on_frame<D>(&mut self, frame: &DataFrame<D>, now: Instant) -> Result<()>
+    where
+        D: AsRef<[u8]>,
+    {
+        self.check(now)?;
+        if Opcode::from(frame.get_opcode()).is_control() {
+            return Ok(());
+        }
+        self.last_message = now;
+        self.fragment_started = match frame.is_fin() {
+            true => None,
+            false => self.fragment_started.or(Some(now)),
+        };
+        Ok(())
+    }
+    #[cfg(any(feature = "futures", feature = "tokio", feature = "mio"))]
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let fragment = self
+            .fragment_started
+            .zip(self.fragment_timeout)
+            .map(|(started, timeout)| started + timeout);
+        let idle = self.idle_timeout.map(|timeout| self.last_message + timeout);
+        match (fragment, idle) {
+            (Some(fragment), Some(idle)) => Some(fragment.min(idle)),
+            (deadline, None) | (None, deadline) => deadline,
+        }
+    }
+    pub fn check(&self, now: Instant) -> Result<()> {
+        if let (Some(started), Some(timeout)) = (self.fragment_started, self.fragment_timeout) {
+            if now.saturating_duration_since(started) >= timeout {
+                return Err(Timeout::Fragment.into());
+            }
+        }
+        if let Some(timeout) = self.idle_timeout {
+            if now.saturating_duration_since(self.last_message) >= timeout {
+                return Err(Timeout::Idle.into());
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,363 @@
+use crate::message::{close_code, WriteMessage};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+/// What [`SendQueue::send`] does with a message while the queue is full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FullPolicy {
+    /// Wait until the queue has drained to the low watermark.
+    ///
+    /// Never block on the queue of the connection the current thread is serving;
+    /// only that thread drains it.
+    Block,
+    /// Discard the message being sent
+    DropNewest,
+    /// Discard queued messages, oldest first, until the new one fits
+    DropOldest,
+    /// Close the connection with this code, usually 1008 or 1013
+    Disconnect(u16),
+}
+
+#[derive(Debug, Clone)]
+pub struct QueueConfig {
+    /// Queued bytes at which the queue becomes full
+    pub high_watermark: usize,
+    /// A full queue accepts messages again once it has drained to this many bytes
+    pub low_watermark: usize,
+    pub policy: FullPolicy,
+}
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            high_watermark: 1 << 20,
+            low_watermark: 1 << 18,
+            policy: FullPolicy::Disconnect(close_code::TRY_AGAIN_LATER),
+        }
+    }
+}
+
+/// The message that couldn't be queued is handed back
+pub enum SendError {
+    Full(Arc<WriteMessage>),
+    Closed(Arc<WriteMessage>),
+}
+impl SendError {
+    pub fn into_inner(self) -> Arc<WriteMessage> {
+        match self {
+            SendError::Full(message) | SendError::Closed(message) => message,
+        }
+    }
+}
+impl fmt::Debug for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Full(_) => f.write_str("Full(..)"),
+            SendError::Closed(_) => f.write_str("Closed(..)"),
+        }
+    }
+}
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Full(_) => f.write_str("send queue is full"),
+            SendError::Closed(_) => f.write_str("send queue is closed"),
+        }
+    }
+}
+impl std::error::Error for SendError {}
+
+struct State {
+    frames: VecDeque<Arc<WriteMessage>>,
+    bytes: usize,
+    full: bool,
+    closed: bool,
+    disconnect: Option<u16>,
+    dropped: u64,
+}
+impl State {
+    fn push(&mut self, message: Arc<WriteMessage>, high_watermark: usize) {
+        self.bytes += message.get_output().len();
+        self.frames.push_back(message);
+        self.full = self.full || self.bytes >= high_watermark;
+    }
+    fn pop(&mut self, low_watermark: usize) -> Option<Arc<WriteMessage>> {
+        let message = self.frames.pop_front()?;
+        self.bytes -= message.get_output().len();
+        self.full = self.full && self.bytes > low_watermark;
+        Some(message)
+    }
+}
+
+struct Shared {
+    config: QueueConfig,
+    state: Mutex<State>,
+    drained: Condvar,
+}
+
+/// A bounded queue of encoded frames waiting to be written to one connection.
+///
+/// Clones share the queue, so any thread can send while the connection's own
+/// thread writes the frames out. Frames are reference counted so the same encoded
+/// frame can wait in many queues at once. The queue is full from the moment it holds
+/// [`QueueConfig::high_watermark`] bytes until it drains to the low watermark.
+#[derive(Clone)]
+pub struct SendQueue {
+    shared: Arc<Shared>,
+}
+impl SendQueue {
+    pub fn new(config: QueueConfig) -> Self {
+        SendQueue {
+            shared: Arc::new(Shared {
+                config,
+                state: Mutex::new(State {
+                    frames: VecDeque::new(),
+                    bytes: 0,
+                    full: false,
+                    closed: false,
+                    disconnect: None,
+                    dropped: 0,
+                }),
+                drained: Condvar::new(),
+            }),
+        }
+    }
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.shared
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    pub fn get_config(&self) -> &QueueConfig {
+        &self.shared.config
+    }
+    /// Queue `message` unless the queue is full or closed
+    pub fn try_send<M>(&self, message: M) -> Result<(), SendError>
+    where
+        M: Into<Arc<WriteMessage>>,
+    {
+        let message = message.into();
+        let mut state = self.lock();
+        if state.closed {
+            return Err(SendError::Closed(message));
+        }
+        if state.full {
+            return Err(SendError::Full(message));
+        }
+        state.push(message, self.shared.config.high_watermark);
+        Ok(())
+    }
+    /// Queue `message`, applying the [`FullPolicy`] if the queue is full.
+    ///
+    /// Returns `false` if the message was dropped instead; fails once the queue is closed.
+    pub fn send<M>(&self, message: M) -> Result<bool, SendError>
+    where
+        M: Into<Arc<WriteMessage>>,
+    {
+        self.send_with_policy(message, self.shared.config.policy)
+    }
+    /// Like [`SendQueue::send`], overriding the policy the queue was configured with
+    pub fn send_with_policy<M>(&self, message: M, policy: FullPolicy) -> Result<bool, SendError>
+    where
+        M: Into<Arc<WriteMessage>>,
+    {
+        let message = message.into();
+        let config = &self.shared.config;
+        let mut state = self.lock();
+        if state.full && !state.closed {
+            match policy {
+                FullPolicy::Block => {
+                    while state.full && !state.closed {
+                        state = self
+                            .shared
+                            .drained
+                            .wait(state)
+                            .unwrap_or_else(|poisoned| poisoned.into_inner());
+                    }
+                }
+                FullPolicy::DropNewest => {
+                    state.dropped += 1;
+                    return Ok(false);
+                }
+                FullPolicy::DropOldest => {
+                    let length = message.get_output().len();
+                    while !state.frames.is_empty() && state.bytes + length >= config.high_watermark
+                    {
+                        state.pop(config.low_watermark);
+                        state.dropped += 1;
+                    }
+                    state.full = false;
+                }
+                FullPolicy::Disconnect(code) => {
+                    state.disconnect = Some(code);
+                    state.closed = true;
+                }
+            }
+        }
+        if state.closed {
+            return Err(SendError::Closed(message));
+        }
+        state.push(message, config.high_watermark);
+        Ok(true)
+    }
+    /// Number of queued frames
+    pub fn depth(&self) -> usize {
+        self.lock().frames.len()
+    }
+    pub fn queued_bytes(&self) -> usize {
+        self.lock().bytes
+    }
+    /// Messages discarded by [`FullPolicy::DropNewest`] and [`FullPolicy::DropOldest`]
+    pub fn dropped(&self) -> u64 {
+        self.lock().dropped
+    }
+    pub fn is_full(&self) -> bool {
+        self.lock().full
+    }
+    pub fn is_closed(&self) -> bool {
+        self.lock().closed
+    }
+    /// Whether both handles share the same queue
+    pub fn same_queue(&self, other: &SendQueue) -> bool {
+        Arc::ptr_eq(&self.shared, &other.shared)
+    }
+    /// Take the next frame to write
+    pub(crate) fn pop(&self) -> Option<Arc<WriteMessage>> {
+        let mut state = self.lock();
+        let message = state.pop(self.shared.config.low_watermark);
+        if !state.full {
+            self.shared.drained.notify_all();
+        }
+        message
+    }
+    /// The close code to send when [`FullPolicy::Disconnect`] kicked in
+    pub(crate) fn get_disconnect(&self) -> Option<u16> {
+        self.lock().disconnect
+    }
+    /// Refuse further messages and wake blocked senders
+    pub(crate) fn close(&self) {
+        let mut state = self.lock();
+        state.closed = true;
+        state.frames.clear();
+        state.bytes = 0;
+        self.shared.drained.notify_all();
+    }
+}
+impl fmt::Debug for SendQueue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.lock();
+        f.debug_struct("SendQueue")
+            .field("depth", &state.frames.len())
+            .field("bytes", &state.bytes)
+            .field("full", &state.full)
+            .field("closed", &state.closed)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Every message is 12 bytes once encoded
+    fn message(text: &str) -> WriteMessage {
+        WriteMessage::new(format!("{:>10}", text))
+    }
+    fn queue(policy: FullPolicy) -> SendQueue {
+        SendQueue::new(QueueConfig {
+            high_watermark: 36,
+            low_watermark: 12,
+            policy,
+        })
+    }
+    fn drain(queue: &SendQueue) -> Vec<String> {
+        std::iter::from_fn(|| queue.pop())
+            .map(|message| {
+                String::from_utf8_lossy(&message.get_output()[2..])
+                    .trim()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn should_report_full_between_watermarks() {
+        let queue = queue(FullPolicy::Block);
+        queue.try_send(message("1")).unwrap();
+        queue.try_send(message("2")).unwrap();
+        assert!(!queue.is_full());
+        queue.try_send(message("3")).unwrap();
+        assert!(queue.is_full());
+        assert!(matches!(
+            queue.try_send(message("4")),
+            Err(SendError::Full(_))
+        ));
+        assert_eq!((queue.depth(), queue.queued_bytes()), (3, 36));
+
+        queue.pop();
+        assert!(matches!(
+            queue.try_send(message("4")),
+            Err(SendError::Full(_))
+        ));
+        queue.pop();
+        assert!(!queue.is_full());
+        queue.try_send(message("4")).unwrap();
+        assert_eq!(drain(&queue), vec!["3", "4"]);
+    }
+    #[test]
+    fn should_drop_by_policy() {
+        let newest = queue(FullPolicy::DropNewest);
+        let oldest = queue(FullPolicy::DropOldest);
+        for text in &["1", "2", "3", "4", "5"] {
+            newest.send(message(text)).unwrap();
+            oldest.send(message(text)).unwrap();
+        }
+        assert_eq!(newest.dropped(), 2);
+        assert_eq!(drain(&newest), vec!["1", "2", "3"]);
+        assert_eq!(oldest.dropped(), 2);
+        assert_eq!(drain(&oldest), vec!["3", "4", "5"]);
+    }
+    #[test]
+    fn should_disconnect_when_full() {
+        let queue = queue(FullPolicy::Disconnect(close_code::POLICY_VIOLATION));
+        for text in &["1", "2", "3"] {
+            queue.send(message(text)).unwrap();
+        }
+        assert_eq!(queue.get_disconnect(), None);
+        assert!(matches!(
+            queue.send(message("4")),
+            Err(SendError::Closed(_))
+        ));
+        assert_eq!(queue.get_disconnect(), Some(1008));
+        assert!(matches!(
+            queue.try_send(message("5")),
+            Err(SendError::Closed(_))
+        ));
+    }
+    #[test]
+    fn should_block_until_drained() {
+        let queue = queue(FullPolicy::Block);
+        for text in &["1", "2", "3"] {
+            queue.send(message(text)).unwrap();
+        }
+        let sender = queue.clone();
+        let thread = thread::spawn(move || sender.send(message("4")).is_ok());
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(queue.depth(), 3);
+        queue.pop();
+        queue.pop();
+        assert!(thread.join().unwrap());
+        assert_eq!(drain(&queue), vec!["3", "4"]);
+
+        for text in &["1", "2", "3"] {
+            queue.send(message(text)).unwrap();
+        }
+        let sender = queue.clone();
+        let thread = thread::spawn(move || sender.send(message("4")).is_ok());
+        thread::sleep(Duration::from_millis(20));
+        queue.close();
+        assert!(!thread.join().unwrap());
+    }
+}
@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// `per_second` on average, with bursts of up to `burst`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub per_second: u32,
+    pub burst: u32,
+}
+impl RateLimit {
+    pub fn new(per_second: u32, burst: u32) -> Self {
+        RateLimit { per_second, burst }
+    }
+}
+
+/// What happens to a message that arrives over the limit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LimitPolicy {
+    /// Stop reading until the message fits, which slows the peer down through TCP
+    Delay,
+    /// Discard the message without handing it to the handler
+    Drop,
+    /// Close the connection with 1008
+    Close,
+}
+
+/// Limits on what peers send; only [`crate::server::Server`] applies them, the stream,
+/// codec and event loop transports leave rate limiting to the caller
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    /// Messages per connection, pings and pongs included
+    pub messages: Option<RateLimit>,
+    /// Payload bytes of messages per connection, pings and pongs included
+    pub bytes: Option<RateLimit>,
+    /// Pings are answered as they are read, so [`LimitPolicy::Drop`] only keeps them from the handler
+    pub policy: LimitPolicy,
+    /// New connections per remote IP; refused ones are answered with `429 Too Many Requests`,
+    /// sharing the threads of [`crate::server::MAX_REJECT_THREADS`] with other refusals
+    pub connections_per_ip: Option<RateLimit>,
+}
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            messages: None,
+            bytes: None,
+            policy: LimitPolicy::Delay,
+            connections_per_ip: None,
+        }
+    }
+}
+
+/// Tokens refill continuously at `rate` per second up to `capacity`
+#[derive(Debug, Clone)]
+pub(crate) struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+impl TokenBucket {
+    pub fn new(limit: RateLimit, now: Instant) -> Self {
+        let capacity = f64::from(limit.burst.max(1));
+        TokenBucket {
+            rate: f64::from(limit.per_second.max(1)),
+            capacity,
+            tokens: capacity,
+            last_refill: now,
+        }
+    }
+    fn available(&self, now: Instant) -> f64 {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        (self.tokens + elapsed * self.rate).min(self.capacity)
+    }
+    /// How long until `amount` tokens can be taken, `None` if they can be right away.
+    ///
+    /// Amounts above the capacity only need a full bucket and leave it in debt.
+    pub fn wait_for(&self, amount: u64, now: Instant) -> Option<Duration> {
+        let (available, needed) = (self.available(now), (amount as f64).min(self.capacity));
+        match available >= needed {
+            true => None,
+            false => Some(Duration::from_secs_f64((needed - available) / self.rate)),
+        }
+    }
+    /// Take `amount` tokens, or say how long until there are enough
+    pub fn take(&mut self, amount: u64, now: Instant) -> Option<Duration> {
+        let wait = self.wait_for(amount, now);
+        if wait.is_none() {
+            self.tokens = self.available(now) - amount as f64;
+            self.last_refill = now;
+        }
+        wait
+    }
+    fn is_full(&self, now: Instant) -> bool {
+        self.available(now) >= self.capacity
+    }
+}
+
+/// What a connection's limiter has seen so far
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RateCounters {
+    /// Messages received, including limited ones
+    pub messages: u64,
+    pub bytes: u64,
+    /// Messages that had to wait for the limit
+    pub delayed: u64,
+    pub dropped: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Verdict {
+    Allow,
+    Delay(Duration),
+    Drop,
+    Close,
+}
+
+/// The message and byte buckets of one connection
+#[derive(Debug)]
+pub(crate) struct ConnectionLimiter {
+    messages: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    policy: LimitPolicy,
+    counters: RateCounters,
+    /// The last verdict was [`Verdict::Delay`], so the next check is a retry of the same message
+    delaying: bool,
+}
+impl ConnectionLimiter {
+    pub fn new(config: &RateLimitConfig, now: Instant) -> Self {
+        ConnectionLimiter {
+            messages: config.messages.map(|limit| TokenBucket::new(limit, now)),
+            bytes: config.bytes.map(|limit| TokenBucket::new(limit, now)),
+            policy: config.policy,
+            counters: RateCounters::default(),
+            delaying: false,
+        }
+    }
+    pub fn get_counters(&self) -> RateCounters {
+        self.counters
+    }
+    /// Decide what to do with a message of `size` payload bytes.
+    ///
+    /// After [`Verdict::Delay`], wait and ask again for the same message; retries, even
+    /// early ones, are not counted twice.
+    pub fn check(&mut self, size: usize, now: Instant) -> Verdict {
+        let wait = [(&self.messages, 1), (&self.bytes, size as u64)]
+            .iter()
+            .filter_map(|(bucket, amount)| bucket.as_ref()?.wait_for(*amount, now))
+            .max();
+        let wait = match (wait, self.policy) {
+            (Some(wait), LimitPolicy::Delay) => {
+                if !self.delaying {
+                    self.counters.delayed += 1;
+                    self.delaying = true;
+                }
+                return Verdict::Delay(wait);
+            }
+            (wait, _) => wait,
+        };
+        self.delaying = false;
+        self.counters.messages += 1;
+        self.counters.bytes += size as u64;
+        match (wait, self.policy) {
+            (None, _) => {
+                if let Some(bucket) = &mut self.messages {
+                    bucket.take(1, now);
+                }
+                if let Some(bucket) = &mut self.bytes {
+                    bucket.take(size as u64, now);
+                }
+                Verdict::Allow
+            }
+            (Some(_), LimitPolicy::Drop) => {
+                self.counters.dropped += 1;
+                Verdict::Drop
+            }
+            (Some(_), _) => Verdict::Close,
+        }
+    }
+}
+
+/// New connections per remote IP, shared by the accept loop
+#[derive(Debug)]
+pub(crate) struct IpLimiter {
+    limit: RateLimit,
+    buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+}
+impl IpLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        IpLimiter {
+            limit,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+    pub fn allow(&self, ip: IpAddr, now: Instant) -> bool {
+        let mut buckets = self
+            .buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        // Full buckets hold nothing worth remembering
+        if buckets.len() > 1024 {
+            buckets.retain(|_, bucket| !bucket.is_full(now));
+        }
+        let limit = self.limit;
+        buckets
+            .entry(ip)
+            .or_insert_with(|| TokenBucket::new(limit, now))
+            .take(1, now)
+            .is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_refill_buckets_over_time() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(RateLimit::new(10, 2), start);
+        assert_eq!(bucket.take(1, start), None);
+        assert_eq!(bucket.take(1, start), None);
+        assert_eq!(bucket.take(1, start), Some(Duration::from_millis(100)));
+        assert_eq!(bucket.take(1, start + Duration::from_millis(100)), None);
+        // Big amounts wait for a full bucket and then go into debt
+        let later = start + Duration::from_secs(1);
+        assert_eq!(bucket.take(5, later), None);
+        assert_eq!(bucket.take(1, later), Some(Duration::from_millis(400)));
+    }
+    #[test]
+    fn should_apply_the_policy_over_the_limit() {
+        let start = Instant::now();
+        let mut config = RateLimitConfig {
+            messages: Some(RateLimit::new(1, 1)),
+            bytes: Some(RateLimit::new(100, 100)),
+            ..RateLimitConfig::default()
+        };
+        let mut limiter = ConnectionLimiter::new(&config, start);
+        assert_eq!(limiter.check(10, start), Verdict::Allow);
+        assert_eq!(
+            limiter.check(10, start),
+            Verdict::Delay(Duration::from_secs(1))
+        );
+        // Waking up a little early asks again for the same message
+        assert_eq!(
+            limiter.check(10, start + Duration::from_millis(999)),
+            Verdict::Delay(Duration::from_millis(1))
+        );
+        assert_eq!(
+            limiter.check(10, start + Duration::from_secs(1)),
+            Verdict::Allow
+        );
+        assert_eq!(
+            limiter.get_counters(),
+            RateCounters {
+                messages: 2,
+                bytes: 20,
+                delayed: 1,
+                dropped: 0
+            }
+        );
+
+        config.messages = None;
+        config.policy = LimitPolicy::Drop;
+        let mut limiter = ConnectionLimiter::new(&config, start);
+        assert_eq!(limiter.check(80, start), Verdict::Allow);
+        assert_eq!(limiter.check(80, start), Verdict::Drop);
+        assert_eq!(limiter.get_counters().dropped, 1);
+
+        config.policy = LimitPolicy::Close;
+        let mut limiter = ConnectionLimiter::new(&config, start);
+        assert_eq!(limiter.check(80, start), Verdict::Allow);
+        assert_eq!(limiter.check(80, start), Verdict::Close);
+        assert_eq!(limiter.get_counters().messages, 2);
+        assert_eq!(limiter.get_counters().bytes, 160);
+    }
+    #[test]
+    fn should_limit_connections_per_ip() {
+        let start = Instant::now();
+        let limiter = IpLimiter::new(RateLimit::new(1, 2));
+        let (first, second) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        assert!(limiter.allow(first, start));
+        assert!(limiter.allow(first, start));
+        assert!(!limiter.allow(first, start));
+        assert!(limiter.allow(second, start));
+        assert!(limiter.allow(first, start + Duration::from_secs(1)));
+    }
+}
@@ -0,0 +1,216 @@
+use crate::message::WriteMessage;
+use crate::net::PeerAddr;
+use crate::queue::{SendError, SendQueue};
+use crate::server::ShutdownHandle;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Instant, SystemTime};
+
+pub type ConnectionId = u64;
+
+/// What the [`Registry`] knows about an open connection
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionInfo {
+    pub id: ConnectionId,
+    pub peer_addr: PeerAddr,
+    /// The request target of the opening handshake
+    pub path: String,
+    pub subprotocol: Option<String>,
+    pub connected_at: SystemTime,
+}
+
+struct Entry {
+    info: ConnectionInfo,
+    queue: SendQueue,
+}
+
+struct Shared {
+    next_id: AtomicU64,
+    entries: Mutex<HashMap<ConnectionId, Entry>>,
+    /// Signalled whenever a connection leaves
+    removed: Condvar,
+    shutdown: ShutdownHandle,
+}
+
+/// Every open connection of a [`crate::server::Server`], by ID.
+///
+/// Connections are added once their handshake succeeds and removed after
+/// [`crate::server::Handler::on_close`] has run.
+#[derive(Clone)]
+pub struct Registry {
+    shared: Arc<Shared>,
+}
+impl Registry {
+    pub(crate) fn new(shutdown: ShutdownHandle) -> Self {
+        Registry {
+            shared: Arc::new(Shared {
+                next_id: AtomicU64::new(1),
+                entries: Mutex::new(HashMap::new()),
+                removed: Condvar::new(),
+                shutdown,
+            }),
+        }
+    }
+    fn lock(&self) -> MutexGuard<'_, HashMap<ConnectionId, Entry>> {
+        self.shared
+            .entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    pub(crate) fn next_id(&self) -> ConnectionId {
+        self.shared.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+    pub(crate) fn insert(&self, info: ConnectionInfo, queue: SendQueue) {
+        self.lock().insert(info.id, Entry { info, queue });
+    }
+    pub(crate) fn remove(&self, id: ConnectionId) {
+        self.lock().remove(&id);
+        self.shared.removed.notify_all();
+    }
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+    pub fn ids(&self) -> Vec<ConnectionId> {
+        self.lock().keys().copied().collect()
+    }
+    pub fn get(&self, id: ConnectionId) -> Option<ConnectionInfo> {
+        self.lock().get(&id).map(|entry| entry.info.clone())
+    }
+    /// Every open connection, in no particular order
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        self.lock()
+            .values()
+            .map(|entry| entry.info.clone())
+            .collect()
+    }
+    pub fn get_queue(&self, id: ConnectionId) -> Option<SendQueue> {
+        self.lock().get(&id).map(|entry| entry.queue.clone())
+    }
+    /// Queue `message` for connection `id`; unknown IDs are treated as closed connections
+    pub fn send<M>(&self, id: ConnectionId, message: M) -> Result<bool, SendError>
+    where
+        M: Into<Arc<WriteMessage>>,
+    {
+        match self.get_queue(id) {
+            Some(queue) => queue.send(message),
+            None => Err(SendError::Closed(message.into())),
+        }
+    }
+    /// Close every connection with 1001 "Going Away" and stop accepting new ones.
+    ///
+    /// Waits for the connections to finish their closing handshakes until `deadline`;
+    /// the ones still open then are dropped without waiting any longer. Returns how
+    /// many connections that was.
+    pub fn shutdown(&self, deadline: Instant) -> usize {
+        self.shared.shutdown.shutdown_by(deadline);
+        let mut entries = self.lock();
+        loop {
+            let now = Instant::now();
+            if entries.is_empty() || now >= deadline {
+                return entries.len();
+            }
+            entries = self
+                .shared
+                .removed
+                .wait_timeout(entries, deadline - now)
+                .map(|(entries, _)| entries)
+                .unwrap_or_else(|poisoned| poisoned.into_inner().0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::connect_with_config;
+    use crate::message::{CloseFrame, Message};
+    use crate::server::tests::{connect_client, read_server_frame, serve_echo, Echo};
+    use crate::server::{Server, ServerConfig};
+    use crate::websocket::WebSocketConfig;
+    use std::net::SocketAddr;
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+
+    fn serve(config: ServerConfig) -> (SocketAddr, Registry, Arc<Echo>, JoinHandle<()>) {
+        let server = Server::bind("127.0.0.1:0").unwrap().with_config(config);
+        let (address, registry) = (server.local_addr().unwrap(), server.registry());
+        let (_shutdown, echo, thread) = serve_echo(server);
+        (address, registry, echo, thread)
+    }
+    fn wait_for_connections(registry: &Registry, count: usize) {
+        while registry.len() != count {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn should_track_connections_and_send_by_id() {
+        let config = ServerConfig {
+            websocket: WebSocketConfig {
+                subprotocols: vec![String::from("chat"), String::from("json")],
+                ..WebSocketConfig::default()
+            },
+            ..ServerConfig::default()
+        };
+        let (address, registry, echo, thread) = serve(config);
+        let client_config = WebSocketConfig {
+            subprotocols: vec![String::from("json")],
+            ..WebSocketConfig::default()
+        };
+        let url = format!("ws://{}/chat?room=1", address);
+        let mut websocket = connect_with_config(&url, client_config).unwrap();
+        assert_eq!(
+            websocket.get_handshake().subprotocol.as_deref(),
+            Some("json")
+        );
+
+        wait_for_connections(&registry, 1);
+        let info = registry.list().remove(0);
+        assert_eq!(registry.get(info.id), Some(info.clone()));
+        assert_eq!(info.path, "/chat?room=1");
+        assert_eq!(info.subprotocol.as_deref(), Some("json"));
+        let local_addr = websocket.get_ref().get_tcp().unwrap().local_addr().unwrap();
+        assert_eq!(info.peer_addr, PeerAddr::from(local_addr));
+
+        registry.send(info.id, WriteMessage::new("Hi")).unwrap();
+        assert!(matches!(websocket.read_message(), Ok(Message::Text(text)) if text == "Hi"));
+        assert!(matches!(
+            registry.send(info.id + 1, WriteMessage::new("Hi")),
+            Err(SendError::Closed(_))
+        ));
+
+        let shutdown = {
+            let registry = registry.clone();
+            let deadline = Instant::now() + Duration::from_secs(5);
+            thread::spawn(move || registry.shutdown(deadline))
+        };
+        assert!(matches!(websocket.read_message(), Ok(Message::Close)));
+        assert_eq!(
+            websocket.get_close_frame(),
+            Some(&CloseFrame::new(1001, "Going Away"))
+        );
+        assert_eq!(shutdown.join().unwrap(), 0);
+        thread.join().unwrap();
+        assert!(registry.is_empty());
+        assert_eq!(*echo.events.lock().unwrap(), vec!["open", "close 1001"]);
+    }
+    #[test]
+    fn should_drop_connections_still_open_at_the_deadline() {
+        let (address, registry, echo, thread) = serve(ServerConfig::default());
+        let mut silent = connect_client(address);
+        wait_for_connections(&registry, 1);
+
+        let deadline = Instant::now() + Duration::from_millis(50);
+        assert_eq!(registry.shutdown(deadline), 1);
+        let (opcode, payload) = read_server_frame(&mut silent);
+        assert_eq!(opcode, 136);
+        assert_eq!(payload, b"\x03\xe9Going Away");
+        thread.join().unwrap();
+        assert!(registry.is_empty());
+        assert_eq!(*echo.events.lock().unwrap(), vec!["open", "close 0"]);
+    }
+}
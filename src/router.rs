@@ -0,0 +1,289 @@
+use crate::error::Error;
+use crate::net::PeerAddr;
+use crate::server::Handler;
+use std::fmt;
+use std::sync::Arc;
+
+/// The values of the `:name` and `*name` segments of a matched route
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Params(Vec<(String, String)>);
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    /// `:name`, exactly one segment
+    Param(String),
+    /// `*` or `*name`, everything that is left
+    Wildcard(Option<String>),
+}
+
+/// A route pattern with a wildcard that isn't its last segment
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatternError(String);
+impl PatternError {
+    pub fn get_pattern(&self) -> &str {
+        &self.0
+    }
+}
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a wildcard has to be the last segment of {:?}", self.0)
+    }
+}
+impl std::error::Error for PatternError {}
+
+/// A path pattern like `/rooms/:room/*rest`; empty segments are ignored
+#[derive(Debug, Clone, PartialEq)]
+struct Pattern(Vec<Segment>);
+impl Pattern {
+    fn parse(pattern: &str) -> Result<Self, PatternError> {
+        let segments: Vec<Segment> = pattern
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| match segment.as_bytes()[0] {
+                b':' => Segment::Param(String::from(&segment[1..])),
+                b'*' => Segment::Wildcard(
+                    Some(&segment[1..])
+                        .filter(|name| !name.is_empty())
+                        .map(String::from),
+                ),
+                _ => Segment::Literal(String::from(segment)),
+            })
+            .collect();
+        let wildcard = segments
+            .iter()
+            .position(|segment| matches!(segment, Segment::Wildcard(_)));
+        if matches!(wildcard, Some(position) if position + 1 != segments.len()) {
+            return Err(PatternError(String::from(pattern)));
+        }
+        Ok(Pattern(segments))
+    }
+    fn matches(&self, path: &str) -> Option<Params> {
+        let mut params = Vec::new();
+        let mut parts = path.split('/').filter(|part| !part.is_empty());
+        for segment in &self.0 {
+            match segment {
+                Segment::Literal(literal) => {
+                    if parts.next()? != literal {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => params.push((name.clone(), String::from(parts.next()?))),
+                Segment::Wildcard(name) => {
+                    let rest = parts.by_ref().collect::<Vec<&str>>().join("/");
+                    if let Some(name) = name {
+                        params.push((name.clone(), rest));
+                    }
+                }
+            }
+        }
+        match parts.next() {
+            Some(_) => None,
+            None => Some(Params(params)),
+        }
+    }
+}
+
+type Factory = Box<dyn Fn(&Params) -> Arc<dyn Handler> + Send + Sync>;
+type ErrorCallback = Box<dyn Fn(&PeerAddr, &Error) + Send + Sync>;
+
+/// Picks the [`Handler`] of each connection from its request path, see [`crate::server::Server::serve_router`].
+///
+/// Routes are tried in the order they were added and the query string is ignored.
+/// Requests that match no route are answered with `404 Not Found`.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<(Pattern, Factory)>,
+    on_error: Option<ErrorCallback>,
+}
+impl Router {
+    pub fn new() -> Self {
+        Router::default()
+    }
+    /// Serve `pattern` with a handler made by `factory` for every connection.
+    ///
+    /// `:name` matches one segment and `*name` or `*` the rest of the path. The
+    /// handler can read the values through [`crate::server::Connection::get_params`] too.
+    /// Patterns with a wildcard before their last segment are refused.
+    pub fn route<F, H>(mut self, pattern: &str, factory: F) -> Result<Self, PatternError>
+    where
+        F: Fn(&Params) -> H + Send + Sync + 'static,
+        H: Handler,
+    {
+        let factory: Factory = Box::new(move |params| Arc::new(factory(params)));
+        self.routes.push((Pattern::parse(pattern)?, factory));
+        Ok(self)
+    }
+    /// Called for connections that fail before a route was picked, like unmatched paths
+    pub fn on_error<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&PeerAddr, &Error) + Send + Sync + 'static,
+    {
+        self.on_error = Some(Box::new(callback));
+        self
+    }
+}
+
+/// Where the server gets the handler of a connection
+pub(crate) trait Routes: Send + Sync + 'static {
+    /// The handler for request `target`, `None` if nothing serves it
+    fn resolve(&self, target: &str) -> Option<(Arc<dyn Handler>, Params)>;
+    /// Errors from before a handler was picked
+    fn on_error(&self, peer_addr: &PeerAddr, error: &Error);
+}
+impl<H> Routes for Arc<H>
+where
+    H: Handler,
+{
+    fn resolve(&self, _target: &str) -> Option<(Arc<dyn Handler>, Params)> {
+        Some((self.clone(), Params::default()))
+    }
+    fn on_error(&self, peer_addr: &PeerAddr, error: &Error) {
+        Handler::on_error(&**self, peer_addr, error)
+    }
+}
+impl Routes for Router {
+    fn resolve(&self, target: &str) -> Option<(Arc<dyn Handler>, Params)> {
+        let path = target.split('?').next().unwrap_or(target);
+        self.routes.iter().find_map(|(pattern, factory)| {
+            let params = pattern.matches(path)?;
+            Some((factory(&params), params))
+        })
+    }
+    fn on_error(&self, peer_addr: &PeerAddr, error: &Error) {
+        if let Some(callback) = &self.on_error {
+            callback(peer_addr, error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accept::keys::KeyError;
+    use crate::message::Message;
+    use crate::server::tests::connect_client;
+    use crate::server::{Connection, Server};
+    use crate::websocket::tests::REQUEST;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::Mutex;
+    use std::thread;
+
+    struct Room(String);
+    impl Handler for Room {
+        fn on_message(&self, connection: &mut Connection, _message: Message) {
+            let user = connection.get_params().get("user").unwrap_or("nobody");
+            let reply = format!("{} in {}", user, self.0);
+            connection.send(Message::Text(reply)).unwrap();
+        }
+    }
+
+    fn params(pattern: &str, path: &str) -> Option<Vec<(String, String)>> {
+        Pattern::parse(pattern)
+            .unwrap()
+            .matches(path)
+            .map(|params| params.0)
+    }
+    fn pairs(pairs: &[(&str, &str)]) -> Option<Vec<(String, String)>> {
+        Some(
+            pairs
+                .iter()
+                .map(|(key, value)| (String::from(*key), String::from(*value)))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn should_match_params_and_wildcards() {
+        assert_eq!(params("/chat", "/chat"), pairs(&[]));
+        assert_eq!(params("/chat", "/chat/"), pairs(&[]));
+        assert_eq!(params("/chat", "/chats"), None);
+        assert_eq!(params("/chat", "/chat/lobby"), None);
+        assert_eq!(
+            params("/rooms/:room/users/:user", "/rooms/1/users/bob"),
+            pairs(&[("room", "1"), ("user", "bob")])
+        );
+        assert_eq!(params("/rooms/:room", "/rooms"), None);
+        assert_eq!(
+            params("/files/*path", "/files/a/b.txt"),
+            pairs(&[("path", "a/b.txt")])
+        );
+        assert_eq!(params("/files/*path", "/files"), pairs(&[("path", "")]));
+        assert_eq!(params("/*", "/anything/at/all"), pairs(&[]));
+    }
+    #[test]
+    fn should_only_allow_wildcards_at_the_end() {
+        let error = PatternError(String::from("/files/*path/edit"));
+        assert_eq!(Pattern::parse("/files/*path/edit"), Err(error.clone()));
+        let router = Router::new().route("/files/*path/edit", |_: &Params| {
+            Room(String::from("files"))
+        });
+        assert_eq!(router.err(), Some(error));
+    }
+    #[test]
+    fn should_route_connections_and_answer_unmatched_paths_with_404() {
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let router = {
+            let errors = errors.clone();
+            Router::new()
+                .route("/chat/:room/:user", |params: &Params| {
+                    Room(String::from(params.get("room").unwrap()))
+                })
+                .unwrap()
+                .route("/chat", |_: &Params| Room(String::from("the lobby")))
+                .unwrap()
+                .on_error(move |_, error| {
+                    errors.lock().unwrap().push(format!("{}", error));
+                })
+        };
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        let (address, shutdown) = (server.local_addr().unwrap(), server.shutdown_handle());
+        let thread = thread::spawn(move || server.serve_router(router).unwrap());
+
+        let url = format!("ws://{}/chat/games/ann?token=1", address);
+        let mut websocket = crate::client::connect(&url).unwrap();
+        websocket
+            .write_message(Message::Text(String::from("Hi")))
+            .unwrap();
+        assert!(
+            matches!(websocket.read_message(), Ok(Message::Text(text)) if text == "ann in games")
+        );
+        // `REQUEST` asks for `/chat`, which the second route serves
+        let lobby = connect_client(address);
+
+        let mut unmatched = TcpStream::connect(address).unwrap();
+        let request = String::from_utf8(REQUEST.to_vec()).unwrap();
+        unmatched
+            .write_all(request.replacen("/chat", "/nope", 1).as_bytes())
+            .unwrap();
+        let mut response = Vec::new();
+        unmatched.read_to_end(&mut response).unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 404 Not Found\r\n"));
+
+        drop((websocket, lobby));
+        shutdown.shutdown();
+        thread.join().unwrap();
+        let expected = format!("{}", Error::Handshake(KeyError::NotFound));
+        assert_eq!(*errors.lock().unwrap(), vec![expected]);
+    }
+}
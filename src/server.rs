@@ -0,0 +1,963 @@
+use crate::accept::keys::KeyError;
+use crate::accept::ws_headers::WsHeaders;
+use crate::error::{Error, Result};
+use crate::keepalive::KeepaliveEvent;
+use crate::message::{close_code, CloseFrame, Message};
+use crate::net::{NetStream, PeerAddr};
+use crate::queue::{QueueConfig, SendQueue};
+use crate::rate_limit::{ConnectionLimiter, IpLimiter, RateCounters, RateLimitConfig, Verdict};
+use crate::registry::{ConnectionId, ConnectionInfo, Registry};
+use crate::router::{Params, Router, Routes};
+use crate::websocket::{
+    parse_request_line, read_http_headers, read_request, report_handshake_failure, WebSocket,
+    WebSocketConfig,
+};
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+const SERVICE_UNAVAILABLE_RESPONSE: &[u8] =
+    b"HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+const TOO_MANY_REQUESTS_RESPONSE: &[u8] =
+    b"HTTP/1.1 429 Too Many Requests\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+/// Threads answering refused connections at once; connections refused past this are dropped
+pub const MAX_REJECT_THREADS: usize = 16;
+
+/// Callbacks for the connections of a [`Server`], shared by every connection thread
+pub trait Handler: Send + Sync + 'static {
+    fn on_open(&self, _connection: &mut Connection) {}
+    fn on_message(&self, connection: &mut Connection, message: Message);
+    fn on_close(&self, _connection: &mut Connection, _close_frame: Option<&CloseFrame>) {}
+    /// Keepalive pings and pongs, see [`WebSocketConfig::ping_interval`]
+    fn on_keepalive(&self, _connection: &mut Connection, _event: KeepaliveEvent) {}
+    /// Called for failed handshakes as well as for errors on open connections
+    fn on_error(&self, _peer_addr: &PeerAddr, _error: &Error) {}
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub websocket: WebSocketConfig,
+    /// Connections above this are answered with `503 Service Unavailable`, or dropped
+    /// without an answer while [`MAX_REJECT_THREADS`] others are being refused
+    pub max_connections: Option<usize>,
+    /// Limits for the send queue of every connection
+    pub queue: QueueConfig,
+    /// Limits for what peers send; the other transports don't apply them
+    pub rate_limit: RateLimitConfig,
+    /// How often the accept loop and blocked reads check for shutdown and deadlines
+    pub poll_interval: Duration,
+    /// How long to wait for the close reply when the server closes a connection
+    pub shutdown_timeout: Duration,
+    /// Answer plain `GET` requests for this path with [`crate::metrics::Metrics::to_prometheus`]
+    /// of [`WebSocketConfig::metrics`], `None` to only accept websockets
+    pub metrics_path: Option<String>,
+    /// Serve `wss://` by wrapping every accepted TCP stream in TLS
+    #[cfg(feature = "rustls")]
+    pub tls: Option<Arc<rustls::ServerConfig>>,
+}
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            websocket: WebSocketConfig::default(),
+            max_connections: None,
+            queue: QueueConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            poll_interval: Duration::from_millis(100),
+            shutdown_timeout: Duration::from_secs(5),
+            metrics_path: None,
+            #[cfg(feature = "rustls")]
+            tls: None,
+        }
+    }
+}
+
+/// An accepted connection as seen by a [`Handler`]
+pub struct Connection {
+    websocket: WebSocket<NetStream>,
+    info: ConnectionInfo,
+    queue: SendQueue,
+    limiter: ConnectionLimiter,
+    params: Params,
+}
+impl Connection {
+    /// Unique among the connections of a [`Server`], see [`Server::registry`]
+    pub fn id(&self) -> ConnectionId {
+        self.info.id
+    }
+    pub fn peer_addr(&self) -> &PeerAddr {
+        &self.info.peer_addr
+    }
+    pub fn get_info(&self) -> &ConnectionInfo {
+        &self.info
+    }
+    /// The path parameters of the [`Router`] route that accepted this connection
+    pub fn get_params(&self) -> &Params {
+        &self.params
+    }
+    /// What the rate limits of [`ServerConfig::rate_limit`] have counted so far
+    pub fn get_rate_counters(&self) -> RateCounters {
+        self.limiter.get_counters()
+    }
+    /// Clone this to send from other threads.
+    ///
+    /// Queued frames are written by the connection's thread between reads, so they
+    /// can wait up to [`ServerConfig::poll_interval`] while the peer is quiet.
+    pub fn get_queue(&self) -> &SendQueue {
+        &self.queue
+    }
+    pub fn send(&mut self, message: Message) -> Result<()> {
+        self.websocket.write_message(message)
+    }
+    pub fn close(&mut self, frame: CloseFrame) -> Result<()> {
+        self.websocket.close(frame)
+    }
+    pub fn get_websocket(&self) -> &WebSocket<NetStream> {
+        &self.websocket
+    }
+    pub fn get_websocket_mut(&mut self) -> &mut WebSocket<NetStream> {
+        &mut self.websocket
+    }
+}
+
+#[derive(Debug, Default)]
+struct ShutdownState {
+    requested: AtomicBool,
+    deadline: Mutex<Option<Instant>>,
+}
+
+/// Stops a running [`Server`] from another thread
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownHandle(Arc<ShutdownState>);
+impl ShutdownHandle {
+    /// Stop accepting connections and close the open ones with 1001 "Going Away".
+    ///
+    /// Each connection waits [`ServerConfig::shutdown_timeout`] for the close reply.
+    pub fn shutdown(&self) {
+        self.0.requested.store(true, Ordering::SeqCst);
+    }
+    /// Like [`ShutdownHandle::shutdown`], dropping every connection still open at `deadline`
+    pub fn shutdown_by(&self, deadline: Instant) {
+        *self
+            .0
+            .deadline
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(deadline);
+        self.shutdown();
+    }
+    pub fn is_shutdown(&self) -> bool {
+        self.0.requested.load(Ordering::SeqCst)
+    }
+    fn get_deadline(&self) -> Option<Instant> {
+        *self
+            .0
+            .deadline
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+    /// Fails the given number of accepts before accepting from the listener
+    #[cfg(test)]
+    Failing(TcpListener, AtomicUsize),
+}
+impl Listener {
+    fn accept(&self) -> io::Result<(NetStream, PeerAddr)> {
+        match self {
+            Listener::Tcp(listener) => listener
+                .accept()
+                .map(|(stream, address)| (NetStream::Tcp(stream), PeerAddr::Tcp(address))),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.accept().map(|(stream, address)| {
+                let path = address.as_pathname().map(Path::to_path_buf);
+                (NetStream::Unix(stream), PeerAddr::Unix(path))
+            }),
+            #[cfg(test)]
+            Listener::Failing(listener, failures) => {
+                let failing = failures.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                    left.checked_sub(1)
+                });
+                match failing {
+                    Ok(_) => Err(io::Error::from(io::ErrorKind::ConnectionAborted)),
+                    Err(_) => listener
+                        .accept()
+                        .map(|(stream, address)| (NetStream::Tcp(stream), PeerAddr::Tcp(address))),
+                }
+            }
+        }
+    }
+}
+
+/// A blocking server running every connection on its own thread
+pub struct Server {
+    listener: Listener,
+    config: ServerConfig,
+    shutdown: ShutdownHandle,
+    registry: Registry,
+}
+impl Server {
+    pub fn bind<A>(address: A) -> io::Result<Server>
+    where
+        A: ToSocketAddrs,
+    {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        Ok(Server::from_listener(Listener::Tcp(listener)))
+    }
+    /// Listen on a unix socket at `path`, which must not exist yet
+    #[cfg(unix)]
+    pub fn bind_unix<P>(path: P) -> io::Result<Server>
+    where
+        P: AsRef<Path>,
+    {
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Server::from_listener(Listener::Unix(listener)))
+    }
+    /// Listen on a unix socket in the Linux abstract namespace, which needs no file
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn bind_unix_abstract<N>(name: N) -> io::Result<Server>
+    where
+        N: AsRef<[u8]>,
+    {
+        #[cfg(target_os = "android")]
+        use std::os::android::net::SocketAddrExt;
+        #[cfg(target_os = "linux")]
+        use std::os::linux::net::SocketAddrExt;
+
+        let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        let listener = UnixListener::bind_addr(&address)?;
+        listener.set_nonblocking(true)?;
+        Ok(Server::from_listener(Listener::Unix(listener)))
+    }
+    fn from_listener(listener: Listener) -> Server {
+        let shutdown = ShutdownHandle::default();
+        Server {
+            listener,
+            config: ServerConfig::default(),
+            registry: Registry::new(shutdown.clone()),
+            shutdown,
+        }
+    }
+    pub fn with_config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+    /// The bound address of a TCP server
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match &self.listener {
+            Listener::Tcp(listener) => listener.local_addr(),
+            #[cfg(test)]
+            Listener::Failing(listener, _) => listener.local_addr(),
+            #[cfg(unix)]
+            Listener::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not listening on tcp",
+            )),
+        }
+    }
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+    /// The open connections, also usable for a graceful [`Registry::shutdown`]
+    pub fn registry(&self) -> Registry {
+        self.registry.clone()
+    }
+    /// Accept connections until shut down, then wait for every connection to finish.
+    ///
+    /// Failed accepts, like running out of file descriptors, are retried after a short pause.
+    pub fn serve<H>(self, handler: H) -> io::Result<()>
+    where
+        H: Handler,
+    {
+        self.serve_routes(Arc::new(handler))
+    }
+    /// Like [`Server::serve`] with a handler per connection, picked by the request path
+    pub fn serve_router(self, router: Router) -> io::Result<()> {
+        self.serve_routes(router)
+    }
+    fn serve_routes<R>(self, routes: R) -> io::Result<()>
+    where
+        R: Routes,
+    {
+        let routes = Arc::new(routes);
+        let config = Arc::new(self.config);
+        let active = Arc::new(AtomicUsize::new(0));
+        let rejecting = Arc::new(AtomicUsize::new(0));
+        let mut threads: Vec<JoinHandle<()>> = Vec::new();
+        let ip_limiter = config.rate_limit.connections_per_ip.map(IpLimiter::new);
+
+        while !self.shutdown.is_shutdown() {
+            let (stream, peer_addr) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                // Running out of file descriptors or a peer giving up before the accept
+                // doesn't stop the server; back off like when nobody is connecting
+                Err(error) => {
+                    #[cfg(feature = "tracing")]
+                    if error.kind() != io::ErrorKind::WouldBlock {
+                        tracing::warn!(%error, "failed to accept a connection");
+                    }
+                    #[cfg(not(feature = "tracing"))]
+                    let _ = error;
+                    threads.retain(|thread| !thread.is_finished());
+                    thread::sleep(config.poll_interval.min(Duration::from_millis(10)));
+                    continue;
+                }
+            };
+            let at_capacity = config
+                .max_connections
+                .map(|max| active.load(Ordering::SeqCst) >= max)
+                .unwrap_or(false);
+            let rate_limited = match (&ip_limiter, peer_addr.ip()) {
+                (Some(limiter), Some(ip)) => !limiter.allow(ip, Instant::now()),
+                _ => false,
+            };
+            if at_capacity || rate_limited {
+                // Each answer waits up to the handshake timeout for the request, so only a
+                // few are answered at once and the rest of a flood is dropped right away
+                if rejecting.fetch_add(1, Ordering::SeqCst) >= MAX_REJECT_THREADS {
+                    rejecting.fetch_sub(1, Ordering::SeqCst);
+                    continue;
+                }
+                let (config, rejecting) = (config.clone(), rejecting.clone());
+                let response = match rate_limited {
+                    true => TOO_MANY_REQUESTS_RESPONSE,
+                    false => SERVICE_UNAVAILABLE_RESPONSE,
+                };
+                threads.push(thread::spawn(move || {
+                    reject(stream, &config, response);
+                    rejecting.fetch_sub(1, Ordering::SeqCst);
+                }));
+                continue;
+            }
+            active.fetch_add(1, Ordering::SeqCst);
+            let (routes, config, active) = (routes.clone(), config.clone(), active.clone());
+            let (shutdown, registry) = (self.shutdown.clone(), self.registry.clone());
+            threads.push(thread::spawn(move || {
+                run_connection(stream, peer_addr, &*routes, &config, &shutdown, &registry);
+                active.fetch_sub(1, Ordering::SeqCst);
+            }));
+        }
+        for thread in threads {
+            let _ = thread.join();
+        }
+        Ok(())
+    }
+}
+
+/// Switch an accepted stream back to blocking and wrap it in TLS if configured
+fn prepare_stream(stream: NetStream, config: &ServerConfig) -> Result<NetStream> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(config.poll_interval))?;
+    #[cfg(feature = "rustls")]
+    let stream = match (stream, &config.tls) {
+        (NetStream::Tcp(stream), Some(tls)) => return crate::tls::accept(tls.clone(), stream),
+        (stream, _) => stream,
+    };
+    Ok(stream)
+}
+/// Read the request before answering so closing the socket doesn't reset the response
+fn reject(stream: NetStream, config: &ServerConfig, response: &[u8]) {
+    if let Ok(mut stream) = prepare_stream(stream, config) {
+        let deadline = config
+            .websocket
+            .handshake_timeout
+            .map(|timeout| Instant::now() + timeout);
+        if read_http_headers(&mut stream, deadline).is_ok() {
+            let _ = stream.write_all(response).and_then(|_| stream.flush());
+        }
+    }
+}
+fn is_timeout(error: &Error) -> bool {
+    matches!(error, Error::Io(error) if crate::websocket::is_timeout(error))
+}
+fn run_connection<R>(
+    stream: NetStream,
+    peer_addr: PeerAddr,
+    routes: &R,
+    config: &ServerConfig,
+    shutdown: &ShutdownHandle,
+    registry: &Registry,
+) where
+    R: Routes,
+{
+    #[cfg(feature = "tracing")]
+    let span = tracing::info_span!(
+        "connection",
+        peer = %peer_addr,
+        path = tracing::field::Empty,
+        id = tracing::field::Empty
+    );
+    #[cfg(feature = "tracing")]
+    let _entered = span.enter();
+    let mut stream = match prepare_stream(stream, config) {
+        Ok(stream) => stream,
+        Err(error) => return routes.on_error(&peer_addr, &error),
+    };
+    let (headers, rest) = match read_request(&mut stream, &config.websocket) {
+        Ok(request) => request,
+        Err(error) => {
+            let error = report_handshake_failure(&config.websocket, error);
+            return routes.on_error(&peer_addr, &error);
+        }
+    };
+    if let Some(response) = metrics_response(&headers, config) {
+        let _ = stream.write_all(&response).and_then(|_| stream.flush());
+        return;
+    }
+    let target = parse_request_line(&headers).map(|(_, target)| target);
+    let (handler, params) = match routes.resolve(target.unwrap_or("/")) {
+        Some(route) => route,
+        None => {
+            let error = KeyError::NotFound;
+            let _ = stream
+                .write_all(error.get_response())
+                .and_then(|_| stream.flush());
+            let error = report_handshake_failure(&config.websocket, error.into());
+            return routes.on_error(&peer_addr, &error);
+        }
+    };
+    let handler = &*handler;
+    let accepted = WebSocket::accept_headers(stream, config.websocket.clone(), &headers, rest);
+    let websocket = match accepted {
+        Ok(websocket) => websocket,
+        Err(error) => return handler.on_error(&peer_addr, &error),
+    };
+    let handshake = websocket.get_handshake().clone();
+    let info = ConnectionInfo {
+        id: registry.next_id(),
+        peer_addr,
+        path: handshake.path,
+        subprotocol: handshake.subprotocol,
+        connected_at: SystemTime::now(),
+    };
+    let queue = SendQueue::new(config.queue.clone());
+    #[cfg(feature = "tracing")]
+    span.record("path", info.path.as_str())
+        .record("id", info.id);
+    registry.insert(info.clone(), queue.clone());
+    let mut connection = Connection {
+        websocket,
+        info,
+        queue,
+        limiter: ConnectionLimiter::new(&config.rate_limit, Instant::now()),
+        params,
+    };
+    let metrics = config.websocket.metrics.clone();
+    if let Some(metrics) = &metrics {
+        metrics.connection_opened();
+    }
+    #[cfg(feature = "tracing")]
+    tracing::info!("connection opened");
+    handler.on_open(&mut connection);
+
+    // Once we close the connection, the peer has until then to answer
+    let mut close_deadline: Option<Instant> = None;
+    let mut rate_limited = false;
+    // What the metrics last heard of this connection's send queue
+    let mut queue_depth = 0;
+    loop {
+        let close_frame = match connection.queue.get_disconnect() {
+            _ if shutdown.is_shutdown() => {
+                Some(CloseFrame::new(close_code::GOING_AWAY, "Going Away"))
+            }
+            _ if rate_limited => Some(CloseFrame::new(
+                close_code::POLICY_VIOLATION,
+                "rate limit exceeded",
+            )),
+            Some(code) => Some(CloseFrame::new(code, "send queue full")),
+            None => None,
+        };
+        if let (Some(frame), None) = (close_frame, close_deadline) {
+            // Closing an already closing connection is fine; only the timer matters here
+            let _ = connection.close(frame);
+            connection.queue.close();
+            close_deadline = shutdown
+                .get_deadline()
+                .filter(|_| shutdown.is_shutdown())
+                .or_else(|| Some(Instant::now() + config.shutdown_timeout));
+        }
+        if let Some(metrics) = &metrics {
+            let depth = connection.queue.depth();
+            if depth != queue_depth {
+                metrics.queue_depth(queue_depth, depth);
+                queue_depth = depth;
+            }
+        }
+        if let Err(error) = write_queued(&mut connection) {
+            handler.on_error(connection.peer_addr(), &error);
+            break;
+        }
+        if matches!(close_deadline, Some(deadline) if Instant::now() >= deadline) {
+            break;
+        }
+        let result = connection.websocket.read_message();
+        for event in connection.websocket.take_keepalive_events() {
+            handler.on_keepalive(&mut connection, event);
+        }
+        match result {
+            Ok(Message::Close) => break,
+            Ok(message) => match check_rate(&mut connection, &message) {
+                Verdict::Allow => handler.on_message(&mut connection, message),
+                Verdict::Close => rate_limited = true,
+                _ => {}
+            },
+            Err(ref error) if is_timeout(error) => continue,
+            Err(Error::ConnectionClosed) => break,
+            Err(error) => {
+                handler.on_error(connection.peer_addr(), &error);
+                break;
+            }
+        }
+    }
+    connection.queue.close();
+    let close_frame = connection.websocket.get_close_frame().cloned();
+    let code = close_frame.as_ref().map(|frame| frame.code);
+    if let Some(metrics) = &metrics {
+        metrics.connection_closed(code.unwrap_or(close_code::ABNORMAL));
+        metrics.queue_depth(queue_depth, 0);
+    }
+    #[cfg(feature = "tracing")]
+    tracing::info!(
+        code = code.unwrap_or(close_code::ABNORMAL),
+        "connection closed"
+    );
+    handler.on_close(&mut connection, close_frame.as_ref());
+    registry.remove(connection.id());
+}
+/// The response to a scrape of [`ServerConfig::metrics_path`], `None` for any other request
+fn metrics_response(headers: &[u8], config: &ServerConfig) -> Option<Vec<u8>> {
+    let (path, metrics) = (
+        config.metrics_path.as_deref()?,
+        config.websocket.metrics.as_ref()?,
+    );
+    let (method, target) = parse_request_line(headers)?;
+    let target = target.split('?').next().unwrap_or(target);
+    let text = String::from_utf8_lossy(headers);
+    if method != "GET" || target != path || WsHeaders::from(&text).get_upgrade().is_some() {
+        return None;
+    }
+    let body = metrics.to_prometheus()?;
+    let mut response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+    response.extend_from_slice(body.as_bytes());
+    Some(response)
+}
+/// Apply the rate limits to every message but close, waiting out [`Verdict::Delay`] on this thread
+fn check_rate(connection: &mut Connection, message: &Message) -> Verdict {
+    let size = match message {
+        Message::Close => return Verdict::Allow,
+        message => message.as_ref().len(),
+    };
+    loop {
+        match connection.limiter.check(size, Instant::now()) {
+            Verdict::Delay(wait) => thread::sleep(wait),
+            verdict => return verdict,
+        }
+    }
+}
+fn write_queued(connection: &mut Connection) -> Result<()> {
+    while connection.websocket.can_write() {
+        match connection.queue.pop() {
+            Some(message) => connection.websocket.write_encoded(&message)?,
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::dataframe::Opcode;
+    use crate::message::WriteMessage;
+    use crate::metrics::AtomicMetrics;
+    use crate::rate_limit::{LimitPolicy, RateLimit};
+    use crate::websocket::tests::{client_frame, REQUEST};
+    use std::io::Read;
+    use std::net::TcpStream;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    pub(crate) struct Echo {
+        pub events: Mutex<Vec<String>>,
+    }
+    impl Handler for Echo {
+        fn on_open(&self, _connection: &mut Connection) {
+            self.events.lock().unwrap().push(String::from("open"));
+        }
+        fn on_message(&self, connection: &mut Connection, message: Message) {
+            if let Message::Text(_) | Message::Binary(_) = message {
+                connection.send(message).unwrap();
+            }
+        }
+        fn on_close(&self, _connection: &mut Connection, close_frame: Option<&CloseFrame>) {
+            let code = close_frame.map(|frame| frame.code).unwrap_or(0);
+            self.events.lock().unwrap().push(format!("close {}", code));
+        }
+        fn on_keepalive(&self, _connection: &mut Connection, event: KeepaliveEvent) {
+            let event = match event {
+                KeepaliveEvent::PingSent => "ping",
+                KeepaliveEvent::PongReceived(_) => "pong",
+                KeepaliveEvent::TimedOut => "timeout",
+            };
+            self.events.lock().unwrap().push(String::from(event));
+        }
+    }
+
+    /// Connect, do the handshake and return the stream for raw frames
+    pub(crate) fn connect_client(address: SocketAddr) -> TcpStream {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(REQUEST).unwrap();
+        let mut response = [0; 129];
+        stream.read_exact(&mut response).unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+        stream
+    }
+    /// Read one small unmasked frame from the server
+    pub(crate) fn read_server_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut header = [0; 2];
+        stream.read_exact(&mut header).unwrap();
+        let mut payload = vec![0; (header[1] & 127) as usize];
+        stream.read_exact(&mut payload).unwrap();
+        (header[0], payload)
+    }
+    pub(crate) fn spawn_server(
+        config: ServerConfig,
+    ) -> (SocketAddr, ShutdownHandle, Arc<Echo>, JoinHandle<()>) {
+        let server = Server::bind("127.0.0.1:0").unwrap().with_config(config);
+        let address = server.local_addr().unwrap();
+        let (shutdown, echo, thread) = serve_echo(server);
+        (address, shutdown, echo, thread)
+    }
+    pub(crate) fn serve_echo(server: Server) -> (ShutdownHandle, Arc<Echo>, JoinHandle<()>) {
+        struct Shared(Arc<Echo>);
+        impl Handler for Shared {
+            fn on_open(&self, connection: &mut Connection) {
+                self.0.on_open(connection)
+            }
+            fn on_message(&self, connection: &mut Connection, message: Message) {
+                self.0.on_message(connection, message)
+            }
+            fn on_close(&self, connection: &mut Connection, close_frame: Option<&CloseFrame>) {
+                self.0.on_close(connection, close_frame)
+            }
+            fn on_keepalive(&self, connection: &mut Connection, event: KeepaliveEvent) {
+                self.0.on_keepalive(connection, event)
+            }
+        }
+        let shutdown = server.shutdown_handle();
+        let echo = Arc::new(Echo::default());
+        let handler = Shared(echo.clone());
+        let thread = thread::spawn(move || server.serve(handler).unwrap());
+        (shutdown, echo, thread)
+    }
+
+    #[test]
+    fn should_echo_messages() {
+        let (address, shutdown, echo, thread) = spawn_server(ServerConfig::default());
+        let mut stream = connect_client(address);
+        stream
+            .write_all(&client_frame(Opcode::Text, b"Hello", true))
+            .unwrap();
+        assert_eq!(read_server_frame(&mut stream), (129, b"Hello".to_vec()));
+        stream
+            .write_all(&client_frame(Opcode::Close, &[3, 232], true))
+            .unwrap();
+        assert_eq!(read_server_frame(&mut stream), (136, vec![3, 232]));
+
+        shutdown.shutdown();
+        thread.join().unwrap();
+        assert_eq!(*echo.events.lock().unwrap(), vec!["open", "close 1000"]);
+    }
+    #[test]
+    fn should_reject_connections_above_the_cap() {
+        let config = ServerConfig {
+            max_connections: Some(1),
+            shutdown_timeout: Duration::from_millis(100),
+            ..ServerConfig::default()
+        };
+        let (address, shutdown, _echo, thread) = spawn_server(config);
+        let _first = connect_client(address);
+
+        let mut second = TcpStream::connect(address).unwrap();
+        second.write_all(REQUEST).unwrap();
+        let mut response = Vec::new();
+        second.read_to_end(&mut response).unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 503 Service Unavailable\r\n"));
+
+        shutdown.shutdown();
+        thread.join().unwrap();
+    }
+    #[test]
+    fn should_drop_refused_connections_while_answering_others() {
+        let config = ServerConfig {
+            max_connections: Some(0),
+            ..ServerConfig::default()
+        };
+        let (address, shutdown, _echo, thread) = spawn_server(config);
+        // Idle sockets that never send a request keep every reject thread waiting
+        let idle: Vec<TcpStream> = (0..MAX_REJECT_THREADS)
+            .map(|_| TcpStream::connect(address).unwrap())
+            .collect();
+
+        let mut dropped = TcpStream::connect(address).unwrap();
+        dropped.write_all(REQUEST).unwrap();
+        let mut response = Vec::new();
+        let _ = dropped.read_to_end(&mut response);
+        assert!(response.is_empty());
+
+        drop(idle);
+        shutdown.shutdown();
+        thread.join().unwrap();
+    }
+    #[test]
+    fn should_keep_serving_after_failed_accepts() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let server = Server::from_listener(Listener::Failing(listener, AtomicUsize::new(3)));
+        let address = server.local_addr().unwrap();
+        let (shutdown, echo, thread) = serve_echo(server);
+
+        let mut stream = connect_client(address);
+        stream
+            .write_all(&client_frame(Opcode::Text, b"Hello", true))
+            .unwrap();
+        assert_eq!(read_server_frame(&mut stream), (129, b"Hello".to_vec()));
+
+        // Shutting down still closes the connection and waits for its thread
+        shutdown.shutdown();
+        assert_eq!(read_server_frame(&mut stream).0, 136);
+        stream
+            .write_all(&client_frame(Opcode::Close, &[3, 233], true))
+            .unwrap();
+        thread.join().unwrap();
+        assert_eq!(*echo.events.lock().unwrap(), vec!["open", "close 1001"]);
+    }
+    #[test]
+    fn should_close_connections_on_shutdown() {
+        let (address, shutdown, echo, thread) = spawn_server(ServerConfig::default());
+        let mut stream = connect_client(address);
+
+        shutdown.shutdown();
+        let (opcode, payload) = read_server_frame(&mut stream);
+        assert_eq!(opcode, 136);
+        assert_eq!(payload[..2], [3, 233]);
+        stream
+            .write_all(&client_frame(Opcode::Close, &[3, 233], true))
+            .unwrap();
+
+        thread.join().unwrap();
+        assert_eq!(*echo.events.lock().unwrap(), vec!["open", "close 1001"]);
+    }
+    #[test]
+    fn should_ping_idle_clients_and_drop_silent_ones() {
+        let config = ServerConfig {
+            websocket: WebSocketConfig {
+                ping_interval: Some(Duration::from_millis(30)),
+                keepalive_timeout: Duration::from_millis(60),
+                ..WebSocketConfig::default()
+            },
+            poll_interval: Duration::from_millis(5),
+            ..ServerConfig::default()
+        };
+        let (address, shutdown, echo, thread) = spawn_server(config);
+        let mut stream = connect_client(address);
+
+        assert_eq!(read_server_frame(&mut stream), (137, vec![]));
+        stream
+            .write_all(&client_frame(Opcode::Pong, &[], true))
+            .unwrap();
+        assert_eq!(read_server_frame(&mut stream), (137, vec![]));
+        let (opcode, payload) = read_server_frame(&mut stream);
+        assert_eq!(opcode, 136);
+        assert_eq!(payload[..2], [3, 233]);
+
+        shutdown.shutdown();
+        thread.join().unwrap();
+        assert_eq!(
+            *echo.events.lock().unwrap(),
+            vec!["open", "ping", "pong", "ping", "timeout", "close 0"]
+        );
+    }
+    #[test]
+    fn should_write_queued_frames_and_disconnect_when_full() {
+        #[derive(Default)]
+        struct Queues(Mutex<Vec<SendQueue>>);
+        impl Handler for Arc<Queues> {
+            fn on_open(&self, connection: &mut Connection) {
+                self.0.lock().unwrap().push(connection.get_queue().clone());
+            }
+            fn on_message(&self, _connection: &mut Connection, _message: Message) {}
+        }
+        let config = ServerConfig {
+            queue: QueueConfig {
+                high_watermark: 8,
+                low_watermark: 0,
+                ..QueueConfig::default()
+            },
+            poll_interval: Duration::from_millis(5),
+            shutdown_timeout: Duration::from_millis(100),
+            ..ServerConfig::default()
+        };
+        let server = Server::bind("127.0.0.1:0").unwrap().with_config(config);
+        let address = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let queues = Arc::new(Queues::default());
+        let handler = queues.clone();
+        let thread = thread::spawn(move || server.serve(handler).unwrap());
+
+        let mut stream = connect_client(address);
+        while queues.0.lock().unwrap().is_empty() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let queue = queues.0.lock().unwrap()[0].clone();
+        queue.send(WriteMessage::new("Hello")).unwrap();
+        assert_eq!(read_server_frame(&mut stream), (129, b"Hello".to_vec()));
+
+        // Every frame fills the queue, so sending fails as soon as two land between drains
+        while queue.send(WriteMessage::new("123456")).is_ok() {}
+        let (opcode, payload) = std::iter::repeat_with(|| read_server_frame(&mut stream))
+            .find(|(opcode, _)| *opcode == 136)
+            .unwrap();
+        assert_eq!(opcode, 136);
+        assert_eq!(payload[..2], [3, 245]);
+
+        shutdown.shutdown();
+        thread.join().unwrap();
+    }
+    #[test]
+    fn should_rate_limit_connections_and_messages() {
+        let config = ServerConfig {
+            rate_limit: RateLimitConfig {
+                messages: Some(RateLimit::new(1, 2)),
+                policy: LimitPolicy::Close,
+                connections_per_ip: Some(RateLimit::new(1, 1)),
+                ..RateLimitConfig::default()
+            },
+            ..ServerConfig::default()
+        };
+        let (address, shutdown, echo, thread) = spawn_server(config);
+        let mut stream = connect_client(address);
+
+        let mut second = TcpStream::connect(address).unwrap();
+        second.write_all(REQUEST).unwrap();
+        let mut response = Vec::new();
+        second.read_to_end(&mut response).unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 429 Too Many Requests\r\n"));
+
+        for _ in 0..3 {
+            stream
+                .write_all(&client_frame(Opcode::Text, b"Hello", true))
+                .unwrap();
+        }
+        assert_eq!(read_server_frame(&mut stream), (129, b"Hello".to_vec()));
+        assert_eq!(read_server_frame(&mut stream), (129, b"Hello".to_vec()));
+        let (opcode, payload) = read_server_frame(&mut stream);
+        assert_eq!(opcode, 136);
+        assert_eq!(payload[..2], [3, 240]);
+        stream
+            .write_all(&client_frame(Opcode::Close, &[3, 240], true))
+            .unwrap();
+
+        shutdown.shutdown();
+        thread.join().unwrap();
+        assert_eq!(*echo.events.lock().unwrap(), vec!["open", "close 1008"]);
+    }
+    #[test]
+    fn should_rate_limit_pings() {
+        let config = ServerConfig {
+            rate_limit: RateLimitConfig {
+                messages: Some(RateLimit::new(1, 2)),
+                policy: LimitPolicy::Close,
+                ..RateLimitConfig::default()
+            },
+            ..ServerConfig::default()
+        };
+        let (address, shutdown, echo, thread) = spawn_server(config);
+        let mut stream = connect_client(address);
+        for _ in 0..3 {
+            stream
+                .write_all(&client_frame(Opcode::Ping, b"", true))
+                .unwrap();
+        }
+        let (opcode, payload) = std::iter::repeat_with(|| read_server_frame(&mut stream))
+            .find(|(opcode, _)| *opcode == 136)
+            .unwrap();
+        assert_eq!(opcode, 136);
+        assert_eq!(payload[..2], [3, 240]);
+        stream
+            .write_all(&client_frame(Opcode::Close, &[3, 240], true))
+            .unwrap();
+
+        shutdown.shutdown();
+        thread.join().unwrap();
+        assert_eq!(*echo.events.lock().unwrap(), vec!["open", "close 1008"]);
+    }
+    #[test]
+    fn should_serve_metrics_next_to_websockets() {
+        let config = ServerConfig {
+            websocket: WebSocketConfig {
+                metrics: Some(Arc::new(AtomicMetrics::new())),
+                ..WebSocketConfig::default()
+            },
+            metrics_path: Some(String::from("/stats")),
+            ..ServerConfig::default()
+        };
+        let (address, shutdown, _echo, thread) = spawn_server(config);
+        let websocket = connect_client(address);
+        let get = |request: &[u8]| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.write_all(request).unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).unwrap();
+            String::from_utf8(response).unwrap()
+        };
+
+        let response = get(b"GET /stats?format=text HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(response.contains("\nwebsocket_handshakes_total{result=\"success\"} 1\n"));
+        let response = get(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        drop(websocket);
+        shutdown.shutdown();
+        thread.join().unwrap();
+    }
+    #[test]
+    fn should_not_serve_metrics_by_default() {
+        let config = ServerConfig {
+            websocket: WebSocketConfig {
+                metrics: Some(Arc::new(AtomicMetrics::new())),
+                ..WebSocketConfig::default()
+            },
+            ..ServerConfig::default()
+        };
+        let (address, shutdown, _echo, thread) = spawn_server(config);
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 400 Bad Request\r\n"));
+
+        shutdown.shutdown();
+        thread.join().unwrap();
+    }
+}
@@ -0,0 +1,795 @@
+use crate::dataframe::{DataFrame, Opcode};
+use crate::error::{Error, Result, Timeout};
+use crate::keepalive::{Keepalive, KeepaliveAction};
+use crate::message::{close_code, CloseFrame, Message, WriteMessage};
+use crate::metrics::{sent_frame, Direction};
+#[cfg(feature = "tracing")]
+use crate::protocol::trace_message;
+use crate::protocol::{
+    close_code_for, peek_frame_header, take_frame, Deadlines, FrameHeader, Incoming,
+    MessageAssembler, Role, StreamedMessage,
+};
+use crate::websocket::{
+    accept_request, report_handshake, split_http_headers, State, WebSocketConfig, READ_CHUNK_SIZE,
+    REQUEST_TIMEOUT_RESPONSE,
+};
+use futures::future::poll_fn;
+use futures::future::{select, Either};
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use futures::stream::{SplitSink, SplitStream};
+use futures::{ready, Future, Sink, Stream, StreamExt};
+use futures_timer::Delay;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+async fn read_http_headers<S>(stream: &mut S) -> Result<(Vec<u8>, Vec<u8>)>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buffer: Vec<u8> = Vec::with_capacity(1024);
+    let mut chunk = [0; 1024];
+    loop {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        let search_from = buffer.len();
+        buffer.extend_from_slice(&chunk[..read]);
+        if let Some(rest) = split_http_headers(&mut buffer, search_from)? {
+            return Ok((buffer, rest));
+        }
+    }
+}
+/// Answer the handshake request on `stream`, returning what was read past it
+async fn respond<S>(stream: &mut S, config: &WebSocketConfig) -> Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let headers = match config.handshake_timeout {
+        Some(timeout) => {
+            let read = Box::pin(read_http_headers(&mut *stream));
+            match select(read, Delay::new(timeout)).await {
+                Either::Left((result, _)) => result,
+                Either::Right(_) => Err(Timeout::Handshake.into()),
+            }
+        }
+        None => read_http_headers(&mut *stream).await,
+    };
+    let (headers, rest) = match headers {
+        Err(Error::Timeout(Timeout::Handshake)) => {
+            #[cfg(feature = "tracing")]
+            tracing::info!(timeout = %Timeout::Handshake, "timed out");
+            let _ = stream.write_all(REQUEST_TIMEOUT_RESPONSE).await;
+            let _ = stream.flush().await;
+            return Err(Timeout::Handshake.into());
+        }
+        result => result?,
+    };
+    match accept_request(&headers, config) {
+        Ok((response, _)) => {
+            stream.write_all(&response).await?;
+            stream.flush().await?;
+            Ok(rest)
+        }
+        Err(error) => {
+            let _ = stream.write_all(error.get_response()).await;
+            let _ = stream.flush().await;
+            Err(error.into())
+        }
+    }
+}
+
+/// A websocket connection over any `futures` byte stream, usable from any runtime.
+///
+/// Pings are answered and the closing handshake is completed while reading; when
+/// the connection is closed the stream ends. Frames queued by the reading side are
+/// written the next time either side is polled. Fragment and idle deadlines are
+/// checked and keepalive pings sent while a read is pending.
+pub struct WebSocketStream<S> {
+    stream: S,
+    config: WebSocketConfig,
+    read_buffer: Vec<u8>,
+    write_buffer: Vec<u8>,
+    assembler: MessageAssembler,
+    state: State,
+    close_frame: Option<CloseFrame>,
+    deadlines: Deadlines,
+    keepalive: Keepalive,
+    /// Wakes the reading side when the next deadline passes
+    timer: Option<(Instant, Delay)>,
+    /// The message a [`MessageReader`] is streaming, until all of it has been read
+    streamed: Option<StreamedMessage>,
+}
+impl<S> WebSocketStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Perform the server side of the opening handshake on `stream`
+    pub async fn accept(stream: S) -> Result<Self> {
+        Self::accept_with_config(stream, WebSocketConfig::default()).await
+    }
+    /// Like [`WebSocketStream::accept`]; a rejected handshake is answered with an HTTP error response.
+    ///
+    /// Timeouts are kept by `futures-timer` on a thread of its own, so they work on any runtime.
+    pub async fn accept_with_config(mut stream: S, config: WebSocketConfig) -> Result<Self> {
+        let rest = respond(&mut stream, &config).await;
+        report_handshake(&config.metrics, &rest);
+        let mut websocket = WebSocketStream::from_raw_stream(stream, config);
+        websocket.read_buffer = rest?;
+        Ok(websocket)
+    }
+    /// Wrap a stream where the opening handshake has already been done
+    pub fn from_raw_stream(stream: S, config: WebSocketConfig) -> Self {
+        WebSocketStream {
+            stream,
+            assembler: MessageAssembler::new(&config, Role::Server),
+            deadlines: Deadlines::new(config.fragment_timeout, config.idle_timeout),
+            keepalive: Keepalive::new(config.ping_interval, config.keepalive_timeout),
+            timer: None,
+            config,
+            read_buffer: Vec::new(),
+            write_buffer: Vec::new(),
+            state: State::Open,
+            close_frame: None,
+            streamed: None,
+        }
+    }
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+    pub fn get_config(&self) -> &WebSocketConfig {
+        &self.config
+    }
+    /// The close frame sent by the peer, once one has been received
+    pub fn get_close_frame(&self) -> Option<&CloseFrame> {
+        self.close_frame.as_ref()
+    }
+    /// Read the next text or binary message as a stream, like [`crate::websocket::WebSocket::next_message_reader`]
+    pub async fn next_message_reader(&mut self) -> Result<MessageReader<'_, S>> {
+        let opcode = poll_fn(|cx| self.poll_next_reader(cx)).await?;
+        Ok(MessageReader {
+            websocket: self,
+            opcode,
+        })
+    }
+    /// Split into a writing and a reading half that can be moved to different tasks
+    pub fn split(self) -> (SplitSink<Self, Message>, SplitStream<Self>) {
+        StreamExt::split(self)
+    }
+    fn queue(&mut self, message: &WriteMessage) {
+        if let Some(metrics) = &self.config.metrics {
+            sent_frame(&**metrics, message.as_ref());
+        }
+        self.write_buffer.extend_from_slice(message.as_ref());
+    }
+    fn queue_close(&mut self, frame: Option<&CloseFrame>) {
+        if self.state == State::Open {
+            #[cfg(feature = "tracing")]
+            tracing::debug!(code = frame.map(|frame| frame.code), "sending close frame");
+            self.queue(&WriteMessage::close(frame));
+            self.state = State::CloseSent;
+        }
+    }
+    fn fail(&mut self, error: Error) -> Error {
+        if let Some(code) = close_code_for(&error) {
+            self.queue_close(Some(&CloseFrame::new(code, "")));
+            self.state = State::Closed;
+        }
+        error
+    }
+    fn poll_write_buffer(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buffer.is_empty() {
+            let written = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.write_buffer))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buffer.drain(..written);
+        }
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+    fn poll_read_buffer(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<usize>> {
+        let start = self.read_buffer.len();
+        self.read_buffer.resize(start + READ_CHUNK_SIZE, 0);
+        let result = Pin::new(&mut self.stream).poll_read(cx, &mut self.read_buffer[start..]);
+        let read = match &result {
+            Poll::Ready(Ok(read)) => *read,
+            _ => 0,
+        };
+        self.read_buffer.truncate(start + read);
+        result
+    }
+    /// Read more into the read buffer.
+    ///
+    /// Fails with [`Error::ConnectionClosed`] if the peer hung up after seeing our close frame.
+    fn poll_fill_read_buffer(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        match self.poll_read_buffer(cx) {
+            Poll::Pending => {
+                let error = match self.poll_deadlines(cx) {
+                    Poll::Ready(Err(error)) => self.fail(error),
+                    _ => return Poll::Pending,
+                };
+                // Nobody may poll again after a timeout, so try to get the close out now
+                let _ = self.poll_write_buffer(cx);
+                Poll::Ready(Err(error))
+            }
+            Poll::Ready(Ok(0)) => {
+                let state = std::mem::replace(&mut self.state, State::Closed);
+                Poll::Ready(Err(match state {
+                    State::CloseSent => Error::ConnectionClosed,
+                    _ => io::Error::from(io::ErrorKind::UnexpectedEof).into(),
+                }))
+            }
+            Poll::Ready(Ok(_)) => Poll::Ready(Ok(())),
+            Poll::Ready(Err(error)) => Poll::Ready(Err(error.into())),
+        }
+    }
+    /// Ready with an error once a deadline passes, pending until then; pings idle peers on the way
+    fn poll_deadlines(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        loop {
+            let deadline = match (
+                self.deadlines.next_deadline(),
+                self.keepalive.next_deadline(),
+            ) {
+                (Some(deadline), Some(keepalive)) => deadline.min(keepalive),
+                (Some(deadline), None) | (None, Some(deadline)) => deadline,
+                (None, None) => {
+                    self.timer = None;
+                    return Poll::Pending;
+                }
+            };
+            let now = Instant::now();
+            if now >= deadline {
+                self.timer = None;
+                self.deadlines.check(now)?;
+                self.check_keepalive(now)?;
+                let _ = self.poll_write_buffer(cx);
+                continue;
+            }
+            match &mut self.timer {
+                Some((armed, delay)) if *armed == deadline => {
+                    ready!(Pin::new(delay).poll(cx));
+                }
+                timer => *timer = Some((deadline, Delay::new(deadline - now))),
+            }
+        }
+    }
+    /// Ping an idle peer, or give up on it if it didn't answer the last ping
+    fn check_keepalive(&mut self, now: Instant) -> Result<()> {
+        match self.keepalive.poll(now) {
+            KeepaliveAction::Wait => Ok(()),
+            KeepaliveAction::Ping => {
+                if self.state == State::Open {
+                    self.queue(&WriteMessage::with_opcode(Opcode::Ping, []));
+                }
+                Ok(())
+            }
+            KeepaliveAction::TimedOut => Err(Timeout::Keepalive.into()),
+        }
+    }
+    /// Account for a frame or frame header from the peer
+    fn on_frame<D>(&mut self, frame: &DataFrame<D>) -> Result<()>
+    where
+        D: AsRef<[u8]>,
+    {
+        let now = Instant::now();
+        self.deadlines.on_frame(frame, now)?;
+        self.keepalive.on_traffic(now);
+        if frame.get_opcode() == Opcode::Pong as u8 {
+            self.keepalive.on_pong(now);
+        }
+        Ok(())
+    }
+    fn handle_incoming(&mut self, incoming: Incoming) -> Message {
+        match incoming {
+            Incoming::Message(Message::Ping(payload)) => {
+                if self.state == State::Open {
+                    self.queue(&WriteMessage::with_opcode(Opcode::Pong, &payload));
+                }
+                Message::Ping(payload)
+            }
+            Incoming::Message(message) => message,
+            Incoming::Close(close_frame) => {
+                let reply = close_frame
+                    .as_ref()
+                    .map(|frame| CloseFrame::new(frame.code, ""));
+                self.queue_close(reply.as_ref());
+                self.state = State::Closed;
+                self.close_frame = close_frame;
+                Message::Close
+            }
+        }
+    }
+    fn poll_next_message(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<Message>>> {
+        // Pending pongs and close replies go out before reading any further
+        if let Poll::Ready(Err(error)) = self.poll_write_buffer(cx) {
+            return Poll::Ready(Some(Err(error.into())));
+        }
+        if let Err(error) = ready!(self.poll_skip_streamed(cx)) {
+            return Poll::Ready(Some(Err(self.fail(error))));
+        }
+        loop {
+            if self.state == State::Closed {
+                return match ready!(self.poll_write_buffer(cx)) {
+                    Ok(()) => Poll::Ready(None),
+                    Err(error) => Poll::Ready(Some(Err(error.into()))),
+                };
+            }
+            let pool = self.config.buffer_pool.as_ref();
+            let frame = match take_frame(&mut self.read_buffer, self.config.max_frame_size, pool) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => match ready!(self.poll_fill_read_buffer(cx)) {
+                    Ok(()) => continue,
+                    Err(Error::ConnectionClosed) => return Poll::Ready(None),
+                    Err(error) => return Poll::Ready(Some(Err(error))),
+                },
+                Err(error) => return Poll::Ready(Some(Err(self.fail(error)))),
+            };
+            if let Err(error) = self.on_frame(&frame) {
+                return Poll::Ready(Some(Err(self.fail(error))));
+            }
+            match self.assembler.push(frame) {
+                Ok(Some(incoming)) => {
+                    let message = self.handle_incoming(incoming);
+                    // Start writing any reply right away; it is finished on later polls
+                    let _ = self.poll_write_buffer(cx);
+                    return Poll::Ready(Some(Ok(message)));
+                }
+                Ok(None) => continue,
+                Err(error) => return Poll::Ready(Some(Err(self.fail(error)))),
+            }
+        }
+    }
+    /// Start streaming the next text or binary message, returning its opcode
+    fn poll_next_reader(&mut self, cx: &mut Context<'_>) -> Poll<Result<Opcode>> {
+        if let Poll::Ready(Err(error)) = self.poll_write_buffer(cx) {
+            return Poll::Ready(Err(error.into()));
+        }
+        let message = match ready!(self.poll_skip_streamed(cx)) {
+            Ok(()) => ready!(self.poll_data_header(cx)),
+            Err(error) => Err(error),
+        }
+        .and_then(|(header, length)| StreamedMessage::new(&header, length, Role::Server));
+        match message {
+            Ok(message) => {
+                let opcode = message.get_opcode();
+                self.streamed = Some(message);
+                Poll::Ready(Ok(opcode))
+            }
+            Err(error) => Poll::Ready(Err(self.fail(error))),
+        }
+    }
+    /// Read up to the payload of the next data frame, handling control frames on the way
+    fn poll_data_header(&mut self, cx: &mut Context<'_>) -> Poll<Result<(FrameHeader, usize)>> {
+        loop {
+            if self.state == State::Closed {
+                return Poll::Ready(Err(Error::ConnectionClosed));
+            }
+            match peek_frame_header(&self.read_buffer) {
+                Some((header, _, _)) if Opcode::from(header.get_opcode()).is_control() => {
+                    if let Some(frame) = take_frame(
+                        &mut self.read_buffer,
+                        self.config.max_frame_size,
+                        self.config.buffer_pool.as_ref(),
+                    )? {
+                        self.on_frame(&frame)?;
+                        if let Some(incoming) = self.assembler.push(frame)? {
+                            self.handle_incoming(incoming);
+                            let _ = self.poll_write_buffer(cx);
+                        }
+                        continue;
+                    }
+                }
+                Some((header, header_length, payload_length)) => {
+                    self.read_buffer.drain(..header_length);
+                    if let Some(metrics) = &self.config.metrics {
+                        let opcode = Opcode::from(header.get_opcode());
+                        let bytes = header_length.saturating_add(payload_length);
+                        metrics.frame(Direction::In, opcode, bytes);
+                    }
+                    self.on_frame(&header)?;
+                    return Poll::Ready(Ok((header, payload_length)));
+                }
+                None => {}
+            }
+            ready!(self.poll_fill_read_buffer(cx))?;
+        }
+    }
+    /// Read the next part of the message a [`MessageReader`] streams, `0` once all of it has been read
+    fn poll_read_payload(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize>> {
+        loop {
+            let message = match &mut self.streamed {
+                Some(message) => message,
+                None => return Poll::Ready(Ok(0)),
+            };
+            if message.is_finished() {
+                message.finish()?;
+                if let Some(metrics) = &self.config.metrics {
+                    metrics.message(Direction::In, message.get_size());
+                }
+                self.streamed = None;
+                return Poll::Ready(Ok(0));
+            }
+            let wanted = buf.len().min(message.get_remaining());
+            if message.get_remaining() == 0 {
+                let (header, length) = ready!(self.poll_data_header(cx))?;
+                if let Some(message) = &mut self.streamed {
+                    message.next_frame(&header, length, Role::Server)?;
+                }
+                continue;
+            }
+            if wanted == 0 {
+                return Poll::Ready(Ok(0));
+            }
+            // Whatever is buffered goes first, the rest is read straight into `buf`
+            let read = match self.read_buffer.is_empty() {
+                false => {
+                    let read = wanted.min(self.read_buffer.len());
+                    buf[..read].copy_from_slice(&self.read_buffer[..read]);
+                    self.read_buffer.drain(..read);
+                    read
+                }
+                true => match Pin::new(&mut self.stream).poll_read(cx, &mut buf[..wanted]) {
+                    Poll::Pending => {
+                        ready!(self.poll_deadlines(cx))?;
+                        return Poll::Pending;
+                    }
+                    Poll::Ready(result) => match result? {
+                        0 => {
+                            self.state = State::Closed;
+                            let error = io::Error::from(io::ErrorKind::UnexpectedEof);
+                            return Poll::Ready(Err(error.into()));
+                        }
+                        read => read,
+                    },
+                },
+            };
+            self.keepalive.on_traffic(Instant::now());
+            if let Some(message) = &mut self.streamed {
+                message.unmask(&mut buf[..read])?;
+            }
+            return Poll::Ready(Ok(read));
+        }
+    }
+    /// Read past what is left of a message a [`MessageReader`] didn't finish
+    fn poll_skip_streamed(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        if self.state == State::Closed {
+            self.streamed = None;
+        }
+        let mut scratch = [0; READ_CHUNK_SIZE];
+        while self.streamed.is_some() {
+            ready!(self.poll_read_payload(cx, &mut scratch))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The payload of one text or binary message as it arrives, see [`WebSocketStream::next_message_reader`]
+pub struct MessageReader<'a, S> {
+    websocket: &'a mut WebSocketStream<S>,
+    opcode: Opcode,
+}
+impl<'a, S> MessageReader<'a, S> {
+    /// [`Opcode::Text`] or [`Opcode::Binary`]
+    pub fn get_opcode(&self) -> Opcode {
+        self.opcode
+    }
+}
+impl<'a, S> AsyncRead for MessageReader<'a, S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match ready!(this.websocket.poll_read_payload(cx, buf)) {
+            Ok(read) => Poll::Ready(Ok(read)),
+            Err(error) => {
+                let error = this.websocket.fail(error);
+                let _ = this.websocket.poll_write_buffer(cx);
+                Poll::Ready(Err(error.into()))
+            }
+        }
+    }
+}
+
+impl<S> Stream for WebSocketStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Item = Result<Message>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_next_message(cx)
+    }
+}
+impl<S> Sink<Message> for WebSocketStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        if this.state != State::Open {
+            return Poll::Ready(Err(Error::ConnectionClosed));
+        }
+        this.poll_write_buffer(cx).map_err(Error::from)
+    }
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<()> {
+        let this = self.get_mut();
+        if this.state != State::Open {
+            return Err(Error::ConnectionClosed);
+        }
+        match item {
+            Message::Close => {
+                this.queue_close(Some(&CloseFrame::new(close_code::NORMAL, "")));
+            }
+            message => {
+                #[cfg(feature = "tracing")]
+                trace_message(this.config.trace_payloads, Direction::Out, &message);
+                this.queue(&WriteMessage::from(message))
+            }
+        }
+        Ok(())
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_write_buffer(cx).map_err(Error::from)
+    }
+    /// Send a close frame unless one was already sent; the reading side sees the peer's reply
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        this.queue_close(Some(&CloseFrame::new(close_code::NORMAL, "")));
+        ready!(this.poll_write_buffer(cx))?;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::AtomicMetrics;
+    use crate::websocket::tests::{client_frame, MockStream, REQUEST};
+    use futures::executor::block_on;
+    use futures::SinkExt;
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    impl AsyncRead for MockStream {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            match Read::read(self.get_mut(), buf) {
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => Poll::Pending,
+                result => Poll::Ready(result),
+            }
+        }
+    }
+    impl AsyncWrite for MockStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Write::write(self.get_mut(), buf))
+        }
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn accepted(frames: &[Vec<u8>]) -> WebSocketStream<MockStream> {
+        let mut input = REQUEST.to_vec();
+        frames
+            .iter()
+            .for_each(|frame| input.extend_from_slice(frame));
+        block_on(WebSocketStream::accept(MockStream::new(input))).unwrap()
+    }
+    fn written_after_handshake(websocket: &WebSocketStream<MockStream>) -> &[u8] {
+        &websocket.get_ref().output[129..]
+    }
+
+    #[test]
+    fn should_answer_pings_while_streaming() {
+        let mut websocket = accepted(&[
+            client_frame(Opcode::Ping, b"ping", true),
+            client_frame(Opcode::Text, b"Hello", true),
+        ]);
+        block_on(async {
+            assert!(matches!(websocket.next().await, Some(Ok(Message::Ping(_)))));
+            assert!(
+                matches!(websocket.next().await, Some(Ok(Message::Text(text))) if text == "Hello")
+            );
+        });
+        assert_eq!(written_after_handshake(&websocket), b"\x8a\x04ping");
+    }
+    #[test]
+    fn should_stream_message_payloads() {
+        let mut websocket = accepted(&[
+            client_frame(Opcode::Binary, &[1; 3000], false),
+            client_frame(Opcode::Ping, b"ping", true),
+            client_frame(Opcode::Continuation, &[2; 3000], true),
+            client_frame(Opcode::Text, b"Hello", true),
+        ]);
+        block_on(async {
+            let mut reader = websocket.next_message_reader().await.unwrap();
+            assert_eq!(reader.get_opcode(), Opcode::Binary);
+            let mut streamed = Vec::new();
+            reader.read_to_end(&mut streamed).await.unwrap();
+            assert_eq!(streamed, [[1; 3000], [2; 3000]].concat());
+            assert!(
+                matches!(websocket.next().await, Some(Ok(Message::Text(text))) if text == "Hello")
+            );
+        });
+        assert_eq!(written_after_handshake(&websocket), b"\x8a\x04ping");
+    }
+    #[test]
+    fn should_report_handshakes_frames_and_messages() {
+        let metrics = Arc::new(AtomicMetrics::new());
+        let config = WebSocketConfig {
+            metrics: Some(metrics.clone()),
+            ..WebSocketConfig::default()
+        };
+        let mut input = REQUEST.to_vec();
+        input.extend_from_slice(&client_frame(Opcode::Text, b"Hello", true));
+        input.extend_from_slice(&client_frame(Opcode::Binary, &[1; 3], false));
+        input.extend_from_slice(&client_frame(Opcode::Continuation, &[2; 3], true));
+        let stream = MockStream::new(input);
+        let mut websocket = block_on(WebSocketStream::accept_with_config(stream, config)).unwrap();
+        block_on(async {
+            assert!(matches!(websocket.next().await, Some(Ok(Message::Text(_)))));
+            let mut reader = websocket.next_message_reader().await.unwrap();
+            reader.read_to_end(&mut Vec::new()).await.unwrap();
+            let reply = Message::Text(String::from("World"));
+            websocket.send(reply).await.unwrap();
+        });
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.handshakes_succeeded, 1);
+        assert_eq!(snapshot.frames_in["text"].frames, 1);
+        assert_eq!(snapshot.frames_in["binary"].frames, 1);
+        assert_eq!(snapshot.frames_in["continuation"].frames, 1);
+        assert_eq!(snapshot.message_sizes_in.count, 2);
+        assert_eq!(snapshot.message_sizes_in.sum, 11);
+        assert_eq!(snapshot.frames_out["text"].frames, 1);
+        assert_eq!(snapshot.message_sizes_out.sum, 5);
+    }
+    #[test]
+    fn should_complete_close_handshake_and_end() {
+        let mut websocket = accepted(&[client_frame(Opcode::Close, &[3, 232], true)]);
+        block_on(async {
+            assert!(matches!(websocket.next().await, Some(Ok(Message::Close))));
+            assert!(websocket.next().await.is_none());
+            assert!(matches!(
+                websocket.send(Message::Text(String::from("late"))).await,
+                Err(Error::ConnectionClosed)
+            ));
+        });
+        assert_eq!(written_after_handshake(&websocket), [136, 2, 3, 232]);
+    }
+    #[test]
+    fn should_send_and_close_through_split_halves() {
+        let websocket = accepted(&[
+            client_frame(Opcode::Text, b"Hello", true),
+            client_frame(Opcode::Close, &[3, 232], true),
+        ]);
+        let (mut sink, mut stream) = websocket.split();
+        block_on(async {
+            sink.send(Message::Binary(vec![1, 2])).await.unwrap();
+            assert!(
+                matches!(stream.next().await, Some(Ok(Message::Text(text))) if text == "Hello")
+            );
+            sink.close().await.unwrap();
+            assert!(matches!(stream.next().await, Some(Ok(Message::Close))));
+            assert!(stream.next().await.is_none());
+        });
+        let websocket = sink.reunite(stream).unwrap();
+        assert_eq!(
+            written_after_handshake(&websocket),
+            [130, 2, 1, 2, 136, 2, 3, 232]
+        );
+    }
+    #[test]
+    fn should_time_out_slow_handshakes() {
+        let config = WebSocketConfig {
+            handshake_timeout: Some(Duration::from_millis(5)),
+            ..WebSocketConfig::default()
+        };
+        let mut stream = MockStream::new(REQUEST[..20].to_vec());
+        stream.stall = true;
+        let result = block_on(WebSocketStream::accept_with_config(&mut stream, config));
+        assert!(matches!(result, Err(Error::Timeout(Timeout::Handshake))));
+        assert!(stream
+            .output
+            .starts_with(b"HTTP/1.1 408 Request Timeout\r\n"));
+    }
+    #[test]
+    fn should_time_out_unfinished_fragments() {
+        let mut input = REQUEST.to_vec();
+        input.extend(client_frame(Opcode::Text, b"Hel", false));
+        let mut stream = MockStream::new(input);
+        stream.stall = true;
+        let config = WebSocketConfig {
+            fragment_timeout: Some(Duration::from_millis(5)),
+            ..WebSocketConfig::default()
+        };
+        let mut websocket = block_on(WebSocketStream::accept_with_config(stream, config)).unwrap();
+        block_on(async {
+            assert!(matches!(
+                websocket.next().await,
+                Some(Err(Error::Timeout(Timeout::Fragment)))
+            ));
+            assert!(websocket.next().await.is_none());
+        });
+        assert_eq!(written_after_handshake(&websocket), [136, 2, 3, 240]);
+    }
+    #[test]
+    fn should_time_out_unfinished_streamed_fragments() {
+        let mut input = REQUEST.to_vec();
+        let frame = client_frame(Opcode::Binary, b"Hello", false);
+        input.extend_from_slice(&frame[..frame.len() - 2]);
+        let mut stream = MockStream::new(input);
+        stream.stall = true;
+        let config = WebSocketConfig {
+            fragment_timeout: Some(Duration::from_millis(5)),
+            ..WebSocketConfig::default()
+        };
+        let mut websocket = block_on(WebSocketStream::accept_with_config(stream, config)).unwrap();
+        block_on(async {
+            let mut reader = websocket.next_message_reader().await.unwrap();
+            let mut streamed = Vec::new();
+            let error = reader.read_to_end(&mut streamed).await.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+            assert_eq!(streamed, b"Hel");
+        });
+        assert_eq!(written_after_handshake(&websocket), [136, 2, 3, 240]);
+    }
+    #[test]
+    fn should_ping_idle_peers_and_drop_silent_ones() {
+        let mut stream = MockStream::new(REQUEST.to_vec());
+        stream.stall = true;
+        let config = WebSocketConfig {
+            ping_interval: Some(Duration::from_millis(5)),
+            keepalive_timeout: Duration::from_millis(5),
+            ..WebSocketConfig::default()
+        };
+        let mut websocket = block_on(WebSocketStream::accept_with_config(stream, config)).unwrap();
+        block_on(async {
+            assert!(matches!(
+                websocket.next().await,
+                Some(Err(Error::Timeout(Timeout::Keepalive)))
+            ));
+        });
+        assert_eq!(
+            written_after_handshake(&websocket),
+            [137, 0, 136, 2, 3, 233]
+        );
+    }
+    #[test]
+    fn should_close_idle_connections() {
+        let mut stream = MockStream::new(REQUEST.to_vec());
+        stream.stall = true;
+        let config = WebSocketConfig {
+            idle_timeout: Some(Duration::from_millis(5)),
+            ..WebSocketConfig::default()
+        };
+        let mut websocket = block_on(WebSocketStream::accept_with_config(stream, config)).unwrap();
+        block_on(async {
+            assert!(matches!(
+                websocket.next().await,
+                Some(Err(Error::Timeout(Timeout::Idle)))
+            ));
+        });
+        assert_eq!(written_after_handshake(&websocket), [136, 2, 3, 233]);
+    }
+}
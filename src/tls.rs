@@ -0,0 +1,139 @@
+use crate::error::Result;
+use crate::net::NetStream;
+use rustls::crypto::ring::default_provider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection};
+use std::convert::TryFrom;
+use std::io;
+use std::net::TcpStream;
+use std::sync::Arc;
+
+pub use rustls;
+
+/// Parse every certificate in a PEM file, in the order they appear
+pub fn certs_from_pem(pem: &[u8]) -> io::Result<Vec<CertificateDer<'static>>> {
+    rustls_pemfile::certs(&mut io::BufReader::new(pem)).collect()
+}
+/// Parse the first private key in a PEM file
+pub fn private_key_from_pem(pem: &[u8]) -> io::Result<PrivateKeyDer<'static>> {
+    rustls_pemfile::private_key(&mut io::BufReader::new(pem))?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no private key in pem"))
+}
+
+/// Server config presenting `cert_chain`, leaf certificate first
+pub fn server_config(
+    cert_chain: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> Result<Arc<ServerConfig>> {
+    let config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(cert_chain, key)?;
+    Ok(Arc::new(config))
+}
+pub fn server_config_from_pem(cert_chain: &[u8], key: &[u8]) -> Result<Arc<ServerConfig>> {
+    server_config(certs_from_pem(cert_chain)?, private_key_from_pem(key)?)
+}
+/// Client config trusting the Mozilla root certificates
+pub fn client_config() -> Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    client_config_with_roots(roots)
+}
+/// Client config trusting only `roots`, for private or self-signed certificates
+pub fn client_config_with_roots(roots: RootCertStore) -> Result<Arc<ClientConfig>> {
+    let config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// Wrap an accepted stream; the TLS handshake happens on the first read or write
+pub(crate) fn accept(config: Arc<ServerConfig>, stream: TcpStream) -> Result<NetStream> {
+    let connection = ServerConnection::new(config)?;
+    Ok(NetStream::TlsServer(Box::new(rustls::StreamOwned::new(
+        connection, stream,
+    ))))
+}
+pub(crate) fn connect(
+    config: Arc<ClientConfig>,
+    host: &str,
+    stream: TcpStream,
+) -> Result<NetStream> {
+    let server_name = ServerName::try_from(String::from(host))
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+    let connection = ClientConnection::new(config, server_name)?;
+    Ok(NetStream::TlsClient(Box::new(rustls::StreamOwned::new(
+        connection, stream,
+    ))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::connect_with_tls;
+    use crate::message::Message;
+    use crate::server::tests::spawn_server;
+    use crate::server::ServerConfig as WsServerConfig;
+    use crate::websocket::WebSocketConfig;
+
+    #[test]
+    fn should_talk_over_tls_with_self_signed_certs() {
+        let certified =
+            rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let tls = server_config_from_pem(
+            certified.cert.pem().as_bytes(),
+            certified.key_pair.serialize_pem().as_bytes(),
+        )
+        .unwrap();
+        let config = WsServerConfig {
+            tls: Some(tls),
+            ..WsServerConfig::default()
+        };
+        let (address, shutdown, echo, thread) = spawn_server(config);
+
+        let mut roots = RootCertStore::empty();
+        roots.add(certified.cert.der().clone()).unwrap();
+        let url = format!("wss://localhost:{}/", address.port());
+        let mut websocket = connect_with_tls(
+            &url,
+            WebSocketConfig::default(),
+            client_config_with_roots(roots).unwrap(),
+        )
+        .unwrap();
+        assert!(websocket.get_ref().is_tls());
+        websocket
+            .write_message(Message::Text(String::from("secret")))
+            .unwrap();
+        assert!(matches!(websocket.read_message(), Ok(Message::Text(text)) if text == "secret"));
+        websocket.write_message(Message::Close).unwrap();
+        assert!(matches!(websocket.read_message(), Ok(Message::Close)));
+
+        shutdown.shutdown();
+        thread.join().unwrap();
+        assert_eq!(*echo.events.lock().unwrap(), vec!["open", "close 1000"]);
+    }
+    #[test]
+    fn should_reject_untrusted_certs() {
+        let certified =
+            rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let tls = server_config(
+            vec![certified.cert.der().clone()],
+            PrivateKeyDer::Pkcs8(certified.key_pair.serialize_der().into()),
+        )
+        .unwrap();
+        let config = WsServerConfig {
+            tls: Some(tls),
+            ..WsServerConfig::default()
+        };
+        let (address, shutdown, _echo, thread) = spawn_server(config);
+
+        let url = format!("wss://localhost:{}/", address.port());
+        let result = connect_with_tls(&url, WebSocketConfig::default(), client_config().unwrap());
+        assert!(result.is_err());
+
+        shutdown.shutdown();
+        thread.join().unwrap();
+    }
+}
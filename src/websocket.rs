@@ -0,0 +1,1354 @@
+use crate::accept::keys::{AcceptResponse, KeyError};
+use crate::accept::origin::OriginPolicy;
+use crate::accept::ws_headers::WsHeaders;
+use crate::dataframe::{DataFrame, Opcode};
+use crate::error::{Error, ProtocolError, Result, Timeout};
+use crate::keepalive::{Keepalive, KeepaliveAction, KeepaliveEvent};
+use crate::message::{
+    close_code, encode_frame, encode_frame_into, CloseFrame, Message, WriteMessage,
+};
+use crate::metrics::{sent_frame, Direction, Metrics};
+use crate::pool::BufferPool;
+#[cfg(feature = "tracing")]
+use crate::protocol::trace_message;
+use crate::protocol::{
+    close_code_for, peek_frame_header, take_frame, Deadlines, FrameHeader, Incoming,
+    MessageAssembler, Role, StreamedMessage,
+};
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const MAX_HEADER_LENGTH: usize = 8192;
+pub(crate) const READ_CHUNK_SIZE: usize = 4096;
+const HTTP_EOC: &[u8; 4] = b"\r\n\r\n";
+pub(crate) const REQUEST_TIMEOUT_RESPONSE: &[u8] =
+    b"HTTP/1.1 408 Request Timeout\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+
+#[derive(Debug, Clone)]
+pub struct WebSocketConfig {
+    /// Largest payload accepted in a single frame
+    pub max_frame_size: usize,
+    /// Largest payload accepted for a message, after joining its fragments
+    pub max_message_size: usize,
+    pub origin_policy: OriginPolicy,
+    /// Subprotocols a client offers or a server accepts; servers pick the first one offered
+    pub subprotocols: Vec<String>,
+    /// Ping the peer after this long without hearing from it, `None` to never ping.
+    ///
+    /// The blocking [`WebSocket`] checks this whenever a read times out, so the
+    /// stream needs a read timeout shorter than the interval. The async stream and
+    /// the event loop keep their own timers, while the tokio `MessageCodec` can't
+    /// send by itself and ignores this.
+    pub ping_interval: Option<Duration>,
+    /// Close the connection when nothing arrives this long after a ping
+    pub keepalive_timeout: Duration,
+    /// Give up on an opening handshake that takes longer; servers answer with `408 Request Timeout`
+    pub handshake_timeout: Option<Duration>,
+    /// Close with 1008 when a fragmented message isn't finished in time
+    pub fragment_timeout: Option<Duration>,
+    /// Close with 1001 when the peer sends no message for this long; pings and pongs don't count
+    pub idle_timeout: Option<Duration>,
+    /// Where handshakes, frames and messages are reported, see [`crate::metrics::AtomicMetrics`]
+    pub metrics: Option<Arc<dyn Metrics>>,
+    /// Log the payloads of messages at `TRACE` level, cut to this many bytes; `None` leaves them out.
+    ///
+    /// Applies to every transport; messages that are streamed instead of read or written
+    /// whole aren't logged.
+    #[cfg(feature = "tracing")]
+    pub trace_payloads: Option<usize>,
+    /// Read frames into and encode frames in buffers from this pool instead of allocating them.
+    ///
+    /// Clones of a pool share their buffers, so one pool serves every connection made with this
+    /// config; give each connection a config with its own pool to keep them apart.
+    pub buffer_pool: Option<BufferPool>,
+}
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig {
+            max_frame_size: 16 << 20,
+            max_message_size: 64 << 20,
+            origin_policy: OriginPolicy::default(),
+            subprotocols: Vec::new(),
+            ping_interval: None,
+            keepalive_timeout: Duration::from_secs(30),
+            handshake_timeout: Some(Duration::from_secs(10)),
+            fragment_timeout: None,
+            idle_timeout: None,
+            metrics: None,
+            #[cfg(feature = "tracing")]
+            trace_payloads: None,
+            buffer_pool: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum State {
+    Open,
+    CloseSent,
+    Closed,
+}
+
+/// Read from `stream` until the end of the HTTP headers.
+///
+/// Returns the headers and whatever was read past them, which belongs to the first frames.
+/// Read timeouts are retried until `deadline` passes; without one they are returned.
+pub(crate) fn read_http_headers<S>(
+    stream: &mut S,
+    deadline: Option<Instant>,
+) -> Result<(Vec<u8>, Vec<u8>)>
+where
+    S: Read,
+{
+    let mut buffer: Vec<u8> = Vec::with_capacity(1024);
+    loop {
+        if matches!(deadline, Some(deadline) if Instant::now() >= deadline) {
+            return Err(Timeout::Handshake.into());
+        }
+        let search_from = buffer.len();
+        match read_into(stream, &mut buffer, 1024) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(_) => {}
+            Err(error) if deadline.is_some() && is_timeout(&error) => continue,
+            Err(error) => return Err(error.into()),
+        }
+        if let Some(rest) = split_http_headers(&mut buffer, search_from)? {
+            return Ok((buffer, rest));
+        }
+    }
+}
+/// Split off whatever follows the end of the HTTP headers once `buffer` contains all of them.
+///
+/// `new_data_from` is where the bytes added since the last call start.
+pub(crate) fn split_http_headers(
+    buffer: &mut Vec<u8>,
+    new_data_from: usize,
+) -> Result<Option<Vec<u8>>> {
+    let search_from = new_data_from.saturating_sub(HTTP_EOC.len() - 1);
+    if let Some(position) = buffer[search_from..]
+        .windows(HTTP_EOC.len())
+        .position(|window| window == HTTP_EOC)
+    {
+        return Ok(Some(
+            buffer.split_off(search_from + position + HTTP_EOC.len()),
+        ));
+    }
+    if buffer.len() > MAX_HEADER_LENGTH {
+        return Err(ProtocolError::HeadersTooLarge.into());
+    }
+    Ok(None)
+}
+/// What a connection keeps from its opening handshake
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Handshake {
+    /// The request target, like `/chat?room=1`
+    pub path: String,
+    /// The subprotocol both sides agreed on
+    pub subprotocol: Option<String>,
+}
+/// Read the handshake request, answering `408 Request Timeout` if it doesn't arrive in time
+pub(crate) fn read_request<S>(
+    stream: &mut S,
+    config: &WebSocketConfig,
+) -> Result<(Vec<u8>, Vec<u8>)>
+where
+    S: Read + Write,
+{
+    let deadline = config
+        .handshake_timeout
+        .map(|timeout| Instant::now() + timeout);
+    match read_http_headers(stream, deadline) {
+        Err(Error::Timeout(Timeout::Handshake)) => {
+            #[cfg(feature = "tracing")]
+            tracing::info!(timeout = %Timeout::Handshake, "timed out");
+            let _ = stream
+                .write_all(REQUEST_TIMEOUT_RESPONSE)
+                .and_then(|_| stream.flush());
+            Err(Timeout::Handshake.into())
+        }
+        result => result,
+    }
+}
+/// Tell [`WebSocketConfig::metrics`] about a handshake that failed before it could be answered
+pub(crate) fn report_handshake_failure(config: &WebSocketConfig, error: Error) -> Error {
+    if let Some(metrics) = &config.metrics {
+        metrics.handshake_failed(&error);
+    }
+    error
+}
+/// Tell [`WebSocketConfig::metrics`] how a handshake went
+pub(crate) fn report_handshake<T>(metrics: &Option<Arc<dyn Metrics>>, result: &Result<T>) {
+    match (metrics, result) {
+        (Some(metrics), Ok(_)) => metrics.handshake_succeeded(),
+        (Some(metrics), Err(error)) => metrics.handshake_failed(error),
+        (None, _) => {}
+    }
+}
+/// The method and target of an HTTP request
+pub(crate) fn parse_request_line(headers: &[u8]) -> Option<(&str, &str)> {
+    let end = headers
+        .windows(2)
+        .position(|window| window == b"\r\n")
+        .unwrap_or(headers.len());
+    let mut parts = std::str::from_utf8(&headers[..end]).ok()?.split(' ');
+    Some((parts.next()?, parts.next()?))
+}
+/// Check a handshake request and build the response that accepts it
+pub(crate) fn accept_request(
+    headers: &[u8],
+    config: &WebSocketConfig,
+) -> std::result::Result<(Vec<u8>, Handshake), KeyError> {
+    let text = String::from_utf8_lossy(headers);
+    let accept =
+        match AcceptResponse::from_header_buffer_with_policy(headers, &config.origin_policy) {
+            Ok(accept) => accept,
+            Err(error) => {
+                #[cfg(feature = "tracing")]
+                tracing::info!(
+                    ?error,
+                    missing_header = WsHeaders::from(&text).get_missing(),
+                    "rejected handshake"
+                );
+                return Err(error);
+            }
+        };
+    let path = parse_request_line(headers)
+        .map(|(_, target)| target)
+        .unwrap_or("/");
+    let subprotocol = WsHeaders::from(&text).get_protocol().and_then(|offered| {
+        offered
+            .split(',')
+            .map(str::trim)
+            .find(|protocol| config.subprotocols.iter().any(|ours| ours == protocol))
+    });
+    let mut response = accept.get_data().to_vec();
+    if let Some(protocol) = subprotocol {
+        response.truncate(response.len() - 2);
+        response.extend_from_slice(b"Sec-WebSocket-Protocol: ");
+        response.extend_from_slice(protocol.as_bytes());
+        response.extend_from_slice(HTTP_EOC);
+    }
+    #[cfg(feature = "tracing")]
+    tracing::debug!(path, subprotocol, "accepted handshake");
+    let handshake = Handshake {
+        path: String::from(path),
+        subprotocol: subprotocol.map(String::from),
+    };
+    Ok((response, handshake))
+}
+pub(crate) fn is_timeout(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+/// Append up to `length` bytes from `stream` to the end of `buffer`
+pub(crate) fn read_into<S>(stream: &mut S, buffer: &mut Vec<u8>, length: usize) -> io::Result<usize>
+where
+    S: Read,
+{
+    let start = buffer.len();
+    buffer.resize(start + length, 0);
+    loop {
+        match stream.read(&mut buffer[start..]) {
+            Ok(read) => {
+                buffer.truncate(start + read);
+                return Ok(read);
+            }
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => {
+                buffer.truncate(start);
+                return Err(error);
+            }
+        }
+    }
+}
+
+/// A websocket connection over any blocking stream.
+///
+/// Pings are answered automatically and fragmented messages are joined
+/// before they are returned from [`WebSocket::read_message`].
+pub struct WebSocket<S> {
+    stream: S,
+    config: WebSocketConfig,
+    role: Role,
+    read_buffer: Vec<u8>,
+    assembler: MessageAssembler,
+    state: State,
+    close_frame: Option<CloseFrame>,
+    keepalive: Keepalive,
+    keepalive_events: Vec<KeepaliveEvent>,
+    deadlines: Deadlines,
+    handshake: Handshake,
+    /// The message a [`MessageReader`] is streaming, until all of it has been read
+    streamed: Option<StreamedMessage>,
+}
+impl<S> WebSocket<S>
+where
+    S: Read + Write,
+{
+    /// Perform the server side of the opening handshake on `stream`
+    pub fn accept(stream: S) -> Result<Self> {
+        Self::accept_with_config(stream, WebSocketConfig::default())
+    }
+    /// Like [`WebSocket::accept`]; a rejected handshake is answered with an HTTP error response.
+    ///
+    /// [`WebSocketConfig::handshake_timeout`] only applies if the stream has a read timeout.
+    pub fn accept_with_config(mut stream: S, config: WebSocketConfig) -> Result<Self> {
+        match read_request(&mut stream, &config) {
+            Ok((headers, rest)) => Self::accept_headers(stream, config, &headers, rest),
+            Err(error) => Err(report_handshake_failure(&config, error)),
+        }
+    }
+    /// Answer request `headers` that have been read already; `rest` is what followed them
+    pub(crate) fn accept_headers(
+        stream: S,
+        config: WebSocketConfig,
+        headers: &[u8],
+        rest: Vec<u8>,
+    ) -> Result<Self> {
+        let metrics = config.metrics.clone();
+        let result = Self::respond(stream, config, headers, rest);
+        report_handshake(&metrics, &result);
+        result
+    }
+    fn respond(
+        mut stream: S,
+        config: WebSocketConfig,
+        headers: &[u8],
+        rest: Vec<u8>,
+    ) -> Result<Self> {
+        let handshake = match accept_request(headers, &config) {
+            Ok((response, handshake)) => {
+                stream.write_all(&response)?;
+                stream.flush()?;
+                handshake
+            }
+            Err(error) => {
+                // The handshake error is more useful to the caller than a failed write
+                let _ = stream
+                    .write_all(error.get_response())
+                    .and_then(|_| stream.flush());
+                return Err(error.into());
+            }
+        };
+        Ok(WebSocket::from_parts(
+            stream,
+            config,
+            Role::Server,
+            handshake,
+            rest,
+        ))
+    }
+    /// Wrap a stream where the server side of the opening handshake has already been done
+    pub fn from_raw_stream(stream: S, config: WebSocketConfig) -> Self {
+        WebSocket::from_parts(
+            stream,
+            config,
+            Role::Server,
+            Handshake::default(),
+            Vec::new(),
+        )
+    }
+    /// `read_buffer` holds whatever was read past the handshake
+    pub(crate) fn from_parts(
+        stream: S,
+        config: WebSocketConfig,
+        role: Role,
+        handshake: Handshake,
+        read_buffer: Vec<u8>,
+    ) -> Self {
+        WebSocket {
+            stream,
+            assembler: MessageAssembler::new(&config, role),
+            keepalive: Keepalive::new(config.ping_interval, config.keepalive_timeout),
+            keepalive_events: Vec::new(),
+            deadlines: Deadlines::new(config.fragment_timeout, config.idle_timeout),
+            config,
+            role,
+            read_buffer,
+            state: State::Open,
+            close_frame: None,
+            handshake,
+            streamed: None,
+        }
+    }
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+    pub fn get_config(&self) -> &WebSocketConfig {
+        &self.config
+    }
+    /// The request path and subprotocol of the opening handshake
+    pub fn get_handshake(&self) -> &Handshake {
+        &self.handshake
+    }
+    /// The close frame sent by the peer, once one has been received
+    pub fn get_close_frame(&self) -> Option<&CloseFrame> {
+        self.close_frame.as_ref()
+    }
+    pub fn can_write(&self) -> bool {
+        self.state == State::Open
+    }
+    /// Keepalive pings, pongs and timeouts since the last call
+    pub fn take_keepalive_events(&mut self) -> Vec<KeepaliveEvent> {
+        std::mem::take(&mut self.keepalive_events)
+    }
+    /// Read the next message.
+    ///
+    /// Returns [`Message::Close`] once when the peer closes the connection, after
+    /// which every call fails with [`Error::ConnectionClosed`]. If the stream
+    /// returns `WouldBlock` or `TimedOut` it is safe to call this again later.
+    pub fn read_message(&mut self) -> Result<Message> {
+        if let Err(error) = self.skip_streamed() {
+            return Err(self.fail(error));
+        }
+        loop {
+            if self.state == State::Closed {
+                return Err(Error::ConnectionClosed);
+            }
+            match self.read_frame().and_then(|frame| self.handle_frame(frame)) {
+                Ok(Some(message)) => return Ok(message),
+                Ok(None) => continue,
+                Err(error) => return Err(self.fail(error)),
+            }
+        }
+    }
+    /// Read the next text or binary message as a stream instead of all at once.
+    ///
+    /// The payload is unmasked as it is read, across all of its fragments, so memory use
+    /// doesn't grow with the message; `max_frame_size` and `max_message_size` don't apply.
+    /// Control frames in between are handled like in [`WebSocket::read_message`] but not
+    /// returned, and a close frame ends it with [`Error::ConnectionClosed`]. Whatever is
+    /// left of a message that wasn't read to the end is skipped by the next read.
+    pub fn next_message_reader(&mut self) -> Result<MessageReader<'_, S>> {
+        let message = self
+            .skip_streamed()
+            .and_then(|_| self.read_data_header())
+            .and_then(|(header, length)| StreamedMessage::new(&header, length, self.role));
+        match message {
+            Ok(message) => {
+                let opcode = message.get_opcode();
+                self.streamed = Some(message);
+                Ok(MessageReader {
+                    websocket: self,
+                    opcode,
+                })
+            }
+            Err(error) => Err(self.fail(error)),
+        }
+    }
+    pub fn write_message(&mut self, message: Message) -> Result<()> {
+        match message {
+            Message::Close => self.close(CloseFrame::new(close_code::NORMAL, "")),
+            message => {
+                if self.state != State::Open {
+                    return Err(Error::ConnectionClosed);
+                }
+                self.send(message.get_opcode(), message.as_ref())?;
+                self.record_sent(&message);
+                if let Some(pool) = &self.config.buffer_pool {
+                    pool.recycle(message.into_payload());
+                }
+                Ok(())
+            }
+        }
+    }
+    /// Send a text or binary message in frames of up to `chunk_size` bytes, see [`MessageWriter`].
+    ///
+    /// The first frame is sent once `chunk_size` bytes have been written and more follow, and the
+    /// rest when the writer is finished or dropped. Text has to be UTF-8 once it is complete.
+    /// Other opcodes and a `chunk_size` of zero are refused with [`io::ErrorKind::InvalidInput`].
+    pub fn message_writer(
+        &mut self,
+        opcode: Opcode,
+        chunk_size: usize,
+    ) -> Result<MessageWriter<'_, S>> {
+        if !matches!(opcode, Opcode::Text | Opcode::Binary) {
+            let message = "only text and binary messages can be streamed";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message).into());
+        }
+        if chunk_size == 0 {
+            let message = "the chunk size has to be at least one byte";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message).into());
+        }
+        if self.state != State::Open {
+            return Err(Error::ConnectionClosed);
+        }
+        Ok(MessageWriter {
+            websocket: self,
+            opcode,
+            buffer: Vec::with_capacity(chunk_size),
+            chunk_size,
+            size: 0,
+            finished: false,
+        })
+    }
+    /// Send everything `reader` returns as one message, in frames of up to `chunk_size` bytes
+    pub fn send_stream<R>(&mut self, opcode: Opcode, mut reader: R, chunk_size: usize) -> Result<()>
+    where
+        R: Read,
+    {
+        let mut writer = self.message_writer(opcode, chunk_size)?;
+        writer.copy_from(&mut reader)?;
+        writer.finish()
+    }
+    /// Write a frame encoded ahead of time, which has to be masked if this is a client
+    pub(crate) fn write_encoded(&mut self, message: &WriteMessage) -> Result<()> {
+        if self.state != State::Open {
+            return Err(Error::ConnectionClosed);
+        }
+        self.stream.write_all(message.as_ref())?;
+        self.stream.flush()?;
+        if let Some(metrics) = &self.config.metrics {
+            sent_frame(&**metrics, message.as_ref());
+        }
+        Ok(())
+    }
+    /// Start the closing handshake.
+    ///
+    /// Keep calling [`WebSocket::read_message`] until the peer's close frame arrives.
+    pub fn close(&mut self, frame: CloseFrame) -> Result<()> {
+        match self.state {
+            State::Open => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(code = frame.code, reason = %frame.reason, "sending close frame");
+                self.send(Opcode::Close, &frame.to_payload())?;
+                self.state = State::CloseSent;
+                Ok(())
+            }
+            State::CloseSent => Ok(()),
+            State::Closed => Err(Error::ConnectionClosed),
+        }
+    }
+    fn send(&mut self, opcode: Opcode, payload: &[u8]) -> Result<()> {
+        self.send_frame(opcode, payload, true)
+    }
+    fn send_frame(&mut self, opcode: Opcode, payload: &[u8], fin: bool) -> Result<()> {
+        let frame = match &self.config.buffer_pool {
+            Some(pool) => {
+                let mut frame = pool.take(payload.len() + 14);
+                encode_frame_into(&mut frame, opcode, payload, fin, self.role.get_mask());
+                frame
+            }
+            None => encode_frame(opcode, payload, fin, self.role.get_mask()),
+        };
+        self.stream.write_all(&frame)?;
+        self.stream.flush()?;
+        if let Some(metrics) = &self.config.metrics {
+            metrics.frame(Direction::Out, opcode, frame.len());
+        }
+        if let Some(pool) = &self.config.buffer_pool {
+            pool.recycle(frame);
+        }
+        Ok(())
+    }
+    /// Received messages are counted and traced by the assembler
+    fn record_sent(&self, message: &Message) {
+        if let (Some(metrics), Message::Text(_) | Message::Binary(_)) =
+            (&self.config.metrics, message)
+        {
+            metrics.message(Direction::Out, message.as_ref().len());
+        }
+        #[cfg(feature = "tracing")]
+        trace_message(self.config.trace_payloads, Direction::Out, message);
+    }
+    /// Send a close frame matching a protocol error before giving up on the connection
+    fn fail(&mut self, error: Error) -> Error {
+        let code = match close_code_for(&error) {
+            Some(code) => code,
+            None => return error,
+        };
+        if self.state == State::Open {
+            let _ = self.send(Opcode::Close, &code.to_be_bytes());
+        }
+        self.state = State::Closed;
+        error
+    }
+    fn read_frame(&mut self) -> Result<DataFrame> {
+        loop {
+            let pool = self.config.buffer_pool.as_ref();
+            if let Some(frame) =
+                take_frame(&mut self.read_buffer, self.config.max_frame_size, pool)?
+            {
+                return Ok(frame);
+            }
+            self.fill_read_buffer()?;
+        }
+    }
+    fn fill_read_buffer(&mut self) -> Result<()> {
+        let read = match read_into(&mut self.stream, &mut self.read_buffer, READ_CHUNK_SIZE) {
+            Err(error) if is_timeout(&error) => {
+                self.check_keepalive()?;
+                self.deadlines.check(Instant::now())?;
+                return Err(error.into());
+            }
+            read => read?,
+        };
+        if read == 0 {
+            let state = std::mem::replace(&mut self.state, State::Closed);
+            return Err(match state {
+                // The peer is allowed to drop the connection once it has seen our close frame
+                State::CloseSent => Error::ConnectionClosed,
+                _ => io::Error::from(io::ErrorKind::UnexpectedEof).into(),
+            });
+        }
+        Ok(())
+    }
+    /// Read up to the payload of the next data frame, handling control frames on the way
+    fn read_data_header(&mut self) -> Result<(FrameHeader, usize)> {
+        loop {
+            if self.state == State::Closed {
+                return Err(Error::ConnectionClosed);
+            }
+            let (header, header_length, payload_length) = match peek_frame_header(&self.read_buffer)
+            {
+                Some(header) => header,
+                None => {
+                    self.fill_read_buffer()?;
+                    continue;
+                }
+            };
+            let opcode = Opcode::from(header.get_opcode());
+            if opcode.is_control() {
+                let frame = self.read_frame()?;
+                self.handle_frame(frame)?;
+                continue;
+            }
+            self.read_buffer.drain(..header_length);
+            if let Some(metrics) = &self.config.metrics {
+                metrics.frame(
+                    Direction::In,
+                    opcode,
+                    header_length.saturating_add(payload_length),
+                );
+            }
+            let now = Instant::now();
+            self.deadlines.on_frame(&header, now)?;
+            self.keepalive.on_traffic(now);
+            return Ok((header, payload_length));
+        }
+    }
+    /// Read the next part of the message a [`MessageReader`] streams, `0` once all of it has been read
+    fn read_payload(&mut self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            let message = match &mut self.streamed {
+                Some(message) => message,
+                None => return Ok(0),
+            };
+            if message.is_finished() {
+                message.finish()?;
+                if let Some(metrics) = &self.config.metrics {
+                    metrics.message(Direction::In, message.get_size());
+                }
+                self.streamed = None;
+                return Ok(0);
+            }
+            let wanted = buf.len().min(message.get_remaining());
+            if message.get_remaining() == 0 {
+                let (header, length) = self.read_data_header()?;
+                if let Some(message) = &mut self.streamed {
+                    message.next_frame(&header, length, self.role)?;
+                }
+                continue;
+            }
+            if wanted == 0 {
+                return Ok(0);
+            }
+            // Whatever is buffered goes first, the rest is read straight into `buf`
+            let read = match self.read_buffer.is_empty() {
+                false => {
+                    let read = wanted.min(self.read_buffer.len());
+                    buf[..read].copy_from_slice(&self.read_buffer[..read]);
+                    self.read_buffer.drain(..read);
+                    read
+                }
+                true => match self.stream.read(&mut buf[..wanted]) {
+                    Ok(0) => {
+                        self.state = State::Closed;
+                        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                    }
+                    Ok(read) => read,
+                    Err(error) if is_timeout(&error) => {
+                        self.check_keepalive()?;
+                        self.deadlines.check(Instant::now())?;
+                        return Err(error.into());
+                    }
+                    Err(error) => return Err(error.into()),
+                },
+            };
+            self.keepalive.on_traffic(Instant::now());
+            if let Some(message) = &mut self.streamed {
+                message.unmask(&mut buf[..read])?;
+            }
+            return Ok(read);
+        }
+    }
+    /// Read past what is left of a message a [`MessageReader`] didn't finish
+    fn skip_streamed(&mut self) -> Result<()> {
+        if self.state == State::Closed {
+            self.streamed = None;
+        }
+        let mut scratch = [0; READ_CHUNK_SIZE];
+        while self.streamed.is_some() {
+            self.read_payload(&mut scratch)?;
+        }
+        Ok(())
+    }
+    /// Ping an idle peer, or give up on it if it didn't answer the last ping
+    fn check_keepalive(&mut self) -> Result<()> {
+        match self.keepalive.poll(Instant::now()) {
+            KeepaliveAction::Wait => Ok(()),
+            KeepaliveAction::Ping => {
+                if self.state == State::Open {
+                    self.send(Opcode::Ping, &[])?;
+                    self.keepalive_events.push(KeepaliveEvent::PingSent);
+                }
+                Ok(())
+            }
+            KeepaliveAction::TimedOut => {
+                self.keepalive_events.push(KeepaliveEvent::TimedOut);
+                Err(Timeout::Keepalive.into())
+            }
+        }
+    }
+    fn handle_frame(&mut self, frame: DataFrame) -> Result<Option<Message>> {
+        let now = Instant::now();
+        self.deadlines.on_frame(&frame, now)?;
+        self.keepalive.on_traffic(now);
+        if frame.get_opcode() == Opcode::Pong as u8 {
+            if let Some(event) = self.keepalive.on_pong(now) {
+                self.keepalive_events.push(event);
+            }
+        }
+        match self.assembler.push(frame)? {
+            Some(Incoming::Message(Message::Ping(payload))) => {
+                if self.state == State::Open {
+                    self.send(Opcode::Pong, &payload)?;
+                }
+                Ok(Some(Message::Ping(payload)))
+            }
+            Some(Incoming::Message(message)) => Ok(Some(message)),
+            Some(Incoming::Close(close_frame)) => {
+                if self.state == State::Open {
+                    let reply = close_frame
+                        .as_ref()
+                        .map(|frame| frame.code.to_be_bytes().to_vec())
+                        .unwrap_or_default();
+                    self.send(Opcode::Close, &reply)?;
+                }
+                self.state = State::Closed;
+                self.close_frame = close_frame;
+                Ok(Some(Message::Close))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// The payload of one text or binary message as it arrives, see [`WebSocket::next_message_reader`].
+///
+/// Text is checked to be UTF-8 as it is read; reads fail once it turns out not to be.
+pub struct MessageReader<'a, S> {
+    websocket: &'a mut WebSocket<S>,
+    opcode: Opcode,
+}
+impl<'a, S> MessageReader<'a, S> {
+    /// [`Opcode::Text`] or [`Opcode::Binary`]
+    pub fn get_opcode(&self) -> Opcode {
+        self.opcode
+    }
+}
+impl<'a, S> Read for MessageReader<'a, S>
+where
+    S: Read + Write,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.websocket.read_payload(buf) {
+            Ok(read) => Ok(read),
+            Err(error) => Err(self.websocket.fail(error).into()),
+        }
+    }
+}
+
+/// Sends one text or binary message in fragments as it is written, see [`WebSocket::message_writer`].
+///
+/// Pings, pongs and close frames can go out in between with [`MessageWriter::write_control`].
+pub struct MessageWriter<'a, S>
+where
+    S: Read + Write,
+{
+    websocket: &'a mut WebSocket<S>,
+    /// The opcode of the next frame, [`Opcode::Continuation`] after the first one
+    opcode: Opcode,
+    buffer: Vec<u8>,
+    chunk_size: usize,
+    size: usize,
+    finished: bool,
+}
+impl<'a, S> MessageWriter<'a, S>
+where
+    S: Read + Write,
+{
+    /// Send a control message between two fragments; data messages have to wait for the end of this one
+    pub fn write_control(&mut self, message: Message) -> Result<()> {
+        match message {
+            Message::Text(_) | Message::Binary(_) => {
+                Err(ProtocolError::ExpectedContinuation.into())
+            }
+            message => self.websocket.write_message(message),
+        }
+    }
+    /// Send what is buffered as the final frame
+    pub fn finish(mut self) -> Result<()> {
+        self.finish_message()
+    }
+    fn write_payload(&mut self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            if self.buffer.len() == self.chunk_size {
+                self.send_buffer(false)?;
+            }
+            let length = data.len().min(self.chunk_size - self.buffer.len());
+            self.buffer.extend_from_slice(&data[..length]);
+            data = &data[length..];
+        }
+        Ok(())
+    }
+    /// Write everything `reader` returns
+    fn copy_from<R>(&mut self, reader: &mut R) -> Result<()>
+    where
+        R: Read,
+    {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        loop {
+            match reader.read(&mut chunk) {
+                Ok(0) => return Ok(()),
+                Ok(read) => self.write_payload(&chunk[..read])?,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error.into()),
+            }
+        }
+    }
+    fn send_buffer(&mut self, fin: bool) -> Result<()> {
+        if self.websocket.state != State::Open {
+            return Err(Error::ConnectionClosed);
+        }
+        self.websocket.send_frame(self.opcode, &self.buffer, fin)?;
+        self.opcode = Opcode::Continuation;
+        self.size += self.buffer.len();
+        self.buffer.clear();
+        Ok(())
+    }
+    fn finish_message(&mut self) -> Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        self.send_buffer(true)?;
+        if let Some(metrics) = &self.websocket.config.metrics {
+            metrics.message(Direction::Out, self.size);
+        }
+        Ok(())
+    }
+}
+impl<'a, S> Write for MessageWriter<'a, S>
+where
+    S: Read + Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_payload(buf)?;
+        Ok(buf.len())
+    }
+    /// Send what is buffered as a fragment of its own
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.send_buffer(false)?;
+        }
+        Ok(())
+    }
+}
+impl<'a, S> Drop for MessageWriter<'a, S>
+where
+    S: Read + Write,
+{
+    fn drop(&mut self) {
+        // Like `BufWriter`, errors can't be reported here; call `finish` to see them
+        let _ = self.finish_message();
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::accept::keys::KeyError;
+    use crate::message::encode_frame;
+    use std::io::Cursor;
+
+    pub(crate) const REQUEST: &[u8] = b"GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nOrigin: https://example.com\r\nSec-WebSocket-Version: 13\r\n\r\n";
+    const RESPONSE: &[u8] = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-Websocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n";
+
+    pub(crate) struct MockStream {
+        pub input: Cursor<Vec<u8>>,
+        pub output: Vec<u8>,
+        /// Time out instead of returning EOF once the input is used up
+        pub stall: bool,
+    }
+    impl MockStream {
+        pub fn new(input: Vec<u8>) -> Self {
+            MockStream {
+                input: Cursor::new(input),
+                output: Vec::new(),
+                stall: false,
+            }
+        }
+    }
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.input.read(buf)? {
+                0 if self.stall && !buf.is_empty() => Err(io::ErrorKind::WouldBlock.into()),
+                read => Ok(read),
+            }
+        }
+    }
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn client_frame(opcode: Opcode, payload: &[u8], fin: bool) -> Vec<u8> {
+        encode_frame(opcode, payload, fin, Some([37, 250, 11, 190]))
+    }
+    fn accepted(frames: &[Vec<u8>]) -> WebSocket<MockStream> {
+        let mut input = REQUEST.to_vec();
+        frames
+            .iter()
+            .for_each(|frame| input.extend_from_slice(frame));
+        let websocket = WebSocket::accept(MockStream::new(input)).unwrap();
+        assert_eq!(websocket.get_ref().output, RESPONSE);
+        websocket
+    }
+    fn written_after_handshake(websocket: &WebSocket<MockStream>) -> &[u8] {
+        &websocket.get_ref().output[RESPONSE.len()..]
+    }
+
+    #[test]
+    fn should_accept_and_read_messages() {
+        let mut websocket = accepted(&[
+            client_frame(Opcode::Text, b"Hello", true),
+            client_frame(Opcode::Binary, &[1, 2, 3], true),
+        ]);
+        assert!(matches!(websocket.read_message(), Ok(Message::Text(text)) if text == "Hello"));
+        assert!(matches!(websocket.read_message(), Ok(Message::Binary(data)) if data == [1, 2, 3]));
+        assert!(matches!(
+            websocket.read_message(),
+            Err(Error::Io(error)) if error.kind() == io::ErrorKind::UnexpectedEof
+        ));
+    }
+    #[test]
+    fn should_reject_denied_origins_with_forbidden() {
+        let config = WebSocketConfig {
+            origin_policy: OriginPolicy::new().allow("https://example.org"),
+            ..WebSocketConfig::default()
+        };
+        let mut stream = MockStream::new(REQUEST.to_vec());
+        let result = WebSocket::accept_with_config(&mut stream, config);
+        assert!(matches!(
+            result,
+            Err(Error::Handshake(KeyError::OriginNotAllowed))
+        ));
+        assert!(stream.output.starts_with(b"HTTP/1.1 403 Forbidden\r\n"));
+    }
+    #[test]
+    fn should_answer_pings() {
+        let mut websocket = accepted(&[client_frame(Opcode::Ping, b"ping", true)]);
+        assert!(matches!(websocket.read_message(), Ok(Message::Ping(data)) if data == b"ping"));
+        assert_eq!(written_after_handshake(&websocket), b"\x8a\x04ping");
+    }
+    #[test]
+    fn should_join_fragments_around_control_frames() {
+        let mut websocket = accepted(&[
+            client_frame(Opcode::Text, b"Hel", false),
+            client_frame(Opcode::Ping, b"", true),
+            client_frame(Opcode::Continuation, b"lo", true),
+        ]);
+        assert!(matches!(websocket.read_message(), Ok(Message::Ping(data)) if data.is_empty()));
+        assert!(matches!(websocket.read_message(), Ok(Message::Text(text)) if text == "Hello"));
+    }
+    #[test]
+    fn should_complete_close_handshake_from_peer() {
+        let payload = CloseFrame::new(close_code::GOING_AWAY, "bye").to_payload();
+        let mut websocket = accepted(&[client_frame(Opcode::Close, &payload, true)]);
+        assert!(matches!(websocket.read_message(), Ok(Message::Close)));
+        assert_eq!(
+            websocket.get_close_frame(),
+            Some(&CloseFrame::new(close_code::GOING_AWAY, "bye"))
+        );
+        assert_eq!(written_after_handshake(&websocket), [136, 2, 3, 233]);
+        assert!(matches!(
+            websocket.read_message(),
+            Err(Error::ConnectionClosed)
+        ));
+        assert!(matches!(
+            websocket.write_message(Message::Text(String::from("late"))),
+            Err(Error::ConnectionClosed)
+        ));
+    }
+    #[test]
+    fn should_complete_close_handshake_from_us() {
+        let mut websocket = accepted(&[client_frame(Opcode::Close, &[3, 232], true)]);
+        websocket
+            .close(CloseFrame::new(close_code::NORMAL, "done"))
+            .unwrap();
+        assert!(matches!(websocket.read_message(), Ok(Message::Close)));
+        assert_eq!(written_after_handshake(&websocket), b"\x88\x06\x03\xe8done");
+    }
+    #[test]
+    fn should_fail_on_protocol_errors() {
+        let mut websocket = accepted(&[encode_frame(Opcode::Text, b"Hello", true, None)]);
+        assert!(matches!(
+            websocket.read_message(),
+            Err(Error::Protocol(ProtocolError::UnmaskedFrame))
+        ));
+        assert_eq!(written_after_handshake(&websocket), [136, 2, 3, 234]);
+
+        let mut websocket = accepted(&[client_frame(Opcode::Text, &[0xff, 0xfe], true)]);
+        assert!(matches!(
+            websocket.read_message(),
+            Err(Error::Protocol(ProtocolError::InvalidUtf8))
+        ));
+        assert_eq!(written_after_handshake(&websocket), [136, 2, 3, 239]);
+    }
+    #[test]
+    fn should_limit_message_size() {
+        let mut input = REQUEST.to_vec();
+        input.extend(client_frame(Opcode::Binary, &[0; 8], false));
+        input.extend(client_frame(Opcode::Continuation, &[0; 8], true));
+        let config = WebSocketConfig {
+            max_message_size: 10,
+            ..WebSocketConfig::default()
+        };
+        let mut websocket = WebSocket::accept_with_config(MockStream::new(input), config).unwrap();
+        assert!(matches!(
+            websocket.read_message(),
+            Err(Error::MessageTooBig)
+        ));
+        assert_eq!(written_after_handshake(&websocket), [136, 2, 3, 241]);
+    }
+    #[test]
+    fn should_stream_messages_across_fragments() {
+        let payload: Vec<u8> = (0..=255).cycle().take(10_000).collect();
+        let mut input = REQUEST.to_vec();
+        input.extend(client_frame(Opcode::Binary, &payload[..6000], false));
+        input.extend(client_frame(Opcode::Ping, b"ping", true));
+        input.extend(client_frame(Opcode::Continuation, &payload[6000..], true));
+        input.extend(client_frame(Opcode::Text, b"after", true));
+        // Nothing is buffered, so the limits don't get in the way
+        let config = WebSocketConfig {
+            max_frame_size: 16,
+            max_message_size: 16,
+            ..WebSocketConfig::default()
+        };
+        let mut websocket = WebSocket::accept_with_config(MockStream::new(input), config).unwrap();
+        let mut reader = websocket.next_message_reader().unwrap();
+        assert_eq!(reader.get_opcode(), Opcode::Binary);
+        let mut streamed = Vec::new();
+        reader.read_to_end(&mut streamed).unwrap();
+        assert_eq!(streamed, payload);
+        assert_eq!(written_after_handshake(&websocket), b"\x8a\x04ping");
+        assert!(matches!(websocket.read_message(), Ok(Message::Text(text)) if text == "after"));
+    }
+    #[test]
+    fn should_send_streams_in_fragments() {
+        let mut websocket = accepted(&[]);
+        websocket
+            .send_stream(Opcode::Binary, &[1; 10][..], 4)
+            .unwrap();
+        websocket
+            .send_stream(Opcode::Binary, &[2; 8][..], 4)
+            .unwrap();
+        assert_eq!(
+            written_after_handshake(&websocket),
+            [
+                [2, 4, 1, 1, 1, 1, 0, 4, 1, 1, 1, 1, 128, 2, 1, 1].as_slice(),
+                [2, 4, 2, 2, 2, 2, 128, 4, 2, 2, 2, 2].as_slice()
+            ]
+            .concat()
+        );
+    }
+    #[test]
+    fn should_refuse_to_stream_control_messages_or_empty_chunks() {
+        let mut websocket = accepted(&[]);
+        let result = websocket.send_stream(Opcode::Ping, &b"ping"[..], 4);
+        assert!(
+            matches!(result, Err(Error::Io(error)) if error.kind() == io::ErrorKind::InvalidInput)
+        );
+        let result = websocket.send_stream(Opcode::Binary, &b"data"[..], 0);
+        assert!(
+            matches!(result, Err(Error::Io(error)) if error.kind() == io::ErrorKind::InvalidInput)
+        );
+        websocket
+            .send_stream(Opcode::Binary, &b"ok"[..], 4)
+            .unwrap();
+        assert_eq!(written_after_handshake(&websocket), b"\x82\x02ok");
+    }
+    #[test]
+    fn should_interleave_control_frames_with_written_fragments() {
+        let mut websocket = accepted(&[]);
+        let mut writer = websocket.message_writer(Opcode::Text, 3).unwrap();
+        writer.write_all(b"Hello").unwrap();
+        writer.write_control(Message::Ping(b"p".to_vec())).unwrap();
+        assert!(writer
+            .write_control(Message::Text(String::from("no")))
+            .is_err());
+        writer.write_all(b"!").unwrap();
+        writer.finish().unwrap();
+        // Dropping a writer finishes its message too
+        websocket
+            .message_writer(Opcode::Text, 3)
+            .unwrap()
+            .write_all(b"ab")
+            .unwrap();
+        assert_eq!(
+            written_after_handshake(&websocket),
+            b"\x01\x03Hel\x89\x01p\x80\x03lo!\x81\x02ab"
+        );
+    }
+    #[test]
+    fn should_skip_unfinished_streams_and_validate_streamed_text() {
+        let text = "h\u{20ac}".as_bytes();
+        let mut websocket = accepted(&[
+            client_frame(Opcode::Binary, &[1; 100], true),
+            client_frame(Opcode::Text, &text[..2], false),
+            client_frame(Opcode::Continuation, &text[2..], true),
+            client_frame(Opcode::Text, &[b'a', 0xff], true),
+        ]);
+        websocket
+            .next_message_reader()
+            .unwrap()
+            .read_exact(&mut [0; 10])
+            .unwrap();
+        let mut streamed = String::new();
+        let mut reader = websocket.next_message_reader().unwrap();
+        reader.read_to_string(&mut streamed).unwrap();
+        assert_eq!(streamed, "h\u{20ac}");
+
+        let mut reader = websocket.next_message_reader().unwrap();
+        let error = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(written_after_handshake(&websocket), [136, 2, 3, 239]);
+    }
+    #[test]
+    fn should_write_messages() {
+        let mut websocket = accepted(&[]);
+        websocket
+            .write_message(Message::Binary(vec![1, 2]))
+            .unwrap();
+        websocket
+            .write_message(Message::Text(String::from("a")))
+            .unwrap();
+        assert_eq!(
+            written_after_handshake(&websocket),
+            [130, 2, 1, 2, 129, 1, 97]
+        );
+    }
+    fn pooled(frames: usize) -> WebSocket<MockStream> {
+        let config = WebSocketConfig {
+            buffer_pool: Some(BufferPool::new()),
+            ..WebSocketConfig::default()
+        };
+        let mut input = REQUEST.to_vec();
+        (0..frames).for_each(|_| input.extend(client_frame(Opcode::Text, b"Hello", true)));
+        WebSocket::accept_with_config(MockStream::new(input), config).unwrap()
+    }
+    fn echo(websocket: &mut WebSocket<MockStream>) {
+        let message = websocket.read_message().unwrap();
+        websocket.write_message(message).unwrap();
+    }
+    #[test]
+    fn should_recycle_pooled_buffers() {
+        let mut websocket = pooled(3);
+        (0..3).for_each(|_| echo(&mut websocket));
+        let pool = websocket.get_config().buffer_pool.clone().unwrap();
+        // One buffer for the frame read and one for the frame written, in the smallest class
+        assert_eq!(pool.get_pooled(), [2, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            written_after_handshake(&websocket),
+            b"\x81\x05Hello".repeat(3).as_slice()
+        );
+    }
+    #[cfg(feature = "count-allocations")]
+    #[test]
+    fn pooled_echo_no_allocations() {
+        let mut websocket = pooled(20);
+        (0..10).for_each(|_| echo(&mut websocket));
+        websocket.read_buffer.reserve(2 * READ_CHUNK_SIZE);
+        websocket.get_mut().output.reserve(1024);
+        let pt_alloc = allocation_counter::count(|| {
+            (0..10).for_each(|_| echo(&mut websocket));
+        });
+        assert_eq!(pt_alloc, 0);
+    }
+    #[test]
+    fn should_time_out_slow_handshakes() {
+        let config = WebSocketConfig {
+            handshake_timeout: Some(Duration::from_millis(5)),
+            ..WebSocketConfig::default()
+        };
+        let mut stream = MockStream::new(REQUEST[..20].to_vec());
+        stream.stall = true;
+        let result = WebSocket::accept_with_config(&mut stream, config);
+        assert!(matches!(result, Err(Error::Timeout(Timeout::Handshake))));
+        assert!(stream
+            .output
+            .starts_with(b"HTTP/1.1 408 Request Timeout\r\n"));
+    }
+    #[test]
+    fn should_time_out_unfinished_fragments() {
+        let mut input = REQUEST.to_vec();
+        input.extend(client_frame(Opcode::Text, b"Hel", false));
+        let mut stream = MockStream::new(input);
+        stream.stall = true;
+        let config = WebSocketConfig {
+            fragment_timeout: Some(Duration::from_millis(5)),
+            ..WebSocketConfig::default()
+        };
+        let mut websocket = WebSocket::accept_with_config(stream, config).unwrap();
+        assert!(matches!(
+            websocket.read_message(),
+            Err(Error::Io(error)) if is_timeout(&error)
+        ));
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(matches!(
+            websocket.read_message(),
+            Err(Error::Timeout(Timeout::Fragment))
+        ));
+        assert_eq!(written_after_handshake(&websocket), [136, 2, 3, 240]);
+        assert!(!websocket.can_write());
+    }
+    #[test]
+    fn should_time_out_unfinished_streamed_fragments() {
+        let mut input = REQUEST.to_vec();
+        let frame = client_frame(Opcode::Binary, b"Hello", false);
+        input.extend_from_slice(&frame[..frame.len() - 2]);
+        let mut stream = MockStream::new(input);
+        stream.stall = true;
+        let config = WebSocketConfig {
+            fragment_timeout: Some(Duration::from_millis(5)),
+            ..WebSocketConfig::default()
+        };
+        let mut websocket = WebSocket::accept_with_config(stream, config).unwrap();
+        let mut reader = websocket.next_message_reader().unwrap();
+        let mut buf = [0; 5];
+        assert_eq!(reader.read(&mut buf).unwrap(), 3);
+        assert!(is_timeout(&reader.read(&mut buf).unwrap_err()));
+        std::thread::sleep(Duration::from_millis(10));
+        let error = reader.read(&mut buf).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(written_after_handshake(&websocket), [136, 2, 3, 240]);
+    }
+    #[test]
+    fn should_close_idle_connections() {
+        let mut input = REQUEST.to_vec();
+        input.extend(client_frame(Opcode::Ping, b"", true));
+        let mut stream = MockStream::new(input);
+        stream.stall = true;
+        let config = WebSocketConfig {
+            idle_timeout: Some(Duration::from_millis(5)),
+            ..WebSocketConfig::default()
+        };
+        let mut websocket = WebSocket::accept_with_config(stream, config).unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(matches!(
+            websocket.read_message(),
+            Err(Error::Timeout(Timeout::Idle))
+        ));
+        assert_eq!(written_after_handshake(&websocket), [136, 2, 3, 233]);
+    }
+    /// Keeps every event as its fields, like ` message=timed out code=1001`
+    #[cfg(feature = "tracing")]
+    struct Recorder(Arc<std::sync::Mutex<Vec<String>>>);
+    #[cfg(feature = "tracing")]
+    struct Fields(String);
+    #[cfg(feature = "tracing")]
+    impl tracing::field::Visit for Fields {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0.push_str(&format!(" {}={:?}", field.name(), value));
+        }
+    }
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for Recorder {
+        fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, _span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            tracing::span::Id::from_u64(1)
+        }
+        fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+        fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+        fn event(&self, event: &tracing::Event<'_>) {
+            let mut fields = Fields(String::new());
+            event.record(&mut fields);
+            self.0.lock().unwrap().push(fields.0);
+        }
+        fn enter(&self, _span: &tracing::span::Id) {}
+        fn exit(&self, _span: &tracing::span::Id) {}
+    }
+    /// Run `f` and return the fields of every event it logged
+    #[cfg(feature = "tracing")]
+    pub(crate) fn record_events(f: impl FnOnce()) -> Vec<String> {
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        tracing::subscriber::with_default(Recorder(events.clone()), f);
+        let events = events.lock().unwrap();
+        events.clone()
+    }
+    /// Fail unless one of `events` contains each of `expected`
+    #[cfg(feature = "tracing")]
+    pub(crate) fn assert_events(events: &[String], expected: &[&str]) {
+        for fields in expected {
+            assert!(
+                events.iter().any(|event| event.contains(fields)),
+                "{} not in {:?}",
+                fields,
+                events
+            );
+        }
+    }
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn should_trace_handshakes_violations_and_payloads() {
+        let events = record_events(|| {
+            let request = String::from_utf8(REQUEST.to_vec())
+                .unwrap()
+                .replace("Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n", "");
+            assert!(WebSocket::accept(MockStream::new(request.into_bytes())).is_err());
+
+            let config = WebSocketConfig {
+                trace_payloads: Some(3),
+                ..WebSocketConfig::default()
+            };
+            let mut input = REQUEST.to_vec();
+            input.extend_from_slice(&client_frame(Opcode::Text, b"Hello", true));
+            input.extend_from_slice(&encode_frame(Opcode::Text, b"Hello", true, None));
+            let mut websocket =
+                WebSocket::accept_with_config(MockStream::new(input), config).unwrap();
+            assert!(websocket.read_message().is_ok());
+            assert!(websocket.read_message().is_err());
+        });
+
+        let expected = [
+            "missing_header=\"Sec-WebSocket-Key\"",
+            "message=accepted handshake path=\"/chat\"",
+            "message=message direction=\"in\" opcode=\"text\" size=5 payload=Hel",
+            "error=protocol error: received an unmasked frame from a client code=1002",
+        ];
+        assert_events(&events, &expected);
+    }
+}